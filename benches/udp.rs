@@ -0,0 +1,105 @@
+//! Loopback throughput of sending and receiving datagrams one at a time against batches of
+//! `sendmmsg` and `recvmmsg`
+#[cfg(target_os = "linux")]
+#[macro_use]
+extern crate criterion;
+#[cfg(target_os = "linux")]
+extern crate nano_lib_rs;
+
+#[cfg(target_os = "linux")]
+use criterion::Criterion;
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use criterion::{Criterion, Fun};
+
+    use std::io;
+    use std::net::{SocketAddr, UdpSocket};
+    use std::os::unix::io::AsRawFd;
+
+    use nano_lib_rs::mmsg::{self, BATCH_SIZE};
+
+    /// The size of a publish message carrying a state block
+    const DATAGRAM_SIZE: usize = 216;
+    const DATAGRAMS: usize = 1024;
+
+    struct Sockets {
+        sender: UdpSocket,
+        receiver: UdpSocket,
+        to: SocketAddr,
+    }
+
+    fn sockets() -> Sockets {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let to = receiver.local_addr().unwrap();
+        Sockets { sender, receiver, to }
+    }
+
+    fn one_at_a_time(sockets: &Sockets) {
+        let datagram = [0u8; DATAGRAM_SIZE];
+        let mut buf = [0u8; 2048];
+        // Send a batch worth before receiving, so the receive buffer never overflows
+        for _ in 0..DATAGRAMS / BATCH_SIZE {
+            for _ in 0..BATCH_SIZE {
+                sockets.sender.send_to(&datagram, sockets.to).unwrap();
+            }
+            for _ in 0..BATCH_SIZE {
+                sockets.receiver.recv_from(&mut buf).unwrap();
+            }
+        }
+    }
+
+    fn batched(sockets: &Sockets) {
+        let datagrams: Vec<([u8; DATAGRAM_SIZE], SocketAddr)> = vec![([0u8; DATAGRAM_SIZE], sockets.to); BATCH_SIZE];
+        let mut bufs = vec![vec![0u8; 2048]; BATCH_SIZE];
+        for _ in 0..DATAGRAMS / BATCH_SIZE {
+            // The calls do not block, so retry until the whole batch has gone through
+            let mut sent = 0;
+            while sent < BATCH_SIZE {
+                let batch = &datagrams[sent..];
+                sent += retry(|| mmsg::send_batch(sockets.sender.as_raw_fd(), batch));
+            }
+            let mut received = 0;
+            while received < BATCH_SIZE {
+                let batch = &mut bufs[received..];
+                received += retry(|| mmsg::recv_batch(sockets.receiver.as_raw_fd(), batch)).len();
+            }
+        }
+    }
+
+    /// Call `f` until it does not fail with `WouldBlock`
+    fn retry<T, F: FnMut() -> io::Result<T>>(mut f: F) -> T {
+        loop {
+            match f() {
+                Ok(result) => return result,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                Err(e) => panic!("batch failed: {}", e),
+            }
+        }
+    }
+
+    pub fn loopback(c: &mut Criterion) {
+        let single = Fun::new("one at a time", |b, _: &usize| {
+            let sockets = sockets();
+            b.iter(|| one_at_a_time(&sockets))
+        });
+        let batch = Fun::new("sendmmsg/recvmmsg", |b, _: &usize| {
+            let sockets = sockets();
+            b.iter(|| batched(&sockets))
+        });
+        c.bench_functions("send and receive 1024 datagrams over loopback", vec![single, batch], DATAGRAMS);
+    }
+}
+
+#[cfg(target_os = "linux")]
+criterion_group!{
+  name = benches;
+  config = Criterion::default().sample_size(10);
+  targets = linux::loopback
+}
+#[cfg(target_os = "linux")]
+criterion_main!(benches);
+
+#[cfg(not(target_os = "linux"))]
+fn main() {}
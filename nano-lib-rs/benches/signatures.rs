@@ -0,0 +1,49 @@
+#[macro_use]
+extern crate criterion;
+extern crate blake2;
+extern crate nano_lib_rs;
+
+use blake2::Blake2b;
+use criterion::{Criterion, Fun};
+
+use nano_lib_rs::keys::{verify_batch, PrivateKey, PublicKey, Seed, Signature};
+
+type Item = (Vec<u8>, PublicKey, Signature);
+
+/// `count` block hashes signed by different accounts
+fn signed(count: u32) -> Vec<Item> {
+    let seed = Seed::from_string("1234567890123456789012345678901234567890123456789012345678901234").unwrap();
+    (0..count).map(|i| {
+        let key = PrivateKey::from_seed(seed.clone(), i);
+        let message = vec![i as u8; 32];
+        let signature = key.sign(&message);
+        (message, key.public_key(), signature)
+    }).collect()
+}
+
+fn verify(c: &mut Criterion) {
+    let single = Fun::new("one at a time", |b, items: &Vec<Item>| {
+        b.iter(|| items.iter()
+            .map(|(message, key, signature)| key.verify::<Blake2b>(message, signature))
+            .collect::<Vec<bool>>())
+    });
+    let batch = Fun::new("batch", |b, items: &Vec<Item>| {
+        b.iter(|| verify_batch(items))
+    });
+    c.bench_functions("verify 64 signatures", vec![single, batch], signed(64));
+}
+
+fn verify_with_bad_signature(c: &mut Criterion) {
+    let mut items = signed(64);
+    items[17].0[0] ^= 1;
+    c.bench_function("verify 64 signatures, one bad", move |b| {
+        b.iter(|| verify_batch(&items))
+    });
+}
+
+criterion_group!{
+  name = benches;
+  config = Criterion::default().sample_size(10);
+  targets = verify, verify_with_bad_signature
+}
+criterion_main!(benches);
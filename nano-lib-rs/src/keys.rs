@@ -4,6 +4,10 @@ use blake2::{
     Blake2b
 };
 use byteorder::{BigEndian, WriteBytesExt};
+use curve25519_dalek::constants::ED25519_BASEPOINT_POINT;
+use curve25519_dalek::edwards::{vartime, CompressedEdwardsY, ExtendedPoint};
+use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::IsIdentity;
 use data_encoding::{Encoding, HEXUPPER_PERMISSIVE, HEXLOWER_PERMISSIVE};
 pub use ed25519_dalek::{
 	Keypair,
@@ -15,7 +19,9 @@ pub use ed25519_dalek::{
 	SIGNATURE_LENGTH
 };
 use nanopow_rs::InputHash;
+use rand::{self, Rng};
 use error::*;
+use std::collections::HashMap;
 use std::ops::{Deref, DerefMut};
 
 impl Hash for PublicKey {
@@ -33,36 +39,62 @@ const XRB_ENCODING: Encoding = new_encoding! {
 pub struct Address(pub String);
 
 #[derive(Clone)]
-pub struct Seed(pub [u8; 64]);
+pub struct Seed(pub [u8; 32]);
 
 #[derive(Debug)]
 pub struct PrivateKey(SecretKey);
 
 impl Seed {
-	pub fn from<T: AsRef<[u8]>>(seed: T) -> Result<Self> {
+	/// Parse a seed from its 64 character hexadecimal representation
+	pub fn from_string<T: AsRef<[u8]>>(seed: T) -> Result<Self> {
 		let seed = seed.as_ref();
 		if seed.len() != 64 {
 			bail!(ErrorKind::SeedLengthError(seed.len()))
 		}
 
-		let seed = HEXUPPER_PERMISSIVE.decode(&seed).unwrap();
+		let seed = HEXUPPER_PERMISSIVE.decode(&seed)?;
 
-		let mut seed_bytes = [0u8; 64];
+		let mut seed_bytes = [0u8; 32];
 		seed_bytes.copy_from_slice(&seed);
 
 		Ok(Seed(seed_bytes))
 	}
+
+	/// Parse a seed from its 64 character hexadecimal representation
+	///
+	/// Seeds used to be stored in 64 bytes, which made this panic for every
+	/// valid seed. They are 32 bytes now; use `from_string` instead.
+	#[deprecated(note = "use Seed::from_string")]
+	pub fn from<T: AsRef<[u8]>>(seed: T) -> Result<Self> {
+		Seed::from_string(seed)
+	}
+
+	/// Create a seed from a raw 32 byte slice
+	pub fn from_bytes<T: AsRef<[u8]>>(bytes: T) -> Result<Self> {
+		let bytes = bytes.as_ref();
+		if bytes.len() != 32 {
+			bail!(ErrorKind::SeedLengthError(bytes.len() * 2))
+		}
+		let mut seed_bytes = [0u8; 32];
+		seed_bytes.copy_from_slice(bytes);
+		Ok(Seed(seed_bytes))
+	}
+
+	/// Encode the seed as an uppercase hexadecimal string
+	pub fn to_hex(&self) -> String {
+		HEXUPPER_PERMISSIVE.encode(&self.0)
+	}
 }
 
 impl Deref for Seed {
-	type Target = [u8; 64];
+	type Target = [u8; 32];
 	fn deref(&self) -> &Self::Target {
 		&self.0
 	}
 }
 
 impl DerefMut for Seed {
-	fn deref_mut(&mut self) -> &mut [u8; 64] {
+	fn deref_mut(&mut self) -> &mut [u8; 32] {
 		&mut self.0
 	}
 }
@@ -160,7 +192,43 @@ impl DerefMut for PrivateKey {
 	}
 }	
 
+impl Clone for PrivateKey {
+	fn clone(&self) -> Self {
+		PrivateKey(SecretKey::from_bytes(self.0.as_bytes()).unwrap())
+	}
+}
+
 impl PrivateKey {
+	/// Create a private key from a raw 32 byte slice
+	pub fn from_bytes<T: AsRef<[u8]>>(bytes: T) -> Result<Self> {
+		Ok(PrivateKey(SecretKey::from_bytes(bytes.as_ref())?))
+	}
+
+	/// Parse a private key from its 64 character hexadecimal representation
+	pub fn from_hex<T: AsRef<[u8]>>(s: T) -> Result<Self> {
+		let bytes = HEXUPPER_PERMISSIVE.decode(s.as_ref())?;
+		PrivateKey::from_bytes(bytes)
+	}
+
+	/// Encode the private key as an uppercase hexadecimal string
+	pub fn to_hex(&self) -> String {
+		HEXUPPER_PERMISSIVE.encode(self.0.as_bytes())
+	}
+
+	/// The public key belonging to this private key
+	pub fn public_key(&self) -> PublicKey {
+		PublicKey::from_secret::<Blake2b>(&self.0)
+	}
+
+	/// Sign `message`, using Blake2b as the ed25519 digest
+	pub fn sign(&self, message: &[u8]) -> Signature {
+		let keypair = Keypair {
+			secret: self.clone().0,
+			public: self.public_key(),
+		};
+		keypair.sign::<Blake2b>(message)
+	}
+
 	pub fn from_seed(seed: Seed, index: u32) -> PrivateKey {
 		let mut blake = Blake2b::new(32).unwrap();
 		let mut index_buf = Vec::with_capacity(4);
@@ -174,9 +242,119 @@ impl PrivateKey {
 	}
 }
 
+/// Verify many `(message, public key, signature)` triples at once with ed25519 batch
+/// verification, hashing with Blake2b like `PublicKey::verify::<Blake2b>`. Returns whether each
+/// signature is valid: if the batch as a whole fails, every signature is checked on its own to
+/// find the bad ones.
+///
+/// Both the batch and the fallback check the cofactored equation `[8][s]B == [8]R + [8][k]A`, so
+/// which path checked a block never decides whether it is valid. Unlike
+/// `PublicKey::verify::<Blake2b>` this also accepts signatures whose `R` or `A` has a small order
+/// component, which only the holder of the key can produce, and rejects non-canonical encodings
+/// of `R` and `A`.
+pub fn verify_batch<M: AsRef<[u8]>>(items: &[(M, PublicKey, Signature)]) -> Vec<bool> {
+	let mut keys = HashMap::new();
+	let terms: Vec<Option<BatchTerms>> = items.iter()
+		.map(|item| batch_terms(item, &mut keys))
+		.collect();
+	let batch_valid = batch_is_valid(terms.iter().filter_map(Option::as_ref));
+	terms.iter()
+		.map(|terms| match *terms {
+			Some(ref terms) => batch_valid || terms.is_valid(),
+			None => false,
+		})
+		.collect()
+}
+
+/// The parts of a signature that enter the batch equation
+struct BatchTerms {
+	r: ExtendedPoint,
+	a: ExtendedPoint,
+	/// The hash of `R || A || message`
+	k: Scalar,
+	s: Scalar,
+}
+
+impl BatchTerms {
+	/// Check the cofactored equation for this signature on its own
+	fn is_valid(&self) -> bool {
+		let minus_a = -self.a;
+		let sb_minus_ka = vartime::double_scalar_mult_basepoint(&self.k, &minus_a, &self.s);
+		(&sb_minus_ka - &self.r).mult_by_cofactor().is_identity()
+	}
+}
+
+/// Decode a signature for the batch equation, or `None` if it cannot be valid. Public keys are
+/// decompressed once per call, since many blocks in a batch often come from the same account.
+fn batch_terms<M: AsRef<[u8]>>(
+	item: &(M, PublicKey, Signature),
+	keys: &mut HashMap<[u8; 32], Option<ExtendedPoint>>,
+) -> Option<BatchTerms> {
+	let (ref message, ref key, ref signature) = *item;
+	let bytes = signature.to_bytes();
+	let mut r_bytes = [0u8; 32];
+	let mut s_bytes = [0u8; 32];
+	r_bytes.copy_from_slice(&bytes[..32]);
+	s_bytes.copy_from_slice(&bytes[32..]);
+	// Single verification rejects these outright
+	if s_bytes[31] & 0xe0 != 0 {
+		return None;
+	}
+	let r = decompress_canonical(r_bytes)?;
+	let a = (*keys.entry(key.to_bytes()).or_insert_with(|| decompress_canonical(key.to_bytes())))?;
+
+	let mut h = Blake2b::default();
+	h.process(&r_bytes);
+	h.process(key.as_bytes());
+	h.process(message.as_ref());
+	Some(BatchTerms {
+		r,
+		a,
+		k: Scalar::from_hash(h),
+		s: Scalar::from_bits(s_bytes),
+	})
+}
+
+/// Decompress a point, returning `None` unless it is canonically encoded
+fn decompress_canonical(bytes: [u8; 32]) -> Option<ExtendedPoint> {
+	let point = CompressedEdwardsY(bytes).decompress()?;
+	if point.compress().0 == bytes {
+		Some(point)
+	} else {
+		None
+	}
+}
+
+/// Check `[8]([-sum(z*s)]B + sum([z]R) + sum([z*k]A)) == 0`, where `k` is the hash of each
+/// signature and every equation is weighted by a random 128 bit `z` so invalid signatures
+/// cannot cancel each other out
+fn batch_is_valid<'a, I: Iterator<Item = &'a BatchTerms>>(batch: I) -> bool {
+	let mut rng = rand::thread_rng();
+	let mut basepoint_scalar = Scalar::zero();
+	let mut scalars = Vec::new();
+	let mut points = Vec::new();
+	for terms in batch {
+		let mut z_bytes = [0u8; 32];
+		rng.fill_bytes(&mut z_bytes[..16]);
+		let z = Scalar::from_bits(z_bytes);
+
+		basepoint_scalar -= &(z * terms.s);
+		scalars.push(z);
+		points.push(terms.r);
+		scalars.push(z * terms.k);
+		points.push(terms.a);
+	}
+	scalars.push(basepoint_scalar);
+	points.push(ED25519_BASEPOINT_POINT);
+	vartime::multiscalar_mult(scalars.iter(), points.iter())
+		.mult_by_cofactor()
+		.is_identity()
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use blake2::digest::FixedOutput;
 
 	#[test]
 	fn can_generate_address_from_seed() {
@@ -199,6 +377,14 @@ mod tests {
 		})
 	}
 
+	#[test]
+	#[allow(deprecated)]
+	fn keeps_deprecated_seed_parser() {
+		let hex = "1234567890123456789012345678901234567890123456789012345678901234";
+		assert_eq!(Seed::from(hex).unwrap().0, Seed::from_string(hex).unwrap().0);
+		assert_eq!(Seed::from_string(hex).unwrap().to_hex(), hex);
+	}
+
 	#[test]
 	fn can_convert_address_to_public_key() {
 		let addr = Address("xrb_3t6k35gi95xu6tergt6p69ck76ogmitsa8mnijtpxm9fkcm736xtoncuohr3".into());
@@ -224,6 +410,74 @@ mod tests {
 		})
 	}
 
+	/// `count` messages signed by different accounts of one seed
+	fn signed_messages(count: u32) -> Vec<(Vec<u8>, PublicKey, Signature)> {
+		let seed = Seed::from_string("1234567890123456789012345678901234567890123456789012345678901234").unwrap();
+		(0..count).map(|i| {
+			let key = PrivateKey::from_seed(seed.clone(), i);
+			let message = vec![i as u8; 32];
+			let signature = key.sign(&message);
+			(message, key.public_key(), signature)
+		}).collect()
+	}
+
+	#[test]
+	fn verifies_batches() {
+		let mut items = signed_messages(8);
+		assert_eq!(verify_batch(&items), vec![true; 8]);
+		assert!(verify_batch::<Vec<u8>>(&[]).is_empty());
+
+		// A signature over another message is found by the fallback
+		items[3].0[0] ^= 1;
+		let mut expected = vec![true; 8];
+		expected[3] = false;
+		assert_eq!(verify_batch(&items), expected);
+	}
+
+	#[test]
+	fn accepts_small_order_components_in_both_paths() {
+		let seed = Seed::from_string("1234567890123456789012345678901234567890123456789012345678901234").unwrap();
+		let key = PrivateKey::from_seed(seed, 0);
+		let message = vec![0xaau8; 32];
+
+		// The secret scalar of the key, as ed25519 expands it
+		let mut h = Blake2b::default();
+		h.process(key.as_bytes());
+		let mut a_bytes = [0u8; 32];
+		a_bytes.copy_from_slice(&h.fixed_result()[..32]);
+		a_bytes[0] &= 248;
+		a_bytes[31] &= 127;
+		a_bytes[31] |= 64;
+		let a = Scalar::from_bits(a_bytes);
+
+		// Sign with R = [r]B + T for the point T of order 2. Then [s]B - [k]A is R - T, which the
+		// cofactored equation accepts but `PublicKey::verify` does not.
+		let mut t_bytes = [0xffu8; 32];
+		t_bytes[0] = 0xec;
+		t_bytes[31] = 0x7f;
+		let t = CompressedEdwardsY(t_bytes).decompress().unwrap();
+		let r = Scalar::from_bits([7u8; 32]);
+		let r_bytes = (&(&ED25519_BASEPOINT_POINT * &r) + &t).compress().0;
+		let mut h = Blake2b::default();
+		h.process(&r_bytes);
+		h.process(key.public_key().as_bytes());
+		h.process(&message);
+		let s = r + Scalar::from_hash(h) * a;
+		let mut signature = [0u8; 64];
+		signature[..32].copy_from_slice(&r_bytes);
+		signature[32..].copy_from_slice(&s.to_bytes());
+		let signature = Signature::from_bytes(&signature).unwrap();
+		assert!(!key.public_key().verify::<Blake2b>(&message, &signature));
+
+		let mut items = signed_messages(4);
+		items.push((message, key.public_key(), signature));
+		assert_eq!(verify_batch(&items), vec![true; 5]);
+
+		// The fallback after a failed batch gives the same answer
+		items[1].0[0] ^= 1;
+		assert_eq!(verify_batch(&items), vec![true, false, true, true, true]);
+	}
+
 	#[test]
 	fn can_invalidate_addresses() {
 		let addresses = vec![
@@ -18,7 +18,9 @@ extern crate serde_derive;
 extern crate serde_json;
 
 extern crate blake2;
+extern crate curve25519_dalek;
 extern crate ed25519_dalek;
+extern crate rand;
 
 extern crate bytes;
 extern crate data_encoding;
@@ -33,6 +35,9 @@ extern crate nanopow_rs;
 
 extern crate tokio_io;
 
+#[cfg(target_os = "linux")]
+extern crate libc;
+
 #[macro_use]
 mod macros; 
 
@@ -41,3 +46,5 @@ pub mod keys;
 pub mod hash;
 pub mod error;
 pub mod message;
+#[cfg(target_os = "linux")]
+pub mod mmsg;
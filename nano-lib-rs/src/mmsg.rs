@@ -0,0 +1,143 @@
+//! Sending and receiving batches of UDP datagrams with `sendmmsg` and `recvmmsg`, one system
+//! call for many datagrams. Linux only; elsewhere the node sends and receives a datagram at a
+//! time.
+use libc;
+
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::RawFd;
+use std::ptr;
+
+/// The most datagrams sent or received in one call
+pub const BATCH_SIZE: usize = 32;
+
+fn to_sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match *addr {
+        SocketAddr::V4(ref addr) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+            mem::size_of::<libc::sockaddr_in>()
+        },
+        SocketAddr::V6(ref addr) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_addr.s6_addr = addr.ip().octets();
+            sin6.sin6_scope_id = addr.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        },
+    };
+    (storage, len as libc::socklen_t)
+}
+
+fn from_sockaddr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+            Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(sin.sin_port))))
+        },
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+            Some(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(sin6.sin6_port), sin6.sin6_flowinfo, sin6.sin6_scope_id)))
+        },
+        _ => None,
+    }
+}
+
+/// Send datagrams from the front of `datagrams` without blocking, returning how many were
+/// sent. An error means not even the first one could be sent.
+pub fn send_batch<B: AsRef<[u8]>>(fd: RawFd, datagrams: &[(B, SocketAddr)]) -> io::Result<usize> {
+    let count = datagrams.len().min(BATCH_SIZE);
+    let mut addrs: Vec<_> = datagrams[..count].iter()
+        .map(|&(_, addr)| to_sockaddr(&addr))
+        .collect();
+    let mut iovecs: Vec<libc::iovec> = datagrams[..count].iter()
+        .map(|(data, _)| libc::iovec {
+            iov_base: data.as_ref().as_ptr() as *mut libc::c_void,
+            iov_len: data.as_ref().len(),
+        })
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = Vec::with_capacity(count);
+    for i in 0..count {
+        let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+        msg.msg_hdr.msg_name = &mut addrs[i].0 as *mut _ as *mut libc::c_void;
+        msg.msg_hdr.msg_namelen = addrs[i].1;
+        msg.msg_hdr.msg_iov = &mut iovecs[i];
+        msg.msg_hdr.msg_iovlen = 1;
+        msgs.push(msg);
+    }
+    let sent = unsafe {
+        libc::sendmmsg(fd, msgs.as_mut_ptr(), count as libc::c_uint, libc::MSG_DONTWAIT)
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+/// Receive a datagram into each buffer of `bufs` without blocking, returning the length and
+/// sender of every datagram received, in the order of the buffers. Datagrams longer than their
+/// buffer are cut short. Fails with `WouldBlock` if no datagram was waiting.
+pub fn recv_batch(fd: RawFd, bufs: &mut [Vec<u8>]) -> io::Result<Vec<(usize, SocketAddr)>> {
+    let count = bufs.len().min(BATCH_SIZE);
+    let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; count];
+    let mut iovecs: Vec<libc::iovec> = bufs[..count].iter_mut()
+        .map(|buf| libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        })
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = Vec::with_capacity(count);
+    for i in 0..count {
+        let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+        msg.msg_hdr.msg_name = &mut addrs[i] as *mut _ as *mut libc::c_void;
+        msg.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_hdr.msg_iov = &mut iovecs[i];
+        msg.msg_hdr.msg_iovlen = 1;
+        msgs.push(msg);
+    }
+    let received = unsafe {
+        libc::recvmmsg(fd, msgs.as_mut_ptr(), count as libc::c_uint, libc::MSG_DONTWAIT, ptr::null_mut())
+    };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // A UDP socket only receives from IPv4 and IPv6 senders, so the fallback is never used
+    let unknown = SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from([0u8; 16]), 0, 0, 0));
+    Ok(msgs[..received as usize].iter().zip(addrs.iter())
+        .map(|(msg, addr)| (msg.msg_len as usize, from_sockaddr(addr).unwrap_or(unknown)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn sends_and_receives_batches() {
+        use super::*;
+        use std::net::UdpSocket;
+        use std::os::unix::io::AsRawFd;
+
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let to = receiver.local_addr().unwrap();
+        let datagrams: Vec<(Vec<u8>, SocketAddr)> = (0..3u8).map(|i| (vec![i; 10 + i as usize], to)).collect();
+        assert_eq!(send_batch(sender.as_raw_fd(), &datagrams).unwrap(), 3);
+
+        let mut bufs = vec![vec![0u8; 64]; 4];
+        let received = recv_batch(receiver.as_raw_fd(), &mut bufs).unwrap();
+        assert_eq!(received.len(), 3);
+        for (i, &(len, from)) in received.iter().enumerate() {
+            assert_eq!(from, sender.local_addr().unwrap());
+            assert_eq!(&bufs[i][..len], &datagrams[i].0[..]);
+        }
+        let e = recv_batch(receiver.as_raw_fd(), &mut bufs).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+    }
+}
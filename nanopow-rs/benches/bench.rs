@@ -2,9 +2,9 @@
 extern crate criterion;
 extern crate nanopow_rs;
 
-use criterion::Criterion;
+use criterion::{Criterion, Fun};
 
-use nanopow_rs::{generate_work, InputHash};
+use nanopow_rs::{check_work, check_work_batch, generate_work, InputHash, Work};
 
 fn generate_unlimited(c: &mut Criterion) {
     c.bench_function("generate unlimited", |b| {
@@ -13,9 +13,21 @@ fn generate_unlimited(c: &mut Criterion) {
     });
 }
 
+fn check_batch(c: &mut Criterion) {
+    let hash = InputHash::from_hex("47F694A96653EB497709490776E492EFBB88EBC5C4E95CC0B2C9DCAB1930C36B").unwrap();
+    let pairs: Vec<(InputHash, Work)> = (0..4096u64).map(|i| (hash, Work(i))).collect();
+    let single = Fun::new("one at a time", |b, pairs: &Vec<(InputHash, Work)>| {
+        b.iter(|| pairs.iter().map(|&(ref h, ref w)| check_work(h, w)).collect::<Vec<bool>>())
+    });
+    let batch = Fun::new("batch", |b, pairs: &Vec<(InputHash, Work)>| {
+        b.iter(|| check_work_batch(pairs))
+    });
+    c.bench_functions("check 4096 work values", vec![single, batch], pairs);
+}
+
 criterion_group!{
   name = benches;
   config = Criterion::default().sample_size(10);
-  targets = generate_unlimited
+  targets = generate_unlimited, check_batch
 }
-criterion_main!(benches);
\ No newline at end of file
+criterion_main!(benches);
@@ -0,0 +1,59 @@
+//! A standalone work server compatible with the nano-work-server HTTP API
+extern crate clap;
+extern crate futures;
+extern crate nanopow_rs;
+extern crate num_cpus;
+extern crate tokio;
+
+use clap::{Arg, App};
+use futures::Future;
+
+use std::net::SocketAddr;
+use std::process;
+
+fn main() {
+    let matches = App::new("nanopow-server")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Generates proof of work for Nano over HTTP")
+        .arg(Arg::with_name("listen")
+            .short("l")
+            .long("listen")
+            .value_name("ADDR")
+            .help("The address to listen for requests on")
+            .default_value("127.0.0.1:7076")
+            .takes_value(true))
+        .arg(Arg::with_name("threads")
+            .short("t")
+            .long("threads")
+            .value_name("N")
+            .help("The number of threads to generate work on, defaulting to one per CPU")
+            .takes_value(true))
+        .get_matches();
+
+    let addr: SocketAddr = match matches.value_of("listen").unwrap().parse() {
+        Ok(addr) => addr,
+        Err(e) => {
+            eprintln!("Invalid listen address: {}", e);
+            process::exit(1);
+        },
+    };
+    let threads = match matches.value_of("threads").map(str::parse::<usize>) {
+        Some(Ok(threads)) if threads > 0 => threads,
+        Some(_) => {
+            eprintln!("Invalid thread count");
+            process::exit(1);
+        },
+        None => num_cpus::get(),
+    };
+
+    match nanopow_rs::server::serve(&addr, threads) {
+        Ok((addr, server)) => {
+            println!("Listening for work requests on {} with {} threads", addr, threads);
+            tokio::run(server.map_err(|e| eprintln!("Work server error: {}", e)));
+        },
+        Err(e) => {
+            eprintln!("Failed to start work server: {}", e);
+            process::exit(1);
+        },
+    }
+}
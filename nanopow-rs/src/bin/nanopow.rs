@@ -0,0 +1,247 @@
+//! Generate, validate and benchmark proof of work from the command line
+extern crate clap;
+extern crate futures;
+extern crate nanopow_rs;
+extern crate num_cpus;
+#[macro_use]
+extern crate serde_json;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use futures::Future;
+use serde_json::Value;
+
+use nanopow_rs::{from_multiplier, spawn_work, to_multiplier, work_value, InputHash, Work, WorkGenerator, DEFAULT_THRESHOLD};
+
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+type CliResult<T> = ::std::result::Result<T, String>;
+
+fn difficulty_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("difficulty")
+            .short("d")
+            .long("difficulty")
+            .value_name("HEX")
+            .conflicts_with("multiplier")
+            .help("The difficulty threshold as 16 hex digits (default ffffffc000000000)"),
+        Arg::with_name("multiplier")
+            .short("m")
+            .long("multiplier")
+            .value_name("MULTIPLIER")
+            .help("The difficulty as a multiplier of the default threshold"),
+    ]
+}
+
+fn parse_difficulty(matches: &ArgMatches) -> CliResult<u64> {
+    if let Some(difficulty) = matches.value_of("difficulty") {
+        return u64::from_str_radix(difficulty, 16)
+            .map_err(|_| format!("Invalid difficulty: {}", difficulty));
+    }
+    match matches.value_of("multiplier") {
+        Some(multiplier) => match multiplier.parse::<f64>() {
+            Ok(m) if m > 0.0 => Ok(from_multiplier(m, DEFAULT_THRESHOLD)),
+            _ => Err(format!("Invalid multiplier: {}", multiplier)),
+        },
+        None => Ok(DEFAULT_THRESHOLD),
+    }
+}
+
+fn parse_root(matches: &ArgMatches) -> CliResult<InputHash> {
+    let root = matches.value_of("root").unwrap();
+    InputHash::from_hex(root.to_uppercase()).map_err(|e| format!("Invalid root {}: {}", root, e))
+}
+
+fn parse_threads(matches: &ArgMatches) -> CliResult<usize> {
+    match matches.value_of("threads") {
+        Some(threads) => match threads.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(format!("Invalid thread count: {}", threads)),
+        },
+        None => Ok(num_cpus::get()),
+    }
+}
+
+/// Print `fields` as `name: value` lines, or as a JSON object
+fn print(json: bool, fields: Value) {
+    if json {
+        println!("{}", fields);
+    } else if let Value::Object(map) = fields {
+        for (name, value) in map {
+            match value {
+                Value::String(s) => println!("{}: {}", name, s),
+                other => println!("{}: {}", name, other),
+            }
+        }
+    }
+}
+
+fn generate(matches: &ArgMatches, json: bool) -> CliResult<()> {
+    let root = parse_root(matches)?;
+    let threshold = parse_difficulty(matches)?;
+    let mut generator = WorkGenerator::new()
+        .with_threshold(threshold)
+        .with_threads(parse_threads(matches)?);
+    if let Some(seed) = matches.value_of("seed") {
+        generator = generator.with_seed(seed.parse().map_err(|_| format!("Invalid seed: {}", seed))?);
+    }
+    let start = Instant::now();
+    let work = generator.generate(&root).ok_or("Work generation stopped without a result")?;
+    let elapsed = start.elapsed();
+    let value = work_value(&root, &work);
+    print(json, json!({
+        "work": String::from(work),
+        "difficulty": format!("{:016x}", value),
+        "multiplier": to_multiplier(value, DEFAULT_THRESHOLD),
+        "seconds": elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9,
+    }));
+    Ok(())
+}
+
+fn validate(matches: &ArgMatches, json: bool) -> CliResult<()> {
+    let root = parse_root(matches)?;
+    let work = matches.value_of("work").unwrap();
+    let work = Work::from_hex(work.to_lowercase()).map_err(|e| format!("Invalid work {}: {}", work, e))?;
+    let threshold = parse_difficulty(matches)?;
+    let value = work_value(&root, &work);
+    print(json, json!({
+        "valid": value >= threshold,
+        "difficulty": format!("{:016x}", value),
+        "multiplier": to_multiplier(value, DEFAULT_THRESHOLD),
+    }));
+    Ok(())
+}
+
+fn bench(matches: &ArgMatches, json: bool) -> CliResult<()> {
+    let seconds = matches.value_of("seconds").unwrap();
+    let seconds: u64 = seconds.parse().map_err(|_| format!("Invalid duration: {}", seconds))?;
+    let thread_counts: Vec<usize> = match matches.values_of("threads") {
+        Some(counts) => counts
+            .map(|n| n.parse::<usize>().ok().filter(|&n| n > 0).ok_or(format!("Invalid thread count: {}", n)))
+            .collect::<CliResult<_>>()?,
+        None => {
+            // Powers of two up to the number of CPUs, then every CPU
+            let cpus = num_cpus::get();
+            let mut counts: Vec<usize> = (0..).map(|i| 1 << i).take_while(|&n| n < cpus).collect();
+            counts.push(cpus);
+            counts
+        },
+    };
+    let root = InputHash::new([0u8; 32]);
+    for threads in thread_counts {
+        // No work reaches the maximum threshold, so every thread hashes until cancelled
+        let mut handle = spawn_work(&root, u64::max_value(), threads, None);
+        let start = Instant::now();
+        thread::sleep(Duration::from_secs(seconds));
+        handle.cancel();
+        let elapsed = start.elapsed();
+        // Wait for the threads to stop so the hash count is complete
+        let _ = (&mut handle).wait();
+        let hashes_tried = handle.hashes_tried();
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        let rate = hashes_tried as f64 / elapsed;
+        if json {
+            println!("{}", json!({
+                "threads": threads,
+                "hashes": hashes_tried,
+                "hashes_per_second": rate.round(),
+                "hashes_per_second_per_thread": (rate / threads as f64).round(),
+            }));
+        } else {
+            println!("{:>3} threads: {:>12.0} hashes/s ({:.0} per thread)", threads, rate, rate / threads as f64);
+        }
+    }
+    Ok(())
+}
+
+fn app<'a, 'b>() -> App<'a, 'b> {
+    let root = Arg::with_name("root")
+        .required(true)
+        .value_name("ROOT")
+        .help("The block hash or account public key the work is for, as hex");
+    App::new("nanopow")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Generates, validates and benchmarks proof of work for Nano")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(Arg::with_name("json")
+            .long("json")
+            .global(true)
+            .help("Print output as JSON"))
+        .subcommand(SubCommand::with_name("generate")
+            .about("Generate work for a root")
+            .arg(root.clone())
+            .args(&difficulty_args())
+            .arg(Arg::with_name("threads")
+                .short("t")
+                .long("threads")
+                .value_name("N")
+                .help("The number of threads to use (default one per CPU)"))
+            .arg(Arg::with_name("seed")
+                .long("seed")
+                .value_name("SEED")
+                .help("Seed the candidate generators, for reproducible results with one thread")))
+        .subcommand(SubCommand::with_name("validate")
+            .about("Print the difficulty of work for a root and whether it reaches a threshold")
+            .arg(root)
+            .arg(Arg::with_name("work")
+                .required(true)
+                .value_name("WORK")
+                .help("The work as 16 hex digits"))
+            .args(&difficulty_args()))
+        .subcommand(SubCommand::with_name("bench")
+            .about("Measure hashes per second for different thread counts")
+            .arg(Arg::with_name("threads")
+                .short("t")
+                .long("threads")
+                .value_name("N")
+                .multiple(true)
+                .use_delimiter(true)
+                .help("The thread counts to measure, e.g. 1,2,4 (default powers of two up to one per CPU)"))
+            .arg(Arg::with_name("seconds")
+                .short("s")
+                .long("seconds")
+                .value_name("SECONDS")
+                .default_value("5")
+                .help("How long to measure each thread count for")))
+}
+
+/// Whether `--json` was given. Global args given after the subcommand are only recorded in the
+/// matches of the subcommand.
+fn json_output(matches: &ArgMatches, sub: &ArgMatches) -> bool {
+    matches.is_present("json") || sub.is_present("json")
+}
+
+fn main() {
+    let matches = app().get_matches();
+    let result = match matches.subcommand() {
+        ("generate", Some(sub)) => generate(sub, json_output(&matches, sub)),
+        ("validate", Some(sub)) => validate(sub, json_output(&matches, sub)),
+        ("bench", Some(sub)) => bench(sub, json_output(&matches, sub)),
+        _ => unreachable!(),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOT: &str = "47F694A96653EB497709490776E492EFBB88EBC5C4E95CC0B2C9DCAB1930C36B";
+
+    fn parses_json(args: &[&str]) -> bool {
+        let matches = app().get_matches_from(args.to_vec());
+        let sub = matches.subcommand_matches("generate").unwrap();
+        json_output(&matches, sub)
+    }
+
+    #[test]
+    fn reads_json_before_or_after_the_subcommand() {
+        assert!(parses_json(&["nanopow", "--json", "generate", ROOT]));
+        assert!(parses_json(&["nanopow", "generate", ROOT, "--json"]));
+        assert!(!parses_json(&["nanopow", "generate", ROOT]));
+    }
+}
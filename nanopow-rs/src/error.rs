@@ -17,9 +17,17 @@ error_chain! {
       description("attempted to create Work with invalid length")
       display("Attempted to create Work with invalid length")
     }
+
+    /// A work generation thread panicked before sending its result
+    WorkerPanicked {
+      description("work generation thread panicked")
+      display("Work generation thread panicked")
+    }
   }
 
   foreign_links {
     FormatError(::std::fmt::Error) #[doc = "A formatting error occured"];
+    HyperError(::hyper::Error) #[cfg(feature = "server")] #[doc = "An error occurred in the HTTP server"];
+    JsonError(::serde_json::Error) #[cfg(feature = "server")] #[doc = "An error occurred while parsing JSON"];
   }
 }
\ No newline at end of file
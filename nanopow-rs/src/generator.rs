@@ -0,0 +1,117 @@
+use byteorder::{ByteOrder, LittleEndian};
+
+use std::sync::atomic::AtomicUsize;
+
+use super::{generate_work_internal, InputHash, Stop, Work, DEFAULT_THRESHOLD};
+use handle::{spawn_work_internal, WorkHandle};
+
+/// Settings for generating work, built up from the defaults with the `with_*` methods.
+///
+/// With a seed and a single thread the same `Work` is found for a given hash every time,
+/// which keeps tests and fixtures reproducible. With a seed and several threads each thread
+/// tries a fixed sequence, but whichever thread finds work first wins.
+#[derive(Clone, Debug)]
+pub struct WorkGenerator {
+    threshold: u64,
+    threads: usize,
+    seed: Option<u64>,
+    max_iters: Option<u64>,
+}
+
+impl Default for WorkGenerator {
+    fn default() -> Self {
+        WorkGenerator::new()
+    }
+}
+
+impl WorkGenerator {
+    /// Generate work reaching the default network threshold on every CPU,
+    /// from random starting points and without an iteration limit
+    pub fn new() -> Self {
+        WorkGenerator {
+            threshold: DEFAULT_THRESHOLD,
+            threads: ::num_cpus::get(),
+            seed: None,
+            max_iters: None,
+        }
+    }
+
+    /// Generate work reaching `threshold` instead of the default network threshold
+    pub fn with_threshold(mut self, threshold: u64) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Generate work on `threads` threads
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Generate work on the calling thread only
+    pub fn single_threaded(self) -> Self {
+        self.with_threads(1)
+    }
+
+    /// Draw candidates from generators seeded from `seed` rather than randomly
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Give up after trying `max_iters` hashes in total across all threads
+    pub fn with_max_iters(mut self, max_iters: u64) -> Self {
+        self.max_iters = Some(max_iters);
+        self
+    }
+
+    /// Generate work for `hash`, blocking until it is found or the iteration limit is reached
+    pub fn generate(&self, hash: &InputHash) -> Option<Work> {
+        let hashes = AtomicUsize::new(0);
+        generate_work_internal(&hash.as_bytes()[..], self.threshold, self.max_iters, self.threads, self.seed, &Stop::new(), &hashes)
+            .map(|w| Work(LittleEndian::read_u64(&w)))
+    }
+
+    /// Start generating work for `hash` on background threads
+    pub fn spawn(&self, hash: &InputHash) -> WorkHandle {
+        spawn_work_internal(hash, self.threshold, self.threads, self.max_iters, self.seed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+    use {check_work_with_threshold, TEST_THRESHOLD};
+
+    fn hash() -> InputHash {
+        InputHash::from_hex("47F694A96653EB497709490776E492EFBB88EBC5C4E95CC0B2C9DCAB1930C36B").unwrap()
+    }
+
+    #[test]
+    fn produces_the_same_work_for_a_seed() {
+        let generator = WorkGenerator::new()
+            .with_threshold(TEST_THRESHOLD)
+            .single_threaded()
+            .with_seed(1);
+        let work = generator.generate(&hash()).unwrap();
+        assert_eq!(work, Work::from_hex("355bd24c5482ace6").unwrap());
+        assert_eq!(generator.generate(&hash()), Some(work));
+        assert_eq!(generator.spawn(&hash()).wait().unwrap(), Some(work));
+        assert!(check_work_with_threshold(&hash(), &work, TEST_THRESHOLD));
+
+        let other = generator.clone().with_seed(2).generate(&hash()).unwrap();
+        assert_eq!(other, Work::from_hex("046cd0daad562171").unwrap());
+    }
+
+    #[test]
+    fn respects_iteration_limit() {
+        // Seed 1 needs 504 hashes to reach the test threshold
+        let generator = WorkGenerator::new()
+            .with_threshold(TEST_THRESHOLD)
+            .single_threaded()
+            .with_seed(1);
+        assert_eq!(generator.clone().with_max_iters(503).generate(&hash()), None);
+        assert!(generator.with_max_iters(504).generate(&hash()).is_some());
+    }
+}
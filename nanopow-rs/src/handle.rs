@@ -0,0 +1,185 @@
+use futures::{Async, Future, Poll};
+use futures::sync::oneshot;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+
+use super::{generate_work_internal, InputHash, Work};
+use error::*;
+
+/// A token which stops work generation when triggered. Clones share the same flag,
+/// so it can be handed to another thread.
+#[derive(Clone, Debug, Default)]
+pub struct Stop(Arc<AtomicBool>);
+
+impl Stop {
+    /// Create a token which has not been triggered
+    pub fn new() -> Self {
+        Stop::default()
+    }
+
+    /// Stop any work generation using this token
+    pub fn stop(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether `stop` has been called on this token or one of its clones
+    pub fn is_stopped(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A handle to work being generated on background threads.
+///
+/// As a `Future` it resolves to `Some(Work)` once valid work is found, or `None` if generation
+/// was cancelled or ran out of iterations. Dropping the handle cancels the generation.
+#[derive(Debug)]
+pub struct WorkHandle {
+    stop: Stop,
+    hashes: Arc<AtomicUsize>,
+    result: oneshot::Receiver<Option<Work>>,
+}
+
+impl WorkHandle {
+    /// Stop generating work. The handle will resolve to `None` shortly after.
+    pub fn cancel(&self) {
+        self.stop.stop();
+    }
+
+    /// A token which can cancel this generation from another thread
+    pub fn stop_token(&self) -> Stop {
+        self.stop.clone()
+    }
+
+    /// The number of hashes tried so far, across all threads
+    pub fn hashes_tried(&self) -> u64 {
+        self.hashes.load(Ordering::Relaxed) as u64
+    }
+}
+
+impl Drop for WorkHandle {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+impl Future for WorkHandle {
+    type Item = Option<Work>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Work>, Error> {
+        match self.result.poll() {
+            Ok(Async::Ready(work)) => Ok(Async::Ready(work)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(_) => bail!(ErrorKind::WorkerPanicked),
+        }
+    }
+}
+
+/// Start generating work for `hash` reaching `threshold` on `threads` background threads,
+/// returning immediately with a handle to the generation
+pub fn spawn_work(hash: &InputHash, threshold: u64, threads: usize, max_iters: Option<u64>) -> WorkHandle {
+    spawn_work_internal(hash, threshold, threads, max_iters, None)
+}
+
+pub(crate) fn spawn_work_internal(
+    hash: &InputHash,
+    threshold: u64,
+    threads: usize,
+    max_iters: Option<u64>,
+    seed: Option<u64>,
+) -> WorkHandle {
+    let hash = *hash.as_bytes();
+    let stop = Stop::new();
+    let hashes = Arc::new(AtomicUsize::new(0));
+    let (tx, rx) = oneshot::channel();
+    {
+        let stop = stop.clone();
+        let hashes = hashes.clone();
+        thread::spawn(move || {
+            let result = generate_work_internal(&hash[..], threshold, max_iters, threads.max(1), seed, &stop, &hashes)
+                .map(|w| Work(LittleEndian::read_u64(&w)));
+            let _ = tx.send(result);
+        });
+    }
+    WorkHandle {
+        stop,
+        hashes,
+        result: rx,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {check_work_with_threshold, TEST_THRESHOLD};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn generates_work_in_background() {
+        let hash = InputHash::from_hex("47F694A96653EB497709490776E492EFBB88EBC5C4E95CC0B2C9DCAB1930C36B").unwrap();
+        let handle = spawn_work(&hash, TEST_THRESHOLD, 2, None);
+        let work = handle.wait().unwrap().unwrap();
+        assert!(check_work_with_threshold(&hash, &work, TEST_THRESHOLD));
+    }
+
+    fn tries_exactly(threshold: u64, threads: usize, max_iters: u64) -> u64 {
+        let hash = InputHash::from_hex("47F694A96653EB497709490776E492EFBB88EBC5C4E95CC0B2C9DCAB1930C36B").unwrap();
+        let handle = spawn_work(&hash, threshold, threads, Some(max_iters));
+        let hashes = handle.hashes.clone();
+        handle.wait().unwrap();
+        hashes.load(Ordering::Relaxed) as u64
+    }
+
+    #[test]
+    fn spends_exact_iteration_budget() {
+        // No work reaches the maximum threshold, so the whole budget is spent
+        assert_eq!(tries_exactly(u64::max_value(), 4, 10_000), 10_000);
+        assert_eq!(tries_exactly(u64::max_value(), 3, 1001), 1001);
+        // Budgets smaller than the thread count still try every hash
+        assert_eq!(tries_exactly(u64::max_value(), 8, 3), 3);
+        assert_eq!(tries_exactly(u64::max_value(), 2, 0), 0);
+    }
+
+    #[test]
+    fn stops_after_first_valid_hash() {
+        // Every hash reaches a threshold of zero
+        assert_eq!(tries_exactly(0, 1, 1000), 1);
+    }
+
+    #[test]
+    fn cancels_from_another_thread() {
+        let hash = InputHash::from_hex("47F694A96653EB497709490776E492EFBB88EBC5C4E95CC0B2C9DCAB1930C36B").unwrap();
+        // No work can reach the maximum threshold in a reasonable time
+        let handle = spawn_work(&hash, u64::max_value(), 2, None);
+        let stop = handle.stop_token();
+        thread::spawn(move || {
+            thread::sleep(::std::time::Duration::from_millis(50));
+            stop.stop();
+        });
+        assert_eq!(handle.wait().unwrap(), None);
+    }
+
+    #[test]
+    fn stops_when_dropped() {
+        let hash = InputHash::from_hex("47F694A96653EB497709490776E492EFBB88EBC5C4E95CC0B2C9DCAB1930C36B").unwrap();
+        let handle = spawn_work(&hash, u64::max_value(), 2, None);
+        let hashes = handle.hashes.clone();
+        drop(handle);
+        // The generating thread holds the other reference until it and its workers exit
+        let start = Instant::now();
+        while Arc::strong_count(&hashes) > 1 {
+            assert!(start.elapsed() < Duration::from_secs(5), "work generation kept running");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn gives_up_when_budget_is_spent() {
+        let hash = InputHash::from_hex("47F694A96653EB497709490776E492EFBB88EBC5C4E95CC0B2C9DCAB1930C36B").unwrap();
+        assert_eq!(::generate_work_with_threshold(&hash, u64::max_value(), Some(100)), None);
+    }
+}
@@ -17,6 +17,14 @@ extern crate num_cpus;
 extern crate byteorder;
 #[macro_use]
 extern crate lazy_static;
+extern crate futures;
+#[cfg(feature = "server")]
+extern crate hyper;
+#[cfg(feature = "server")]
+extern crate tokio;
+#[cfg(feature = "server")]
+#[macro_use]
+extern crate serde_json;
 
 use blake2::{Blake2b};
 use blake2::digest::{Input, VariableOutput};
@@ -28,18 +36,42 @@ use rand::{XorShiftRng, Rng, SeedableRng};
 use byteorder::{ByteOrder, LittleEndian, BigEndian};
 
 use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 /// Error types, using error-chain
 pub mod error;
 use error::*;
 
-const THRESHOLD_STR: &[u8] = b"ffffffc000000000";
+/// Cancellable work generation on background threads
+pub mod handle;
+pub use handle::{Stop, WorkHandle, spawn_work};
+
+/// Configurable, optionally deterministic work generation
+pub mod generator;
+pub use generator::WorkGenerator;
+
+/// Verification of many work values at once
+pub mod batch;
+pub use batch::{check_work_batch, check_work_batch_with_threshold, work_values_batch};
+
+/// An HTTP work server compatible with nano-work-server
+#[cfg(feature = "server")]
+pub mod server;
+
+/// The default network difficulty threshold
+pub const DEFAULT_THRESHOLD: u64 = 0xffffffc000000000;
+/// The raised threshold for send and change blocks
+pub const SEND_THRESHOLD: u64 = 0xfffffff800000000;
+/// The lowered threshold for receive and open blocks
+pub const RECEIVE_THRESHOLD: u64 = 0xfffffe0000000000;
+/// The low threshold used on the test network
+pub const TEST_THRESHOLD: u64 = 0xff00000000000000;
 
 lazy_static! {
-    /// The network threshold
+    /// The default network threshold as big-endian bytes
     pub static ref THRESHOLD: [u8; 8] = {
         let mut buf = [0u8; 8];
-        let _ = HEXLOWER.decode_mut(THRESHOLD_STR, &mut buf).unwrap();
+        BigEndian::write_u64(&mut buf, DEFAULT_THRESHOLD);
         buf
     };
 }
@@ -144,26 +176,54 @@ impl fmt::Display for InputHash {
     }
 }
 
-fn check_result_threshold(hash: &[u8; 8]) -> bool {
-    (&hash).iter().rev().enumerate().fold(true, |acc, (i, &byte)| {
-        acc && byte >= THRESHOLD[i]
-    })
-}
-
-fn hash_work_internal(work: &[u8], hash: &[u8]) -> [u8; 8] {
+fn hash_work_internal(work: &[u8], hash: &[u8]) -> u64 {
     let mut hasher = Blake2b::new(8).unwrap();
     hasher.process(&work[..]);
     hasher.process(&hash[..]);
     let mut output = [0u8; 8];
     hasher.variable_result(&mut output).unwrap();
-    output
+    LittleEndian::read_u64(&output)
+}
+
+/// Computes the difficulty value of a `Work` for a given `InputHash`.
+/// The work is valid for any threshold less than or equal to this value.
+pub fn work_value(hash: &InputHash, work: &Work) -> u64 {
+    let mut work_bytes = [0u8; 8];
+    LittleEndian::write_u64(&mut work_bytes, work.0);
+    hash_work_internal(&work_bytes, &hash.0)
+}
+
+/// Converts a difficulty into a multiplier relative to `base`,
+/// e.g. `to_multiplier(SEND_THRESHOLD, DEFAULT_THRESHOLD) == 8.0`
+pub fn to_multiplier(difficulty: u64, base: u64) -> f64 {
+    base.wrapping_neg() as f64 / difficulty.wrapping_neg() as f64
+}
+
+/// Converts a multiplier relative to `base` into a difficulty,
+/// e.g. `from_multiplier(8.0, DEFAULT_THRESHOLD) == SEND_THRESHOLD`
+pub fn from_multiplier(multiplier: f64, base: u64) -> u64 {
+    ((base.wrapping_neg() as f64 / multiplier) as u64).wrapping_neg()
 }
 
 /// Attempts to generate valid work for a given `InputHash` (usually a block hash or public key)
-/// with optional maximum iterations
+/// using the default network threshold. At most `max_iters` hashes are tried in total across
+/// all threads; `None` searches until valid work is found.
 pub fn generate_work(hash: &InputHash, max_iters: Option<u64>) -> Option<Work> {
+    generate_work_with_threshold(hash, DEFAULT_THRESHOLD, max_iters)
+}
+
+/// Generates valid work for a given `InputHash` using the default network threshold,
+/// searching for as long as it takes
+pub fn generate_work_unbounded(hash: &InputHash) -> Work {
+    generate_work(hash, None).expect("unbounded work generation always finds work")
+}
+
+/// Attempts to generate work for a given `InputHash` whose value is at least `threshold`.
+/// At most `max_iters` hashes are tried in total across all threads.
+pub fn generate_work_with_threshold(hash: &InputHash, threshold: u64, max_iters: Option<u64>) -> Option<Work> {
     let hash = hash.0;
-    if let Some(w) = generate_work_internal(&hash[..], max_iters) {
+    let hashes = AtomicUsize::new(0);
+    if let Some(w) = generate_work_internal(&hash[..], threshold, max_iters, num_cpus::get(), None, &Stop::new(), &hashes) {
         let work = LittleEndian::read_u64(&w);
         Some(Work(work))
     } else {
@@ -171,59 +231,90 @@ pub fn generate_work(hash: &InputHash, max_iters: Option<u64>) -> Option<Work> {
     }
 }
 
-fn generate_work_internal(hash: &[u8], max_iters: Option<u64>) -> Option<[u8; 8]> {
-    let numcpus = num_cpus::get();
+/// Flush the per-thread hash count to the shared counter this often
+const HASH_COUNT_INTERVAL: u64 = 1024;
+
+/// Split an iteration budget across threads so the shares add up to exactly `max_iters`
+fn thread_budget(max_iters: Option<u64>, thread: usize, numcpus: usize) -> Option<u64> {
+    max_iters.map(|n| {
+        let numcpus = numcpus as u64;
+        let extra = if (thread as u64) < n % numcpus { 1 } else { 0 };
+        n / numcpus + extra
+    })
+}
+
+/// The random number generator seed for one thread of a seeded generation.
+/// The constant last word keeps the seed from being all zeros, which `XorShiftRng` rejects.
+fn thread_seed(seed: u64, thread: usize) -> [u32; 4] {
+    [seed as u32, (seed >> 32) as u32, thread as u32, 0x9e3779b9]
+}
+
+/// Searches for work on `numcpus` threads. Every hash tried is counted in `hashes`,
+/// and at most `max_iters` hashes are tried in total if it is given. Each thread draws
+/// candidates from a generator seeded from `seed`, or randomly if there is none.
+pub(crate) fn generate_work_internal(
+    hash: &[u8],
+    threshold: u64,
+    max_iters: Option<u64>,
+    numcpus: usize,
+    seed: Option<u64>,
+    stop: &Stop,
+    hashes: &AtomicUsize,
+) -> Option<[u8; 8]> {
     let (tx,rx) = crossbeam_channel::bounded::<Option<[u8; 8]>>(numcpus);
-    let (donetx, donerx) = crossbeam_channel::bounded::<bool>(numcpus);
-    let has_max_iters = max_iters.is_some();
-    let max_iters = max_iters.unwrap_or(numcpus as u64 * 2);
+    let found = AtomicBool::new(false);
     crossbeam_utils::scoped::scope(|scope| {
-        for _ in 0..numcpus {
-            scope.spawn(|| {
-                let mut rng: XorShiftRng = SeedableRng::from_seed(rand::random::<[u32; 4]>());
-                let mut work = [0u8; 8];
-                let mut iters = 0u64;
-                let mut result_valid = false;
-                let mut done = donerx.try_recv().unwrap_or(false);
-                while !result_valid && !done && iters < max_iters/numcpus as u64 {
-                    work = rng.gen::<[u8; 8]>();
-                    let output = hash_work_internal(&work[..], hash);
-                    result_valid = check_result_threshold(&output);
-                    if has_max_iters {
-                        iters += 1;
+        for thread in 0..numcpus {
+            let budget = thread_budget(max_iters, thread, numcpus);
+            let (tx, found) = (&tx, &found);
+            scope.spawn(move || {
+                let rng_seed = match seed {
+                    Some(seed) => thread_seed(seed, thread),
+                    None => rand::random::<[u32; 4]>(),
+                };
+                let mut rng: XorShiftRng = SeedableRng::from_seed(rng_seed);
+                let mut tried = 0u64;
+                let mut result = None;
+                while budget.map(|b| tried < b).unwrap_or(true) {
+                    if found.load(Ordering::Relaxed) || stop.is_stopped() {
+                        break;
+                    }
+                    let work = rng.gen::<[u8; 8]>();
+                    let value = hash_work_internal(&work[..], hash);
+                    tried += 1;
+                    if tried % HASH_COUNT_INTERVAL == 0 {
+                        hashes.fetch_add(HASH_COUNT_INTERVAL as usize, Ordering::Relaxed);
+                    }
+                    if value >= threshold {
+                        found.store(true, Ordering::Relaxed);
+                        result = Some(work);
+                        break;
                     }
-                    done = donerx.try_recv().unwrap_or(false);
-                }
-                if done {
-                    return;
-                }
-                if result_valid {
-                    let _ = tx.send(Some(work)).is_ok();
-                } else {
-                    let _ = tx.send(None).is_ok();
                 }
+                hashes.fetch_add((tried % HASH_COUNT_INTERVAL) as usize, Ordering::Relaxed);
+                let _ = tx.send(result).is_ok();
             });
         }
-        let mut res = rx.recv().unwrap();
-        let mut msgs_resvd = 0;
-        while res.is_none() && msgs_resvd < numcpus-1 {
-            res = rx.recv().unwrap();
-            msgs_resvd += 1;
-        }
+        let mut res = None;
         for _ in 0..numcpus {
-            donetx.send(true).unwrap();
+            if let Some(work) = rx.recv().unwrap() {
+                res = Some(work);
+                break;
+            }
         }
         res
     })
 }
 
 /// Checks if a given `Work` value is valid for a given `InputHash` (usually a block hash or public key)
+/// using the default network threshold
 pub fn check_work(hash: &InputHash, work: &Work) -> bool {
-    let hash = hash.0;
-    let mut work_bytes = [0u8; 8];
-    LittleEndian::write_u64(&mut work_bytes, work.0);
-    let value = hash_work_internal(&work_bytes, &hash);
-    check_result_threshold(&value)
+    check_work_with_threshold(hash, work, DEFAULT_THRESHOLD)
+}
+
+/// Checks if a given `Work` value reaches `threshold` for a given `InputHash`
+pub fn check_work_with_threshold(hash: &InputHash, work: &Work, threshold: u64) -> bool {
+    work_value(hash, work) >= threshold
 }
 
 #[cfg(test)]
@@ -277,12 +368,38 @@ mod tests {
         assert!(valid == false);
     }
 
+    #[test]
+    fn computes_work_value() {
+        let hash = InputHash::from_hex("8D3E5F07BFF7B7484CDCB392F47009F62997253D28BD98B94BCED95F03C4DA09").unwrap();
+        let work = Work::from_hex("4effb6b0cd5625e2").unwrap();
+        let value = work_value(&hash, &work);
+        assert!(value >= DEFAULT_THRESHOLD);
+        assert!(check_work_with_threshold(&hash, &work, value));
+        assert!(!check_work_with_threshold(&hash, &work, value + 1));
+    }
+
+    #[test]
+    fn converts_multipliers() {
+        assert_eq!(to_multiplier(SEND_THRESHOLD, DEFAULT_THRESHOLD), 8.0);
+        assert_eq!(to_multiplier(RECEIVE_THRESHOLD, DEFAULT_THRESHOLD), 0.125);
+        assert_eq!(from_multiplier(8.0, DEFAULT_THRESHOLD), SEND_THRESHOLD);
+        assert_eq!(from_multiplier(1.0, DEFAULT_THRESHOLD), DEFAULT_THRESHOLD);
+    }
+
+    #[test]
+    fn generates_work_with_low_threshold() {
+        let hash = InputHash::from_hex("47F694A96653EB497709490776E492EFBB88EBC5C4E95CC0B2C9DCAB1930C36B").unwrap();
+        let work = generate_work_with_threshold(&hash, TEST_THRESHOLD, None).unwrap();
+        assert!(check_work_with_threshold(&hash, &work, TEST_THRESHOLD));
+    }
+
     #[test]
     fn generates_valid_work() {
         let hash = InputHash::from_hex("47F694A96653EB497709490776E492EFBB88EBC5C4E95CC0B2C9DCAB1930C36B").unwrap();
-        let work = generate_work(&hash, None).unwrap();
+        // This seed finds valid work after 14788 hashes
+        let work = WorkGenerator::new().single_threaded().with_seed(958).generate(&hash).unwrap();
         let work_str: String = work.clone().into();
-        println!("generated work: {}", work_str);
+        assert_eq!(work_str, "49bf97fbffd55294");
         let valid = check_work(&hash, &work);
         assert!(valid);
     }
@@ -0,0 +1,370 @@
+//! A work server speaking the JSON HTTP protocol of
+//! [nano-work-server](https://github.com/nanocurrency/nano-work-server).
+//!
+//! Requests are queued by priority and then by arrival, requests for a root which is already
+//! queued are merged into one generation, and results are cached per root.
+use hyper::{self, Body, Method, Request, Response, Server, StatusCode};
+use hyper::header::CONTENT_TYPE;
+use hyper::service::service_fn;
+use serde_json::{self, Value};
+
+use futures::{future, Future, Stream};
+use futures::sync::oneshot;
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use super::{spawn_work, work_value, to_multiplier, from_multiplier, InputHash, Stop, Work, DEFAULT_THRESHOLD};
+use error::*;
+
+/// The number of roots to remember results for
+const CACHE_SIZE: usize = 4096;
+
+#[derive(Debug, PartialEq, Eq)]
+struct QueuedRoot {
+    priority: i64,
+    sequence: u64,
+    root: [u8; 32],
+}
+
+impl Ord for QueuedRoot {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Highest priority first, then oldest first
+        self.priority.cmp(&other.priority)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+impl PartialOrd for QueuedRoot {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+struct Waiter {
+    difficulty: u64,
+    result: oneshot::Sender<Work>,
+}
+
+#[derive(Default)]
+struct Inner {
+    queue: BinaryHeap<QueuedRoot>,
+    sequence: u64,
+    waiters: HashMap<[u8; 32], Vec<Waiter>>,
+    active: Option<([u8; 32], Stop)>,
+    cache: HashMap<[u8; 32], Work>,
+    cache_order: VecDeque<[u8; 32]>,
+}
+
+impl Inner {
+    fn enqueue(&mut self, root: [u8; 32], priority: i64) {
+        self.sequence += 1;
+        let sequence = self.sequence;
+        self.queue.push(QueuedRoot { priority, sequence, root });
+    }
+
+    fn cache(&mut self, root: [u8; 32], work: Work) {
+        if self.cache.insert(root, work).is_none() {
+            self.cache_order.push_back(root);
+            if self.cache_order.len() > CACHE_SIZE {
+                if let Some(old) = self.cache_order.pop_front() {
+                    self.cache.remove(&old);
+                }
+            }
+        }
+    }
+}
+
+/// A queue of work requests served by a single generation at a time using all threads
+#[derive(Clone)]
+pub struct WorkQueue {
+    inner: Arc<Mutex<Inner>>,
+    available: Arc<Condvar>,
+}
+
+impl WorkQueue {
+    /// Create a queue and start a worker generating work on `threads` threads
+    pub fn new(threads: usize) -> Self {
+        let queue = WorkQueue {
+            inner: Arc::new(Mutex::new(Inner::default())),
+            available: Arc::new(Condvar::new()),
+        };
+        let worker = queue.clone();
+        thread::spawn(move || worker.run(threads));
+        queue
+    }
+
+    /// Request work for `root` reaching `difficulty`. Requests for a root which is already
+    /// queued or being generated share the same generation.
+    pub fn generate(&self, root: InputHash, difficulty: u64, priority: i64) -> oneshot::Receiver<Work> {
+        let (tx, rx) = oneshot::channel();
+        let key = *root.as_bytes();
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(&work) = inner.cache.get(&key) {
+            if work_value(&root, &work) >= difficulty {
+                let _ = tx.send(work);
+                return rx;
+            }
+        }
+        let merged = inner.waiters.contains_key(&key);
+        inner.waiters.entry(key).or_insert_with(Vec::new).push(Waiter { difficulty, result: tx });
+        if !merged {
+            inner.enqueue(key, priority);
+            self.available.notify_one();
+        }
+        rx
+    }
+
+    /// Cancel every request for `root`. Their receivers resolve as cancelled.
+    pub fn cancel(&self, root: &InputHash) {
+        let key = *root.as_bytes();
+        let mut inner = self.inner.lock().unwrap();
+        inner.waiters.remove(&key);
+        if let Some((active, ref stop)) = inner.active {
+            if active == key {
+                stop.stop();
+            }
+        }
+    }
+
+    fn run(&self, threads: usize) {
+        loop {
+            let (root, difficulty, handle) = {
+                let mut inner = self.inner.lock().unwrap();
+                let next = loop {
+                    match inner.queue.pop() {
+                        Some(queued) => {
+                            if inner.waiters.contains_key(&queued.root) {
+                                break queued;
+                            }
+                        },
+                        None => inner = self.available.wait(inner).unwrap(),
+                    }
+                };
+                let difficulty = inner.waiters[&next.root].iter()
+                    .map(|w| w.difficulty)
+                    .max()
+                    .unwrap_or(DEFAULT_THRESHOLD);
+                let root = InputHash::new(next.root);
+                let handle = spawn_work(&root, difficulty, threads, None);
+                inner.active = Some((next.root, handle.stop_token()));
+                (root, difficulty, handle)
+            };
+            let result = handle.wait().unwrap_or(None);
+            let mut inner = self.inner.lock().unwrap();
+            inner.active = None;
+            let key = *root.as_bytes();
+            if let Some(work) = result {
+                debug_assert!(work_value(&root, &work) >= difficulty);
+                inner.cache(key, work);
+                // Waiters which arrived with a higher difficulty during generation are requeued
+                let value = work_value(&root, &work);
+                let waiters = inner.waiters.remove(&key).unwrap_or_default();
+                let (done, remaining): (Vec<_>, Vec<_>) = waiters.into_iter()
+                    .partition(|w| w.difficulty <= value);
+                for waiter in done {
+                    let _ = waiter.result.send(work);
+                }
+                if !remaining.is_empty() {
+                    inner.waiters.insert(key, remaining);
+                    inner.enqueue(key, i64::max_value());
+                }
+            }
+        }
+    }
+}
+
+/// Bind a work server to `addr`, returning the bound address and a future serving requests,
+/// which fails if the server stops with an error
+pub fn serve(addr: &SocketAddr, threads: usize) -> Result<(SocketAddr, impl Future<Item=(), Error=hyper::Error>)> {
+    let queue = WorkQueue::new(threads);
+    let server = Server::try_bind(addr)?
+        .serve(move || {
+            let queue = queue.clone();
+            service_fn(move |req| handle_request(req, queue.clone()))
+        });
+    let local_addr = server.local_addr();
+    Ok((local_addr, server))
+}
+
+fn handle_request(req: Request<Body>, queue: WorkQueue)
+    -> Box<Future<Item=Response<Body>, Error=hyper::Error> + Send>
+{
+    if req.method() != &Method::POST {
+        let response = Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .body(Body::empty())
+            .unwrap();
+        return Box::new(future::ok(response));
+    }
+    Box::new(req.into_body().concat2().and_then(move |body| {
+        let result: Box<Future<Item=Value, Error=Error> + Send> = match serde_json::from_slice::<Value>(&body) {
+            Ok(request) => dispatch(&request, &queue),
+            Err(e) => Box::new(future::err(e.into())),
+        };
+        result.then(|res| {
+            let value = res.unwrap_or_else(|e| json!({ "error": format!("{}", e) }));
+            let response = Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(value.to_string()))
+                .unwrap();
+            Ok(response)
+        })
+    }))
+}
+
+fn dispatch(request: &Value, queue: &WorkQueue) -> Box<Future<Item=Value, Error=Error> + Send> {
+    let result = match request["action"].as_str() {
+        Some("work_generate") => work_generate(request, queue),
+        Some("work_cancel") => work_cancel(request, queue),
+        Some("work_validate") => work_validate(request),
+        Some(action) => Err(format!("Unknown action: {}", action).into()),
+        None => Err("Missing action".into()),
+    };
+    match result {
+        Ok(future) => future,
+        Err(e) => Box::new(future::err(e)),
+    }
+}
+
+fn parse_hash(request: &Value) -> Result<InputHash> {
+    match request["hash"].as_str() {
+        Some(hash) => InputHash::from_hex(hash.to_uppercase()),
+        None => bail!("Missing hash"),
+    }
+}
+
+/// Read the requested difficulty from either the `difficulty` or `multiplier` field
+fn parse_difficulty(request: &Value) -> Result<u64> {
+    if let Some(difficulty) = request["difficulty"].as_str() {
+        return u64::from_str_radix(difficulty, 16)
+            .map_err(|_| format!("Invalid difficulty: {}", difficulty).into());
+    }
+    let multiplier = match request["multiplier"] {
+        Value::String(ref s) => Some(s.parse::<f64>().map_err(|_| Error::from(format!("Invalid multiplier: {}", s)))?),
+        Value::Number(ref n) => n.as_f64(),
+        _ => None,
+    };
+    match multiplier {
+        Some(m) if m > 0.0 => Ok(from_multiplier(m, DEFAULT_THRESHOLD)),
+        Some(m) => bail!("Invalid multiplier: {}", m),
+        None => Ok(DEFAULT_THRESHOLD),
+    }
+}
+
+fn difficulty_fields(value: u64) -> (String, String) {
+    (format!("{:016x}", value), format!("{}", to_multiplier(value, DEFAULT_THRESHOLD)))
+}
+
+fn work_generate(request: &Value, queue: &WorkQueue) -> Result<Box<Future<Item=Value, Error=Error> + Send>> {
+    let hash = parse_hash(request)?;
+    let difficulty = parse_difficulty(request)?;
+    let priority = request["priority"].as_i64().unwrap_or(0);
+    let result = queue.generate(hash, difficulty, priority);
+    Ok(Box::new(result.then(move |res| match res {
+        Ok(work) => {
+            let (difficulty, multiplier) = difficulty_fields(work_value(&hash, &work));
+            Ok(json!({
+                "work": String::from(work),
+                "difficulty": difficulty,
+                "multiplier": multiplier,
+            }))
+        },
+        Err(_) => Err("Cancelled".into()),
+    })))
+}
+
+fn work_cancel(request: &Value, queue: &WorkQueue) -> Result<Box<Future<Item=Value, Error=Error> + Send>> {
+    let hash = parse_hash(request)?;
+    queue.cancel(&hash);
+    Ok(Box::new(future::ok(json!({}))))
+}
+
+fn work_validate(request: &Value) -> Result<Box<Future<Item=Value, Error=Error> + Send>> {
+    let hash = parse_hash(request)?;
+    let work = match request["work"].as_str() {
+        Some(work) => Work::from_hex(work.to_lowercase())?,
+        None => bail!("Missing work"),
+    };
+    let threshold = parse_difficulty(request)?;
+    let value = work_value(&hash, &work);
+    let (difficulty, multiplier) = difficulty_fields(value);
+    Ok(Box::new(future::ok(json!({
+        "valid": if value >= threshold { "1" } else { "0" },
+        "difficulty": difficulty,
+        "multiplier": multiplier,
+    }))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Client;
+    use tokio::runtime::Runtime;
+    use TEST_THRESHOLD;
+
+    const HASH: &str = "47F694A96653EB497709490776E492EFBB88EBC5C4E95CC0B2C9DCAB1930C36B";
+
+    fn request(rt: &mut Runtime, addr: &SocketAddr, body: Value) -> Value {
+        let req = Request::post(&*format!("http://{}", addr))
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = Client::new().request(req)
+            .and_then(|res| res.into_body().concat2());
+        let body = rt.block_on(response).unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[test]
+    fn generates_and_validates_over_http() {
+        let mut rt = Runtime::new().unwrap();
+        let (addr, server) = serve(&"127.0.0.1:0".parse().unwrap(), 2).unwrap();
+        rt.spawn(server.map_err(|e| panic!("Work server error: {}", e)));
+
+        let difficulty = format!("{:016x}", TEST_THRESHOLD);
+        let generated = request(&mut rt, &addr, json!({
+            "action": "work_generate",
+            "hash": HASH,
+            "difficulty": difficulty,
+        }));
+        let work = generated["work"].as_str().unwrap().to_owned();
+
+        let validated = request(&mut rt, &addr, json!({
+            "action": "work_validate",
+            "hash": HASH,
+            "work": work,
+            "difficulty": difficulty,
+        }));
+        assert_eq!(validated["valid"], "1");
+        assert_eq!(validated["difficulty"], generated["difficulty"]);
+
+        // A second request for the same root is answered from the cache
+        let cached = request(&mut rt, &addr, json!({
+            "action": "work_generate",
+            "hash": HASH,
+            "difficulty": difficulty,
+        }));
+        assert_eq!(cached["work"], work.as_str());
+    }
+
+    #[test]
+    fn merges_requests_for_the_same_root() {
+        let queue = WorkQueue::new(2);
+        let hash = InputHash::from_hex(HASH).unwrap();
+        let first = queue.generate(hash, TEST_THRESHOLD, 0);
+        let second = queue.generate(hash, TEST_THRESHOLD, 0);
+        assert_eq!(first.wait().unwrap(), second.wait().unwrap());
+    }
+
+    #[test]
+    fn cancels_requests() {
+        let queue = WorkQueue::new(2);
+        let hash = InputHash::from_hex(HASH).unwrap();
+        let result = queue.generate(hash, u64::max_value(), 0);
+        queue.cancel(&hash);
+        assert!(result.wait().is_err());
+    }
+}
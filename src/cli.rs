@@ -0,0 +1,422 @@
+//! Subcommands for working with keys, blocks and the data directory without starting the node.
+//! Every command prints its result as JSON on stdout.
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use data_encoding::{HEXUPPER, HEXUPPER_PERMISSIVE};
+use rand::{OsRng, Rng};
+use serde_json::{self, Value};
+
+use nano_lib_rs::block::{Block, BlockHash, BlockKind, BlockPayload, Link};
+use nano_lib_rs::keys::{Account, Address, PrivateKey, PublicKey, Seed, Signature};
+use nano_lib_rs::message::NetworkKind;
+use nanopow_rs::Work;
+
+use std::io::{self, BufRead, Read};
+use std::net::SocketAddr;
+use std::path::Path;
+
+use data_dir::DataDir;
+use ledger::Ledger;
+use node::peers::PeerFile;
+use rpc;
+use wallet::{Wallet, WalletStore};
+use error::*;
+
+pub fn subcommands() -> Vec<App<'static, 'static>> {
+    let block_arg = Arg::with_name("block")
+        .value_name("JSON")
+        .required(true)
+        .help("A state block in the RPC JSON format, or - to read it from stdin");
+    vec![
+        SubCommand::with_name("key")
+            .about("Create and inspect private keys")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("create")
+                .about("Generate a random private key"))
+            .subcommand(SubCommand::with_name("expand")
+                .about("Show the public key and account of a private key")
+                .arg(Arg::with_name("private").value_name("PRIVATE_KEY").required(true))),
+        SubCommand::with_name("account")
+            .about("Convert between public keys and accounts")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("address")
+                .about("Show the account address of a public key")
+                .arg(Arg::with_name("public").value_name("PUBLIC_KEY").required(true))),
+        SubCommand::with_name("block")
+            .about("Hash and sign blocks")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("hash")
+                .about("Compute the hash of a block")
+                .arg(block_arg.clone()))
+            .subcommand(SubCommand::with_name("sign")
+                .about("Sign a block with the private key of its account")
+                .arg(block_arg)
+                .arg(Arg::with_name("key")
+                    .long("key")
+                    .value_name("PRIVATE_KEY")
+                    .required(true)
+                    .help("The private key of the block's account"))),
+        SubCommand::with_name("ledger")
+            .about("Read the saved ledger")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("info")
+                .about("Count the accounts and blocks in the ledger"))
+            .subcommand(SubCommand::with_name("account")
+                .about("Show the state and pending sends of an account")
+                .arg(Arg::with_name("account").value_name("ACCOUNT").required(true)))
+            .subcommand(SubCommand::with_name("export")
+                .about("Print every block in the order it was applied, one JSON object per line")),
+        SubCommand::with_name("peers")
+            .about("Read the saved peers, or the traffic statistics of a running node")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("list")
+                .about("List the peers saved by the last run"))
+            .subcommand(SubCommand::with_name("stats")
+                .about("Ask a running node for the traffic of its peers, or of a single peer")
+                .arg(Arg::with_name("peer").value_name("PEER").help("The address of a peer, e.g. [::ffff:192.0.2.1]:7075"))
+                .arg(Arg::with_name("details")
+                    .long("details")
+                    .conflicts_with("peer")
+                    .help("List every active peer with its traffic instead of the totals"))
+                .arg(Arg::with_name("rpc-addr")
+                    .long("rpc-addr")
+                    .value_name("ADDR")
+                    .default_value("[::1]:7076")
+                    .help("The RPC address of the node"))),
+        SubCommand::with_name("wallet")
+            .about("Manage the wallets in the data directory")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("create")
+                .about("Create a wallet with a random seed, or an imported one. The password is read from stdin.")
+                .arg(Arg::with_name("seed")
+                    .long("seed")
+                    .help("Import a seed, read from stdin after the password, instead of generating one")))
+            .subcommand(SubCommand::with_name("list")
+                .about("List every wallet and its accounts"))
+            .subcommand(SubCommand::with_name("add-key")
+                .about("Add a private key to a wallet. The password is read from stdin.")
+                .arg(Arg::with_name("wallet").value_name("WALLET").required(true))
+                .arg(Arg::with_name("private").value_name("PRIVATE_KEY").required(true))),
+    ]
+}
+
+/// Run the subcommand in `matches`. Commands which read the data directory lock it, so they
+/// cannot be used while a node is running on the same directory. `peers stats` is the
+/// exception, asking the running node over RPC instead.
+pub fn run(matches: &ArgMatches, network: NetworkKind, data_dir_path: &Path) -> Result<()> {
+    let output = match matches.subcommand() {
+        ("key", Some(m)) => key(m)?,
+        ("account", Some(m)) => account(m)?,
+        ("block", Some(m)) => block(m)?,
+        ("ledger", Some(m)) => ledger(m, network, &DataDir::open(data_dir_path)?)?,
+        ("peers", Some(m)) => peers(m, data_dir_path)?,
+        ("wallet", Some(m)) => wallet(m, &DataDir::open(data_dir_path)?)?,
+        _ => unreachable!(),
+    };
+    if !output.is_null() {
+        println!("{}", serde_json::to_string_pretty(&output)?);
+    }
+    Ok(())
+}
+
+fn key(matches: &ArgMatches) -> Result<Value> {
+    let key = match matches.subcommand() {
+        ("create", _) => {
+            let mut bytes = [0u8; 32];
+            OsRng::new()?.fill_bytes(&mut bytes);
+            PrivateKey::from_bytes(bytes)?
+        },
+        ("expand", Some(m)) => PrivateKey::from_hex(m.value_of("private").unwrap())?,
+        _ => unreachable!(),
+    };
+    let account: Account = key.clone().into();
+    Ok(json!({
+        "private": key.to_hex(),
+        "public": HEXUPPER.encode(account.public_key.as_bytes()),
+        "account": account.address.0,
+    }))
+}
+
+fn account(matches: &ArgMatches) -> Result<Value> {
+    match matches.subcommand() {
+        ("address", Some(m)) => {
+            let key = parse_public_key(m.value_of("public").unwrap())?;
+            Ok(json!({ "account": Address::from(key).0 }))
+        },
+        _ => unreachable!(),
+    }
+}
+
+fn block(matches: &ArgMatches) -> Result<Value> {
+    match matches.subcommand() {
+        ("hash", Some(m)) => {
+            let mut block = block_from_json(&read_block_arg(m)?)?;
+            Ok(json!({ "hash": String::from(block.hash(true)?) }))
+        },
+        ("sign", Some(m)) => {
+            let mut block = block_from_json(&read_block_arg(m)?)?;
+            let key = PrivateKey::from_hex(m.value_of("key").unwrap())?;
+            if let Some(BlockPayload::State { ref account, .. }) = block.payload {
+                if *account != key.public_key() {
+                    bail!("The key does not belong to the block's account");
+                }
+            }
+            block.sign(&key)?;
+            block_to_json(&mut block)
+        },
+        _ => unreachable!(),
+    }
+}
+
+fn ledger(matches: &ArgMatches, network: NetworkKind, data_dir: &DataDir) -> Result<Value> {
+    let ledger = Ledger::for_network(network).load(data_dir.ledger_path())?;
+    match matches.subcommand() {
+        ("info", _) => Ok(json!({
+            "accounts": ledger.account_count(),
+            "blocks": ledger.block_count(),
+        })),
+        ("account", Some(m)) => {
+            let address = m.value_of("account").unwrap();
+            let account = Address(address.to_owned()).to_public_key()?;
+            let info = ledger.account_info(&account)
+                .ok_or_else(|| Error::from(ErrorKind::AccountNotOpened(address.to_owned())))?;
+            let pending: Vec<Value> = ledger.pending_for(&account).into_iter()
+                .map(|(hash, pending)| json!({
+                    "hash": String::from(hash),
+                    "source": Address::from(pending.source).0,
+                    "amount": pending.amount.to_string(),
+                }))
+                .collect();
+            Ok(json!({
+                "frontier": String::from(info.head),
+                "open_block": String::from(info.open_block),
+                "representative": Address::from(info.representative).0,
+                "balance": info.balance.to_string(),
+                "block_count": info.block_count,
+                "pending": pending,
+            }))
+        },
+        ("export", _) => {
+            // Printed as it goes rather than collected, since the ledger may be large
+            for block in ledger.blocks() {
+                println!("{}", block_to_json(&mut block.clone())?);
+            }
+            Ok(Value::Null)
+        },
+        _ => unreachable!(),
+    }
+}
+
+fn peers(matches: &ArgMatches, data_dir_path: &Path) -> Result<Value> {
+    match matches.subcommand() {
+        ("list", _) => {
+            let data_dir = DataDir::open(data_dir_path)?;
+            Ok(serde_json::to_value(PeerFile::load(data_dir.peers_path())?)?)
+        },
+        // Traffic is only kept in memory, so it comes from the node itself
+        ("stats", Some(m)) => {
+            let addr = m.value_of("rpc-addr").unwrap().parse::<SocketAddr>()?;
+            let request = match m.value_of("peer") {
+                Some(peer) => json!({ "action": "stats", "peer": peer }),
+                None if m.is_present("details") => json!({ "action": "peers", "peer_details": true }),
+                None => json!({ "action": "stats" }),
+            };
+            rpc::call(addr, &request)
+        },
+        _ => unreachable!(),
+    }
+}
+
+fn wallet(matches: &ArgMatches, data_dir: &DataDir) -> Result<Value> {
+    let mut wallets = WalletStore::open(data_dir.wallets_path())?;
+    match matches.subcommand() {
+        ("create", Some(m)) => {
+            let stdin = io::stdin();
+            let mut input = stdin.lock();
+            let password = read_secret(&mut input, "Password")?;
+            if password.is_empty() {
+                bail!("The wallet password must not be empty");
+            }
+            let id = if m.is_present("seed") {
+                let seed = Seed::from_string(read_secret(&mut input, "Seed")?)?;
+                wallets.insert(Wallet::from_seed(seed, &password)?)?
+            } else {
+                wallets.create(&password)?
+            };
+            Ok(json!({ "wallet": id }))
+        },
+        ("list", _) => {
+            let mut ids = wallets.ids();
+            ids.sort();
+            let list: Vec<Value> = ids.iter()
+                .map(|id| {
+                    let accounts: Vec<String> = wallets.get(id).unwrap().accounts().into_iter()
+                        .map(|account| account.address.0)
+                        .collect();
+                    json!({ "wallet": id, "accounts": accounts })
+                })
+                .collect();
+            Ok(Value::Array(list))
+        },
+        ("add-key", Some(m)) => {
+            let id = m.value_of("wallet").unwrap();
+            let key = PrivateKey::from_hex(m.value_of("private").unwrap())?;
+            let account = {
+                let wallet = wallets.get_mut(id)?;
+                wallet.unlock(&read_secret(&mut io::stdin().lock(), "Password")?)?;
+                wallet.insert_adhoc(key)?
+            };
+            wallets.save(id)?;
+            Ok(json!({ "account": account.address.0 }))
+        },
+        _ => unreachable!(),
+    }
+}
+
+/// Read a secret from a line of `input`. Secrets are not taken as arguments, since those end up
+/// in the shell history and the process list.
+fn read_secret<R: BufRead>(input: &mut R, prompt: &str) -> Result<String> {
+    eprint!("{}: ", prompt);
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        bail!("Expected {} on stdin", prompt.to_lowercase());
+    }
+    Ok(line.trim_right_matches(|c| c == '\n' || c == '\r').to_owned())
+}
+
+fn read_block_arg(matches: &ArgMatches) -> Result<Value> {
+    let arg = matches.value_of("block").unwrap();
+    if arg == "-" {
+        let mut contents = String::new();
+        io::stdin().read_to_string(&mut contents)?;
+        Ok(serde_json::from_str(&contents)?)
+    } else {
+        Ok(serde_json::from_str(arg)?)
+    }
+}
+
+fn parse_public_key(s: &str) -> Result<PublicKey> {
+    let bytes = HEXUPPER_PERMISSIVE.decode(s.as_bytes())
+        .map_err(|_| Error::from(format!("Invalid public key: {}", s)))?;
+    PublicKey::from_bytes(&bytes)
+        .map_err(|_| format!("Invalid public key: {}", s).into())
+}
+
+/// Parse a state block from the JSON format used by the RPC of the reference node
+pub fn block_from_json(value: &Value) -> Result<Block> {
+    fn invalid(field: &str) -> Error {
+        ErrorKind::InvalidBlockJson(format!("missing or invalid field: {}", field)).into()
+    }
+    fn field<'a>(value: &'a Value, name: &str) -> Result<&'a str> {
+        value[name].as_str().ok_or_else(|| invalid(name))
+    }
+    fn bytes32(value: &Value, name: &str) -> Result<[u8; 32]> {
+        let bytes = HEXUPPER_PERMISSIVE.decode(field(value, name)?.as_bytes()).map_err(|_| invalid(name))?;
+        if bytes.len() != 32 {
+            return Err(invalid(name));
+        }
+        let mut buf = [0u8; 32];
+        buf.copy_from_slice(&bytes);
+        Ok(buf)
+    }
+    fn account(value: &Value, name: &str) -> Result<PublicKey> {
+        Address(field(value, name)?.to_owned()).to_public_key().map_err(|_| invalid(name))
+    }
+
+    if field(value, "type")? != "state" {
+        bail!(ErrorKind::InvalidBlockJson("only state blocks are supported".into()));
+    }
+    let payload = BlockPayload::State {
+        account: account(value, "account")?,
+        previous: BlockHash::from_bytes(bytes32(value, "previous")?)?,
+        representative: account(value, "representative")?,
+        balance: field(value, "balance")?.parse().map_err(|_| invalid("balance"))?,
+        link: Link::Unknown(bytes32(value, "link")?),
+    };
+    let signature = match value.get("signature") {
+        Some(_) => {
+            let bytes = HEXUPPER_PERMISSIVE.decode(field(value, "signature")?.as_bytes())
+                .map_err(|_| invalid("signature"))?;
+            Some(Signature::from_bytes(&bytes).map_err(|_| invalid("signature"))?)
+        },
+        None => None,
+    };
+    let work = match value.get("work") {
+        Some(_) => Some(Work::from_hex(field(value, "work")?.to_lowercase()).map_err(|_| invalid("work"))?),
+        None => None,
+    };
+    Ok(Block::new(BlockKind::State, Some(payload), signature, work))
+}
+
+/// Encode a state block in the JSON format used by the RPC of the reference node, with its hash
+pub fn block_to_json(block: &mut Block) -> Result<Value> {
+    let hash = block.hash(false)?;
+    let mut value = match block.payload {
+        Some(BlockPayload::State { ref account, ref previous, ref representative, balance, ref link }) => json!({
+            "type": "state",
+            "account": Address::from(*account).0,
+            "previous": String::from(*previous),
+            "representative": Address::from(*representative).0,
+            "balance": balance.to_string(),
+            "link": HEXUPPER.encode(link.as_bytes()),
+        }),
+        _ => bail!(ErrorKind::InvalidBlockJson("only state blocks are supported".into())),
+    };
+    if let Some(ref signature) = block.signature {
+        value["signature"] = json!(HEXUPPER.encode(&signature.to_bytes()));
+    }
+    if let Some(work) = block.work {
+        value["work"] = json!(work.to_string());
+    }
+    value["hash"] = json!(String::from(hash));
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_secrets_by_line() {
+        let mut input = io::Cursor::new("hunter2\r\n1234\n");
+        assert_eq!(read_secret(&mut input, "Password").unwrap(), "hunter2");
+        assert_eq!(read_secret(&mut input, "Seed").unwrap(), "1234");
+        assert!(read_secret(&mut input, "Seed").is_err());
+    }
+
+    #[test]
+    fn round_trips_block_json() {
+        let seed = Seed::from_string("1234567890123456789012345678901234567890123456789012345678901234").unwrap();
+        let key = PrivateKey::from_seed(seed, 0);
+        let address = Address::from(key.public_key()).0;
+        let json = json!({
+            "type": "state",
+            "account": address,
+            "previous": "47F694A96653EB497709490776E492EFBB88EBC5C4E95CC0B2C9DCAB1930C36B",
+            "representative": address,
+            "balance": "1000000",
+            "link": "0000000000000000000000000000000000000000000000000000000000000000",
+            "work": "49bf97fbffd55294",
+        });
+        let mut block = block_from_json(&json).unwrap();
+        block.sign(&key).unwrap();
+        let hash = block.hash(false).unwrap();
+        assert!(block.verify_signature(&key.public_key()).unwrap());
+
+        let encoded = block_to_json(&mut block).unwrap();
+        assert_eq!(encoded["hash"], json!(String::from(hash)));
+        assert_eq!(encoded["balance"], json!("1000000"));
+        assert_eq!(encoded["work"], json!("49bf97fbffd55294"));
+        let mut decoded = block_from_json(&encoded).unwrap();
+        assert_eq!(decoded.hash(false).unwrap(), hash);
+        assert_eq!(decoded.signature, block.signature);
+        assert_eq!(decoded.work, block.work);
+
+        assert!(block_from_json(&json!({ "type": "send" })).is_err());
+        let mut bad_balance = json.clone();
+        bad_balance["balance"] = json!("lots");
+        match block_from_json(&bad_balance) {
+            Err(Error(ErrorKind::InvalidBlockJson(_), _)) => {},
+            other => panic!("expected invalid block JSON: {:?}", other),
+        }
+    }
+}
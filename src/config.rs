@@ -0,0 +1,394 @@
+//! The node configuration file.
+//!
+//! `config.toml` only needs to contain the settings which differ from the defaults of the
+//! network being run. The file is laid over those defaults before it is parsed, so a beta node
+//! with an empty config file still gets the beta port and peers.
+use nano_lib_rs::message::NetworkKind;
+
+use hyper::Uri;
+use toml::{self, Value};
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::time::Duration;
+
+use data_dir::DataDir;
+use node::NodeConfig;
+use node::limits::{PeerLimits, RateLimits};
+use wallet::receiver::DEFAULT_RECEIVE_MINIMUM;
+use error::*;
+
+pub const LOG_LEVELS: &[&str] = &["off", "error", "warn", "info", "debug", "trace"];
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// One of `LOG_LEVELS`
+    pub log_level: String,
+    pub node: NodeSection,
+    pub rpc: RpcSection,
+    pub metrics: MetricsSection,
+    pub limits: LimitsSection,
+    pub work: WorkSection,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NodeSection {
+    /// The IP address to listen for messages on
+    pub listen_address: String,
+    pub port: u16,
+    /// Hostnames or IPs of peers to contact at startup, with an optional port
+    pub preconfigured_peers: Vec<String>,
+    /// Seconds between keepalives to every peer
+    pub keepalive_interval: u64,
+    /// Seconds without hearing from a peer before it is pruned
+    pub keepalive_cutoff: u64,
+    /// Seconds between checks for peers to prune
+    pub peer_prune_interval: u64,
+    /// Sends smaller than this many raw are not received automatically. A string since
+    /// TOML integers cannot hold every amount.
+    pub receive_minimum: String,
+    /// Misbehavior score at which a peer's IP is banned
+    pub ban_threshold: u32,
+    /// Seconds a misbehaving IP stays banned
+    pub ban_duration: u64,
+    /// Threads checking the work and signatures of received blocks, 0 for one per CPU
+    pub validation_threads: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RpcSection {
+    pub enabled: bool,
+    pub address: String,
+}
+
+/// The Prometheus `/metrics` endpoint
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsSection {
+    pub enabled: bool,
+    pub address: String,
+}
+
+/// Limits on the traffic and peers of a single host. Rates are messages per second from one
+/// IP, or one /64 for native IPv6, with bursts of twice the rate.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsSection {
+    /// Messages of every kind
+    pub messages_per_sec: u32,
+    pub keepalives_per_sec: u32,
+    pub publishes_per_sec: u32,
+    pub confirm_reqs_per_sec: u32,
+    pub max_peers_per_ip: usize,
+    /// Applied to IPv6 peers only
+    pub max_peers_per_subnet: usize,
+    /// The most messages waiting to be sent. When full, lower priority messages are dropped.
+    pub outbound_queue_size: usize,
+    /// Cap on the bytes sent per second, 0 for no cap. Keepalives are dropped while it is reached.
+    pub outbound_bytes_per_sec: u64,
+    /// The most received blocks waiting to be validated. When half full, confirm_reqs are
+    /// dropped, and when full, publishes too.
+    pub validation_queue_size: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WorkSection {
+    /// Threads to generate work on locally, 0 for one per CPU
+    pub threads: usize,
+    /// URLs of HTTP work peers
+    pub peers: Vec<String>,
+}
+
+impl Config {
+    /// The default configuration for `network`
+    pub fn default_for(network: NetworkKind) -> Self {
+        let (port, peers) = match network {
+            NetworkKind::Beta => (54000, vec!["rai-beta.raiblocks.net".to_owned()]),
+            NetworkKind::Test => (54000, Vec::new()),
+            _ => (7075, vec!["rai.raiblocks.net".to_owned()]),
+        };
+        Config {
+            log_level: "info".to_owned(),
+            node: NodeSection {
+                listen_address: "::".to_owned(),
+                port,
+                preconfigured_peers: peers,
+                keepalive_interval: 60,
+                keepalive_cutoff: 300,
+                peer_prune_interval: 120,
+                receive_minimum: DEFAULT_RECEIVE_MINIMUM.to_string(),
+                ban_threshold: 100,
+                ban_duration: 3600,
+                validation_threads: 0,
+            },
+            rpc: RpcSection {
+                enabled: false,
+                address: "[::1]:7076".to_owned(),
+            },
+            metrics: MetricsSection {
+                enabled: false,
+                address: "[::1]:7077".to_owned(),
+            },
+            limits: LimitsSection {
+                messages_per_sec: 200,
+                keepalives_per_sec: 2,
+                publishes_per_sec: 100,
+                confirm_reqs_per_sec: 100,
+                max_peers_per_ip: 4,
+                max_peers_per_subnet: 16,
+                outbound_queue_size: 2048,
+                outbound_bytes_per_sec: 0,
+                validation_queue_size: 4096,
+            },
+            work: WorkSection {
+                threads: 0,
+                peers: Vec::new(),
+            },
+        }
+    }
+
+    /// Parse a config file laid over the defaults for `network`
+    pub fn from_toml(contents: &str, network: NetworkKind) -> Result<Self> {
+        let file: Value = toml::from_str(contents)?;
+        let mut merged = Value::try_from(Config::default_for(network))?;
+        merge(&mut merged, file);
+        let config: Config = merged.try_into()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Load the config file at `path` for `network`, using the defaults if it does not exist
+    pub fn load<P: AsRef<Path>>(path: P, network: NetworkKind) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Config::default_for(network));
+        }
+        let contents = fs::read_to_string(path)?;
+        Config::from_toml(&contents, network)
+            .chain_err(|| format!("Failed to load config from {}", path.display()))
+    }
+
+    /// Write the defaults for `network` to `path`, refusing to replace an existing file
+    pub fn write_defaults<P: AsRef<Path>>(path: P, network: NetworkKind) -> Result<()> {
+        let contents = toml::to_string_pretty(&Config::default_for(network))?;
+        let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        file.write_all(contents.as_bytes())?;
+        Ok(())
+    }
+
+    /// Check every setting, reporting the first one which is invalid
+    pub fn validate(&self) -> Result<()> {
+        fn invalid<T>(setting: &str, reason: String) -> Result<T> {
+            Err(ErrorKind::InvalidConfig(setting.to_owned(), reason).into())
+        }
+        if !LOG_LEVELS.contains(&self.log_level.as_str()) {
+            return invalid("log_level", format!("expected one of {}", LOG_LEVELS.join(", ")));
+        }
+        if let Err(e) = self.node.listen_address.parse::<IpAddr>() {
+            return invalid("node.listen_address", e.to_string());
+        }
+        if self.node.port == 0 {
+            return invalid("node.port", "must not be 0".into());
+        }
+        if self.node.keepalive_interval == 0 {
+            return invalid("node.keepalive_interval", "must be at least 1 second".into());
+        }
+        if self.node.keepalive_cutoff <= self.node.keepalive_interval {
+            return invalid("node.keepalive_cutoff", "must be longer than node.keepalive_interval".into());
+        }
+        if self.node.peer_prune_interval == 0 {
+            return invalid("node.peer_prune_interval", "must be at least 1 second".into());
+        }
+        if self.node.receive_minimum.parse::<u128>().is_err() {
+            return invalid("node.receive_minimum", "expected an amount in raw".into());
+        }
+        if self.node.ban_threshold == 0 {
+            return invalid("node.ban_threshold", "must be at least 1".into());
+        }
+        if self.node.ban_duration == 0 {
+            return invalid("node.ban_duration", "must be at least 1 second".into());
+        }
+        if let Err(e) = self.rpc.address.parse::<SocketAddr>() {
+            return invalid("rpc.address", e.to_string());
+        }
+        if let Err(e) = self.metrics.address.parse::<SocketAddr>() {
+            return invalid("metrics.address", e.to_string());
+        }
+        let rates = [
+            ("limits.messages_per_sec", self.limits.messages_per_sec),
+            ("limits.keepalives_per_sec", self.limits.keepalives_per_sec),
+            ("limits.publishes_per_sec", self.limits.publishes_per_sec),
+            ("limits.confirm_reqs_per_sec", self.limits.confirm_reqs_per_sec),
+        ];
+        for &(setting, rate) in rates.iter() {
+            if rate == 0 {
+                return invalid(setting, "must be at least 1".into());
+            }
+        }
+        if self.limits.max_peers_per_ip == 0 {
+            return invalid("limits.max_peers_per_ip", "must be at least 1".into());
+        }
+        if self.limits.max_peers_per_subnet < self.limits.max_peers_per_ip {
+            return invalid("limits.max_peers_per_subnet", "must be at least limits.max_peers_per_ip".into());
+        }
+        if self.limits.outbound_queue_size == 0 {
+            return invalid("limits.outbound_queue_size", "must be at least 1".into());
+        }
+        if self.limits.validation_queue_size < 2 {
+            return invalid("limits.validation_queue_size", "must be at least 2".into());
+        }
+        for peer in self.work.peers.iter() {
+            if let Err(e) = peer.parse::<Uri>() {
+                return invalid("work.peers", format!("{}: {}", peer, e));
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolve the settings into a `NodeConfig`. Preconfigured peers are looked up in DNS; ones
+    /// which cannot be resolved are skipped, since the node may still have saved peers.
+    pub fn to_node_config(&self, network: NetworkKind, data_dir: &DataDir) -> Result<NodeConfig> {
+        self.validate()?;
+        let listen_ip: IpAddr = self.node.listen_address.parse()?;
+        let mut peers = Vec::new();
+        for host in self.node.preconfigured_peers.iter() {
+            match resolve_peer(host, self.node.port) {
+                Ok(addrs) => peers.extend(addrs),
+                Err(e) => warn!("Could not resolve preconfigured peer {}: {}", host, e),
+            }
+        }
+        let work_threads = match self.work.threads {
+            0 => ::num_cpus::get(),
+            n => n,
+        };
+        let validation_threads = match self.node.validation_threads {
+            0 => ::num_cpus::get(),
+            n => n,
+        };
+        Ok(NodeConfig {
+            peers,
+            require_peers: !self.node.preconfigured_peers.is_empty(),
+            listen_addr: SocketAddr::new(listen_ip, self.node.port),
+            network,
+            ledger_path: data_dir.ledger_path(),
+            peers_path: data_dir.peers_path(),
+            wallets_path: data_dir.wallets_path(),
+            work_cache_path: data_dir.work_cache_path(),
+            rpc_addr: if self.rpc.enabled { Some(self.rpc.address.parse()?) } else { None },
+            metrics_addr: if self.metrics.enabled { Some(self.metrics.address.parse()?) } else { None },
+            receive_minimum: self.node.receive_minimum.parse().unwrap_or(DEFAULT_RECEIVE_MINIMUM),
+            work_peers: self.work.peers.iter().filter_map(|peer| peer.parse().ok()).collect(),
+            work_threads,
+            keepalive_interval: Duration::from_secs(self.node.keepalive_interval),
+            keepalive_cutoff: Duration::from_secs(self.node.keepalive_cutoff),
+            peer_prune_interval: Duration::from_secs(self.node.peer_prune_interval),
+            ban_threshold: self.node.ban_threshold,
+            ban_duration: Duration::from_secs(self.node.ban_duration),
+            rate_limits: RateLimits {
+                messages: self.limits.messages_per_sec,
+                keepalives: self.limits.keepalives_per_sec,
+                publishes: self.limits.publishes_per_sec,
+                confirm_reqs: self.limits.confirm_reqs_per_sec,
+            },
+            peer_limits: PeerLimits {
+                per_ip: self.limits.max_peers_per_ip,
+                per_subnet: self.limits.max_peers_per_subnet,
+            },
+            outbound_queue_size: self.limits.outbound_queue_size,
+            outbound_bytes_per_sec: self.limits.outbound_bytes_per_sec,
+            validation_threads,
+            validation_queue_size: self.limits.validation_queue_size,
+        })
+    }
+}
+
+/// Resolve `host`, which may be a hostname or IP with or without a port
+fn resolve_peer(host: &str, default_port: u16) -> Result<Vec<SocketAddr>> {
+    if let Ok(addr) = host.parse::<SocketAddr>() {
+        return Ok(vec![addr]);
+    }
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, default_port)]);
+    }
+    let addrs = if host.contains(':') {
+        host.to_socket_addrs()?
+    } else {
+        (host, default_port).to_socket_addrs()?
+    };
+    Ok(addrs.collect())
+}
+
+/// Recursively lay the tables of `overlay` over `base`
+fn merge(base: &mut Value, overlay: Value) {
+    match overlay {
+        Value::Table(overlay) => {
+            if let Value::Table(ref mut table) = *base {
+                for (key, value) in overlay {
+                    if table.contains_key(&key) {
+                        merge(table.get_mut(&key).unwrap(), value);
+                    } else {
+                        table.insert(key, value);
+                    }
+                }
+                return;
+            }
+            *base = Value::Table(overlay);
+        },
+        overlay => *base = overlay,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_defaults() {
+        for &network in [NetworkKind::Live, NetworkKind::Beta, NetworkKind::Test].iter() {
+            let defaults = Config::default_for(network);
+            defaults.validate().unwrap();
+            let contents = toml::to_string_pretty(&defaults).unwrap();
+            assert_eq!(Config::from_toml(&contents, network).unwrap(), defaults);
+        }
+    }
+
+    #[test]
+    fn lays_file_over_network_defaults() {
+        let contents = "log_level = \"debug\"\n[node]\nkeepalive_interval = 30\n[work]\npeers = [\"http://[::1]:7076\"]\n";
+        let config = Config::from_toml(contents, NetworkKind::Beta).unwrap();
+        assert_eq!(config.log_level, "debug");
+        assert_eq!(config.node.keepalive_interval, 30);
+        assert_eq!(config.node.keepalive_cutoff, 300);
+        assert_eq!(config.node.port, 54000);
+        assert_eq!(config.work.peers, vec!["http://[::1]:7076".to_owned()]);
+        assert!(!config.rpc.enabled);
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        assert!(Config::from_toml("[node]\nunknown_setting = 1\n", NetworkKind::Live).is_err());
+        assert!(Config::from_toml("[node]\nport = \"7075\"\n", NetworkKind::Live).is_err());
+        match Config::from_toml("[node]\nkeepalive_cutoff = 10\n", NetworkKind::Live) {
+            Err(Error(ErrorKind::InvalidConfig(setting, _), _)) => assert_eq!(setting, "node.keepalive_cutoff"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match Config::from_toml("[limits]\nmax_peers_per_ip = 8\nmax_peers_per_subnet = 4\n", NetworkKind::Live) {
+            Err(Error(ErrorKind::InvalidConfig(setting, _), _)) => assert_eq!(setting, "limits.max_peers_per_subnet"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match Config::from_toml("[node]\nban_threshold = 0\n", NetworkKind::Live) {
+            Err(Error(ErrorKind::InvalidConfig(setting, _), _)) => assert_eq!(setting, "node.ban_threshold"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match Config::from_toml("[node]\nreceive_minimum = \"lots\"\n", NetworkKind::Live) {
+            Err(Error(ErrorKind::InvalidConfig(setting, _), _)) => assert_eq!(setting, "node.receive_minimum"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! The data directory, holding everything the node stores on disk:
+//!
+//! ```text
+//! config.toml       settings, see `config`
+//! ledger.bin        every block in the ledger, in the order it was applied
+//! wallets/          one encrypted file per wallet
+//! work_cache.json   precomputed work for wallet accounts
+//! peers.json        peers seen in earlier runs
+//! log/              log files
+//! lock              held while a node has the directory open
+//! ```
+use nano_lib_rs::message::NetworkKind;
+
+use fs2::FileExt;
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use error::*;
+
+/// An open data directory. Only one `DataDir` can hold a directory at a time, across
+/// processes; the lock is released when it is dropped.
+#[derive(Debug)]
+pub struct DataDir {
+    path: PathBuf,
+    _lock: File,
+}
+
+impl DataDir {
+    /// The default data directory for `network`, `.nano-rs/<network>` in the home directory
+    pub fn default_path(network: NetworkKind) -> PathBuf {
+        let name = match network {
+            NetworkKind::Beta => "beta",
+            NetworkKind::Test => "test",
+            _ => "live",
+        };
+        let home = env::var_os("HOME")
+            .or_else(|| env::var_os("USERPROFILE"))
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("."));
+        home.join(".nano-rs").join(name)
+    }
+
+    /// Open the directory at `path`, creating it if needed. Fails if another node has it open.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        fs::create_dir_all(&path)?;
+        let mut lock = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path.join("lock"))?;
+        if lock.try_lock_exclusive().is_err() {
+            bail!(ErrorKind::DataDirLocked(path.display().to_string()));
+        }
+        lock.set_len(0)?;
+        writeln!(lock, "{}", ::std::process::id())?;
+        Ok(DataDir { path, _lock: lock })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn config_path(&self) -> PathBuf {
+        self.path.join("config.toml")
+    }
+
+    pub fn ledger_path(&self) -> PathBuf {
+        self.path.join("ledger.bin")
+    }
+
+    pub fn wallets_path(&self) -> PathBuf {
+        self.path.join("wallets")
+    }
+
+    pub fn work_cache_path(&self) -> PathBuf {
+        self.path.join("work_cache.json")
+    }
+
+    pub fn peers_path(&self) -> PathBuf {
+        self.path.join("peers.json")
+    }
+
+    pub fn log_path(&self) -> PathBuf {
+        self.path.join("log")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locks_the_directory() {
+        let path = env::temp_dir().join(format!("nano-rs-data-{}", ::rand::random::<u64>()));
+        {
+            let data_dir = DataDir::open(&path).unwrap();
+            assert_eq!(data_dir.wallets_path(), path.join("wallets"));
+            match DataDir::open(&path) {
+                Err(Error(ErrorKind::DataDirLocked(_), _)) => {},
+                other => panic!("expected the directory to be locked: {:?}", other),
+            }
+        }
+        // Dropping the first handle releases the lock
+        DataDir::open(&path).unwrap();
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -12,6 +12,91 @@ error_chain!{
             description("Error in Tokio Timeout")
             display("Error in tokio timeout: {}", inner)
         }
+        /// Attempted to use the keys of a locked wallet
+        WalletLocked {
+            description("Wallet is locked")
+            display("Wallet is locked")
+        }
+        /// The password given for a wallet was wrong
+        InvalidWalletPassword {
+            description("Invalid wallet password")
+            display("Invalid wallet password")
+        }
+        /// A wallet file could not be decoded or failed authentication
+        WalletCorrupted(reason: String) {
+            description("Wallet data is corrupted")
+            display("Wallet data is corrupted: {}", reason)
+        }
+        /// No wallet exists with the given id
+        UnknownWallet(id: String) {
+            description("Unknown wallet")
+            display("Unknown wallet: {}", id)
+        }
+        /// The account has no blocks in the ledger yet
+        AccountNotOpened(address: String) {
+            description("Account not opened")
+            display("Account has not been opened: {}", address)
+        }
+        /// The account balance is lower than the amount being sent
+        InsufficientBalance(balance: u128, amount: u128) {
+            description("Insufficient balance")
+            display("Insufficient balance: have {}, need {}", balance, amount)
+        }
+        /// The block is not a pending send to the account
+        NotPending(hash: String) {
+            description("Block is not pending")
+            display("Block is not pending for this account: {}", hash)
+        }
+        /// Work generation stopped without finding a valid result
+        WorkGenerationFailed {
+            description("Failed to generate work")
+            display("Failed to generate work")
+        }
+        /// The ledger rejected a locally created block
+        BlockRejected(reason: String) {
+            description("Block was rejected by the ledger")
+            display("Block was rejected by the ledger: {}", reason)
+        }
+        /// An RPC request was malformed
+        InvalidRpcRequest(reason: String) {
+            description("Invalid RPC request")
+            display("Invalid RPC request: {}", reason)
+        }
+        /// Too many RPC requests are waiting for a worker
+        RpcBusy {
+            description("RPC server is busy")
+            display("RPC server is busy, try again later")
+        }
+        /// A block given as JSON could not be parsed
+        InvalidBlockJson(reason: String) {
+            description("Invalid block JSON")
+            display("Invalid block JSON: {}", reason)
+        }
+        /// Another node has the data directory open
+        DataDirLocked(path: String) {
+            description("Data directory is in use")
+            display("Data directory {} is in use by another node", path)
+        }
+        /// The saved ledger could not be loaded
+        LedgerCorrupted(reason: String) {
+            description("Ledger data is corrupted")
+            display("Ledger data is corrupted: {}", reason)
+        }
+        /// A setting in the config file is invalid
+        InvalidConfig(setting: String, reason: String) {
+            description("Invalid config setting")
+            display("Invalid config setting {}: {}", setting, reason)
+        }
+        /// A work peer answered with something other than valid work
+        InvalidWorkPeerResponse(peer: String) {
+            description("Invalid response from work peer")
+            display("Invalid response from work peer: {}", peer)
+        }
+        /// The account is not part of the wallet
+        UnknownWalletAccount(address: String) {
+            description("Account not found in wallet")
+            display("Account not found in wallet: {}", address)
+        }
     }
     links{
         NanoLibError(::nano_lib_rs::error::Error, ::nano_lib_rs::error::ErrorKind) #[doc = "An error occurred in nano-lib"];
@@ -22,6 +107,11 @@ error_chain!{
         IoError(::std::io::Error) #[doc = "An IO error occurred"];
         AddrParseError(::std::net::AddrParseError) #[doc = "An error occurred while parsing an address"];
         TokioTimerError(::tokio_timer::TimerError) #[doc = "An error occurred in a tokio timer"];
+        JsonError(::serde_json::Error) #[doc = "An error occurred while serializing/deserializing JSON"];
+        Argon2Error(::argon2::Error) #[doc = "An error occurred while deriving a key with Argon2"];
+        HyperError(::hyper::Error) #[doc = "An error occurred in the HTTP server"];
+        TomlDeError(::toml::de::Error) #[doc = "An error occurred while parsing TOML"];
+        TomlSerError(::toml::ser::Error) #[doc = "An error occurred while writing TOML"];
     }
 }
 
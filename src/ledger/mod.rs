@@ -0,0 +1,465 @@
+//! An in-memory ledger of account chains, validating and applying state blocks.
+//! It is saved to disk as the list of blocks in the order they were applied, and loaded
+//! by processing them again.
+use nano_lib_rs::block::{Block, BlockHash, BlockKind, BlockPayload};
+use nano_lib_rs::keys::{Address, PublicKey, SIGNATURE_LENGTH};
+use nano_lib_rs::message::NetworkKind;
+
+use bytes::Bytes;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+use error::*;
+
+/// The outcome of processing a block
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProcessResult {
+    /// The block was valid and has been added to the ledger
+    Progress,
+    /// The block is already in the ledger
+    Old,
+    /// The block is not a state block, which the ledger cannot process yet
+    Unsupported,
+    /// The signature does not match the account
+    BadSignature,
+    /// The work does not pass the network threshold
+    InsufficientWork,
+    /// The previous block is not in the ledger yet
+    GapPrevious,
+    /// The source block of a receive is not in the ledger yet
+    GapSource,
+    /// The previous block already has a successor
+    Fork,
+    /// The link does not refer to a pending send for this account
+    Unreceivable,
+    /// The balance change does not match the amount being received
+    BalanceMismatch,
+}
+
+/// The current state of an account chain
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AccountInfo {
+    pub head: BlockHash,
+    pub open_block: BlockHash,
+    pub representative: PublicKey,
+    pub balance: u128,
+    pub block_count: u64,
+}
+
+/// A send which has not been received yet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PendingInfo {
+    pub source: PublicKey,
+    pub amount: u128,
+}
+
+type AccountKey = [u8; 32];
+
+const LIVE_GENESIS_ACCOUNT: &str = "xrb_3t6k35gi95xu6tergt6p69ck76ogmitsa8mnijtpxm9fkcm736xtoncuohr3";
+const LIVE_GENESIS_BLOCK: &str = "991CF190094C00F0B68E2E5F75F6BEE95A2E0BD93CEAA4A6734DB9F19B728948";
+
+#[derive(Debug, Default)]
+pub struct Ledger {
+    genesis: Option<BlockHash>,
+    accounts: HashMap<AccountKey, AccountInfo>,
+    blocks: HashMap<BlockHash, Block>,
+    /// Pending sends, keyed by destination account and send block hash
+    pending: BTreeMap<(AccountKey, BlockHash), PendingInfo>,
+    /// Every block in the order it was applied, so dependencies come first when reloading
+    order: Vec<BlockHash>,
+}
+
+impl Ledger {
+    pub fn new() -> Self {
+        Ledger::default()
+    }
+
+    /// Create a ledger whose only account is the genesis account, holding the full supply
+    pub fn with_genesis(account: PublicKey, open_block: BlockHash, balance: u128) -> Self {
+        let mut ledger = Ledger::new();
+        ledger.genesis = Some(open_block);
+        ledger.accounts.insert(account.to_bytes(), AccountInfo {
+            head: open_block,
+            open_block,
+            representative: account,
+            balance,
+            block_count: 1,
+        });
+        ledger
+    }
+
+    /// A ledger starting from the genesis of the live network
+    pub fn live() -> Self {
+        let account = Address(LIVE_GENESIS_ACCOUNT.into()).to_public_key().unwrap();
+        let open_block = BlockHash::from_hex(LIVE_GENESIS_BLOCK).unwrap();
+        Ledger::with_genesis(account, open_block, u128::max_value())
+    }
+
+    /// The starting ledger for `network`. Only the live network has a known genesis.
+    pub fn for_network(network: NetworkKind) -> Self {
+        match network {
+            NetworkKind::Live => Ledger::live(),
+            _ => Ledger::new(),
+        }
+    }
+
+    pub fn account_count(&self) -> usize {
+        self.accounts.len()
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn account_info(&self, account: &PublicKey) -> Option<AccountInfo> {
+        self.accounts.get(&account.to_bytes()).cloned()
+    }
+
+    /// Every block except the genesis, in the order they were applied
+    pub fn blocks<'a>(&'a self) -> impl Iterator<Item=&'a Block> + 'a {
+        self.order.iter().map(move |hash| &self.blocks[hash])
+    }
+
+    pub fn block(&self, hash: &BlockHash) -> Option<&Block> {
+        self.blocks.get(hash)
+    }
+
+    pub fn contains_block(&self, hash: &BlockHash) -> bool {
+        self.blocks.contains_key(hash) || self.genesis == Some(*hash)
+    }
+
+    pub fn pending_info(&self, account: &PublicKey, hash: &BlockHash) -> Option<PendingInfo> {
+        self.pending.get(&(account.to_bytes(), *hash)).cloned()
+    }
+
+    /// All pending sends to `account`
+    pub fn pending_for(&self, account: &PublicKey) -> Vec<(BlockHash, PendingInfo)> {
+        let key = account.to_bytes();
+        self.pending.range((key, BlockHash::zero())..)
+            .take_while(|&(&(dest, _), _)| dest == key)
+            .map(|(&(_, hash), info)| (hash, *info))
+            .collect()
+    }
+
+    /// Validate a block against the ledger and apply it if it is valid
+    pub fn process(&mut self, block: Block) -> ProcessResult {
+        self.process_checked(block, false)
+    }
+
+    /// Like `process`, for a block which already passed `verify`
+    pub fn process_verified(&mut self, block: Block) -> ProcessResult {
+        self.process_checked(block, true)
+    }
+
+    fn process_checked(&mut self, mut block: Block, verified: bool) -> ProcessResult {
+        let hash = match block.hash(false) {
+            Ok(hash) => hash,
+            Err(_) => return ProcessResult::Unsupported,
+        };
+        if self.contains_block(&hash) {
+            return ProcessResult::Old;
+        }
+        let (account, previous, representative, balance, link) = match block.payload {
+            Some(BlockPayload::State { ref account, ref previous, ref representative, ref balance, ref link }) => {
+                (*account, *previous, *representative, *balance, *link.as_bytes())
+            },
+            _ => return ProcessResult::Unsupported,
+        };
+        if !verified {
+            if !block.verify_work().unwrap_or(false) {
+                return ProcessResult::InsufficientWork;
+            }
+            if !block.verify_signature(&account).unwrap_or(false) {
+                return ProcessResult::BadSignature;
+            }
+        }
+
+        let info = self.account_info(&account);
+        let prev_balance = match info {
+            Some(ref info) => {
+                if previous != info.head {
+                    return if self.contains_block(&previous) {
+                        ProcessResult::Fork
+                    } else {
+                        ProcessResult::GapPrevious
+                    };
+                }
+                info.balance
+            },
+            None => {
+                if !previous.is_zero() {
+                    return ProcessResult::GapPrevious;
+                }
+                0
+            },
+        };
+
+        let link_hash = BlockHash::from_bytes(&link[..]).unwrap();
+        if balance < prev_balance {
+            // Send: the link is the destination account
+            let destination = match PublicKey::from_bytes(&link[..]) {
+                Ok(key) => key,
+                Err(_) => return ProcessResult::Unreceivable,
+            };
+            self.pending.insert((destination.to_bytes(), hash), PendingInfo {
+                source: account,
+                amount: prev_balance - balance,
+            });
+        } else if balance > prev_balance || info.is_none() {
+            // Receive: the link is the hash of the send being received
+            match self.pending_info(&account, &link_hash) {
+                Some(pending) => {
+                    if balance - prev_balance != pending.amount {
+                        return ProcessResult::BalanceMismatch;
+                    }
+                    self.pending.remove(&(account.to_bytes(), link_hash));
+                },
+                None => {
+                    return if self.contains_block(&link_hash) {
+                        ProcessResult::Unreceivable
+                    } else {
+                        ProcessResult::GapSource
+                    };
+                },
+            }
+        } else if !link_hash.is_zero() {
+            // Representative change, which must not carry a link
+            return ProcessResult::BalanceMismatch;
+        }
+
+        let (open_block, block_count) = match info {
+            Some(info) => (info.open_block, info.block_count + 1),
+            None => (hash, 1),
+        };
+        self.accounts.insert(account.to_bytes(), AccountInfo {
+            head: hash,
+            open_block,
+            representative,
+            balance,
+            block_count,
+        });
+        self.blocks.insert(hash, block);
+        self.order.push(hash);
+        ProcessResult::Progress
+    }
+
+    /// Apply the blocks saved at `path` on top of this ledger. A missing file is an empty ledger.
+    pub fn load<P: AsRef<Path>>(mut self, path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(self);
+        }
+        let mut contents = Vec::new();
+        File::open(path)?.read_to_end(&mut contents)?;
+        let record_size = saved_block_size();
+        if contents.len() % record_size != 0 {
+            bail!(ErrorKind::LedgerCorrupted(format!("{} is not a whole number of blocks", path.display())));
+        }
+        let contents = Bytes::from(contents);
+        for offset in (0..contents.len()).step_by(record_size) {
+            let record = contents.slice(offset, offset + record_size);
+            let block = Block::deserialize_bytes(record, BlockKind::State)?;
+            match self.process(block) {
+                ProcessResult::Progress | ProcessResult::Old => {},
+                result => bail!(ErrorKind::LedgerCorrupted(format!("block {} was rejected: {:?}", offset / record_size, result))),
+            }
+        }
+        info!("Loaded {} blocks from {}", self.block_count(), path.display());
+        Ok(self)
+    }
+
+    /// Write every block to `path`, atomically replacing the previous file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.snapshot().save(path)
+    }
+
+    /// Every block in its saved form, to be written once the ledger is no longer borrowed
+    pub fn snapshot(&self) -> Snapshot {
+        let mut bytes = Vec::with_capacity(self.order.len() * saved_block_size());
+        for block in self.blocks() {
+            bytes.extend_from_slice(&block.serialize_bytes());
+        }
+        Snapshot(bytes)
+    }
+}
+
+/// The saved form of a ledger, taken by `Ledger::snapshot`
+#[derive(Debug)]
+pub struct Snapshot(Vec<u8>);
+
+impl Snapshot {
+    /// Write the blocks to `path`, atomically replacing the previous file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            writer.write_all(&self.0)?;
+            writer.flush()?;
+        }
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+/// The size of a saved state block, including its signature and work
+fn saved_block_size() -> usize {
+    BlockKind::State.size() + SIGNATURE_LENGTH + 8
+}
+
+/// The checks of `process` which need nothing from the ledger: the work, the block kind and the
+/// signature. They are the expensive part, so they can be run on other threads beforehand.
+/// Returns `Progress` if the block passed.
+pub fn verify(block: &mut Block) -> ProcessResult {
+    if !block.verify_work().unwrap_or(false) {
+        return ProcessResult::InsufficientWork;
+    }
+    let account = match block.payload {
+        Some(BlockPayload::State { ref account, .. }) => *account,
+        _ => return ProcessResult::Unsupported,
+    };
+    if !block.verify_signature(&account).unwrap_or(false) {
+        return ProcessResult::BadSignature;
+    }
+    ProcessResult::Progress
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nano_lib_rs::block::Link;
+    use nano_lib_rs::keys::{PrivateKey, Seed};
+    use nanopow_rs::Work;
+    use std::env;
+
+    const GENESIS_BLOCK: &str = "47F694A96653EB497709490776E492EFBB88EBC5C4E95CC0B2C9DCAB1930C36B";
+    /// Work for blocks following the genesis block
+    const GENESIS_WORK: &str = "49bf97fbffd55294";
+    /// Work for the open block of the second account
+    const OPEN_WORK: &str = "000000000343f92a";
+
+    fn keys() -> (PrivateKey, PrivateKey) {
+        let seed = Seed::from_string("1234567890123456789012345678901234567890123456789012345678901234").unwrap();
+        (PrivateKey::from_seed(seed.clone(), 0), PrivateKey::from_seed(seed, 1))
+    }
+
+    fn genesis() -> BlockHash {
+        BlockHash::from_hex(GENESIS_BLOCK).unwrap()
+    }
+
+    /// A ledger whose genesis account, holding 1000 raw, is the first account
+    fn genesis_ledger(key: &PrivateKey) -> Ledger {
+        Ledger::with_genesis(key.public_key(), genesis(), 1000)
+    }
+
+    fn state_block(key: &PrivateKey, previous: BlockHash, balance: u128, link: Link, work: &str) -> Block {
+        let payload = BlockPayload::State {
+            account: key.public_key(),
+            previous,
+            representative: key.public_key(),
+            balance,
+            link,
+        };
+        let mut block = Block::new(BlockKind::State, Some(payload), None, Some(Work::from_hex(work).unwrap()));
+        block.sign(key).unwrap();
+        block
+    }
+
+    #[test]
+    fn sends_and_receives() {
+        let (first, second) = keys();
+        let mut ledger = genesis_ledger(&first);
+        let mut send = state_block(&first, genesis(), 900, Link::Destination(second.public_key()), GENESIS_WORK);
+        let send_hash = send.hash(false).unwrap();
+        assert_eq!(ledger.process(send.clone()), ProcessResult::Progress);
+        assert_eq!(ledger.process(send), ProcessResult::Old);
+        assert_eq!(ledger.account_info(&first.public_key()).unwrap().balance, 900);
+        assert_eq!(ledger.pending_for(&second.public_key()), vec![
+            (send_hash, PendingInfo { source: first.public_key(), amount: 100 }),
+        ]);
+
+        let mut open = state_block(&second, BlockHash::zero(), 100, Link::Source(send_hash), OPEN_WORK);
+        let open_hash = open.hash(false).unwrap();
+        assert_eq!(ledger.process(open), ProcessResult::Progress);
+        let info = ledger.account_info(&second.public_key()).unwrap();
+        assert_eq!((info.head, info.open_block, info.balance, info.block_count), (open_hash, open_hash, 100, 1));
+        assert!(ledger.pending_for(&second.public_key()).is_empty());
+        assert_eq!(ledger.block_count(), 2);
+    }
+
+    #[test]
+    fn rejects_forks_and_gaps() {
+        let (first, second) = keys();
+        let mut ledger = genesis_ledger(&first);
+        let send = state_block(&first, genesis(), 900, Link::Destination(second.public_key()), GENESIS_WORK);
+        assert_eq!(ledger.process(send), ProcessResult::Progress);
+
+        // Another successor of the genesis block
+        let fork = state_block(&first, genesis(), 800, Link::Destination(second.public_key()), GENESIS_WORK);
+        assert_eq!(ledger.process(fork), ProcessResult::Fork);
+        // The first block of an account must not have a previous block
+        let gap = state_block(&second, genesis(), 0, Link::Unknown([0u8; 32]), GENESIS_WORK);
+        assert_eq!(ledger.process(gap), ProcessResult::GapPrevious);
+        // A receive of a send the ledger has not seen
+        let unknown_source = BlockHash::from_bytes(&[1u8; 32][..]).unwrap();
+        let gap = state_block(&second, BlockHash::zero(), 100, Link::Source(unknown_source), OPEN_WORK);
+        assert_eq!(ledger.process(gap), ProcessResult::GapSource);
+        assert_eq!(ledger.block_count(), 1);
+    }
+
+    #[test]
+    fn checks_receive_amounts() {
+        let (first, second) = keys();
+        let mut ledger = genesis_ledger(&first);
+        let mut send = state_block(&first, genesis(), 900, Link::Destination(second.public_key()), GENESIS_WORK);
+        let send_hash = send.hash(false).unwrap();
+        assert_eq!(ledger.process(send), ProcessResult::Progress);
+
+        let short = state_block(&second, BlockHash::zero(), 50, Link::Source(send_hash), OPEN_WORK);
+        assert_eq!(ledger.process(short), ProcessResult::BalanceMismatch);
+        // The genesis block is in the ledger but is not a send to this account
+        let unreceivable = state_block(&second, BlockHash::zero(), 100, Link::Source(genesis()), OPEN_WORK);
+        assert_eq!(ledger.process(unreceivable), ProcessResult::Unreceivable);
+        assert_eq!(ledger.pending_info(&second.public_key(), &send_hash).map(|p| p.amount), Some(100));
+    }
+
+    #[test]
+    fn checks_work_and_signatures() {
+        let (first, second) = keys();
+        let mut ledger = genesis_ledger(&first);
+        let send = state_block(&first, genesis(), 900, Link::Destination(second.public_key()), "0000000000000000");
+        assert_eq!(ledger.process(send), ProcessResult::InsufficientWork);
+        let mut send = state_block(&first, genesis(), 900, Link::Destination(second.public_key()), GENESIS_WORK);
+        send.sign(&second).unwrap();
+        assert_eq!(ledger.process(send), ProcessResult::BadSignature);
+        assert_eq!(ledger.account_info(&first.public_key()).unwrap().balance, 1000);
+    }
+
+    #[test]
+    fn saves_and_loads_blocks() {
+        let (first, second) = keys();
+        let mut ledger = genesis_ledger(&first);
+        let mut send = state_block(&first, genesis(), 900, Link::Destination(second.public_key()), GENESIS_WORK);
+        let send_hash = send.hash(false).unwrap();
+        let open = state_block(&second, BlockHash::zero(), 100, Link::Source(send_hash), OPEN_WORK);
+        assert_eq!(ledger.process(send), ProcessResult::Progress);
+        assert_eq!(ledger.process(open), ProcessResult::Progress);
+
+        let path = env::temp_dir().join(format!("nano-rs-ledger-{}.bin", ::rand::random::<u64>()));
+        ledger.save(&path).unwrap();
+        let loaded = genesis_ledger(&first).load(&path).unwrap();
+        assert_eq!(loaded.block_count(), 2);
+        assert_eq!(loaded.account_info(&first.public_key()), ledger.account_info(&first.public_key()));
+        assert_eq!(loaded.account_info(&second.public_key()), ledger.account_info(&second.public_key()));
+        assert!(loaded.pending_for(&second.public_key()).is_empty());
+
+        // A truncated file is rejected rather than silently dropping blocks
+        let contents = fs::read(&path).unwrap();
+        fs::write(&path, &contents[..contents.len() - 1]).unwrap();
+        assert!(genesis_ledger(&first).load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,6 +1,7 @@
 extern crate tokio;
 extern crate tokio_io;
 extern crate tokio_timer;
+extern crate tokio_signal;
 extern crate net2;
 #[macro_use]
 extern crate futures;
@@ -8,6 +9,7 @@ extern crate futures;
 extern crate data_encoding;
 
 extern crate nano_lib_rs;
+extern crate nanopow_rs;
 
 #[macro_use]
 extern crate log;
@@ -23,68 +25,86 @@ extern crate bytes;
 extern crate rand;
 extern crate indexmap;
 
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate hyper;
+extern crate num_cpus;
+extern crate toml;
+extern crate fs2;
+
+extern crate argon2;
+extern crate chacha20_poly1305_aead;
+
 mod error;
 mod net;
 mod utils;
 mod node;
+mod ledger;
+mod wallet;
+mod rpc;
+mod metrics;
+mod work;
+mod config;
+mod data_dir;
+mod cli;
 
 use error::*;
 use node::{NodeConfig};
+use config::{Config, LOG_LEVELS};
+use data_dir::DataDir;
 
 use nano_lib_rs::message::NetworkKind;
 
-use std::net::{ToSocketAddrs, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc as std_mpsc;
+use std::thread;
+use std::time::Duration;
 
 use futures::{Future};
 
-use clap::{Arg, App};
-
-fn run(network: NetworkKind) -> Result<()> {
-    info!("Starting nano-rs!");
-
-    // TODO: Figure out why beta doesn't work and add test
-    let port = match network {
-        NetworkKind::Beta => 54000,
-        _ => 7075
-    };
+use clap::{Arg, App, ArgMatches};
 
-    let listen_addr = format!("[::]:{}", port).parse()?;
-    let peers: Vec<SocketAddr> = format!("rai.raiblocks.net:{}", port).to_socket_addrs()?.collect();
-    if let None = peers.get(0) {
-        return Err("Could not connect to initial peer".into());
-    }
+/// How long to wait for the node's tasks to finish after a shutdown signal
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
 
-    let config = NodeConfig {
-        peers,
-        network,
-        listen_addr,
-    };
+fn run(config: NodeConfig) -> Result<()> {
+    info!("Starting nano-rs!");
 
     let mut runtime = tokio::runtime::Runtime::new()?;
     let handle = runtime.handle().clone();
-    let node = node::run(config, &handle)?;
+    let (node, future) = node::run(config, &handle)?;
 
-    runtime.spawn(node);
-    runtime.shutdown_on_idle().wait().unwrap();
+    // Run until a signal arrives, or until the node stops by itself because its socket failed
+    let signal = node::shutdown::wait_for_signal().map(|()| true);
+    let stopped = future.then(|_| Ok(false));
+    let signalled = runtime.block_on(signal.select(stopped).map(|(signalled, _)| signalled).map_err(|(e, _)| e))?;
 
     info!("Stopping nano-rs!");
+    node.shutdown();
+
+    // Open RPC connections can keep the runtime busy, so only wait so long for it to go idle
+    let (stopped_tx, stopped_rx) = std_mpsc::channel();
+    thread::spawn(move || {
+        let _ = runtime.shutdown_on_idle().wait();
+        let _ = stopped_tx.send(());
+    });
+    if stopped_rx.recv_timeout(SHUTDOWN_TIMEOUT).is_err() {
+        warn!("Tasks still running after {} seconds, exiting anyway", SHUTDOWN_TIMEOUT.as_secs());
+    }
+    node.save();
+
+    if !signalled {
+        bail!("The node stopped after a fatal network error");
+    }
+    info!("Stopped nano-rs");
     Ok(())
 }
 
-fn setup_logger(log_level: log::LevelFilter) -> Result<()> {
-    use std::fs::create_dir;
-    let base_path: &str = match create_dir("log") {
-        Ok(_) => {
-            "log/"
-        },
-        Err(e) => {
-            if e.kind() == std::io::ErrorKind::AlreadyExists {
-                "log/"
-            } else {
-                ""
-            }
-        }
-    };
+fn setup_logger(log_level: log::LevelFilter, log_path: &Path) -> Result<()> {
+    std::fs::create_dir_all(log_path)?;
     fern::Dispatch::new()
         .format(|out, message, record| {
             out.finish(format_args!(
@@ -98,22 +118,68 @@ fn setup_logger(log_level: log::LevelFilter) -> Result<()> {
         .level(log_level)
         .level_for("tokio_reactor", log::LevelFilter::Error)
         .chain(std::io::stderr())
-        .chain(fern::log_file(format!("{}nano-rs__{}.log", base_path, chrono::Local::now().format("%Y-%m-%d__%H-%M-%S")))?)
+        .chain(fern::log_file(log_path.join(format!("nano-rs__{}.log", chrono::Local::now().format("%Y-%m-%d__%H-%M-%S"))))?)
         .apply()?;
     Ok(())
 }
 
+/// Load the config file for `network` and apply the command line overrides on top
+fn load_config(matches: &ArgMatches, network: NetworkKind, data_dir: &DataDir) -> Result<Config> {
+    fn invalid(setting: &str, value: &str) -> Error {
+        ErrorKind::InvalidConfig(setting.to_owned(), format!("invalid value: {}", value)).into()
+    }
+    let config_path = matches.value_of("config").map(PathBuf::from).unwrap_or_else(|| data_dir.config_path());
+    let mut config = Config::load(config_path, network)?;
+    if let Some(level) = matches.value_of("log-level") {
+        config.log_level = level.to_lowercase();
+    }
+    if let Some(port) = matches.value_of("port") {
+        config.node.port = port.parse().map_err(|_| invalid("node.port", port))?;
+    }
+    if let Some(peers) = matches.values_of("peer") {
+        config.node.preconfigured_peers = peers.map(String::from).collect();
+    }
+    if let Some(addr) = matches.value_of("rpc-addr") {
+        config.rpc.enabled = true;
+        config.rpc.address = addr.to_owned();
+    }
+    if let Some(addr) = matches.value_of("metrics-addr") {
+        config.metrics.enabled = true;
+        config.metrics.address = addr.to_owned();
+    }
+    if let Some(peers) = matches.values_of("work-peer") {
+        config.work.peers = peers.map(String::from).collect();
+    }
+    if let Some(threads) = matches.value_of("work-threads") {
+        config.work.threads = threads.parse().map_err(|_| invalid("work.threads", threads))?;
+    }
+    config.validate()?;
+    Ok(config)
+}
+
 fn main() {
     let matches = App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
         .author("Gray Olson <gray@grayolson.com>")
         .about("An implementation of Nano in Rust using Tokio.")
+        .arg(Arg::with_name("config")
+            .short("c")
+            .long("config")
+            .value_name("PATH")
+            .help("The config file to load settings from (default config.toml in the data directory)"))
+        .arg(Arg::with_name("data-dir")
+            .short("d")
+            .long("data-dir")
+            .value_name("PATH")
+            .help("The directory to keep the ledger, wallets and logs in (default ~/.nano-rs/<network>)"))
+        .arg(Arg::with_name("generate-config")
+            .long("generate-config")
+            .help("Write the default settings for the network to the config file and exit"))
         .arg(Arg::with_name("log-level")
             .short("l")
             .long("log-level")
             .value_name("LOG_LEVEL")
-            .default_value("info")
-            .possible_values(&["off", "error", "warn", "info", "debug", "trace"])
+            .possible_values(LOG_LEVELS)
             .case_insensitive(true)
             .help("Set logging level (default Info)"))
         .arg(Arg::with_name("network")
@@ -123,6 +189,36 @@ fn main() {
             .default_value("live")
             .possible_values(&["live", "beta", "test"])
             .help("The nano network to connect to"))
+        .arg(Arg::with_name("port")
+            .short("p")
+            .long("port")
+            .value_name("PORT")
+            .help("The port to listen for messages on"))
+        .arg(Arg::with_name("peer")
+            .long("peer")
+            .value_name("HOST")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Contact this peer at startup instead of the configured peers"))
+        .arg(Arg::with_name("rpc-addr")
+            .long("rpc-addr")
+            .value_name("ADDR")
+            .help("Enable the RPC server on the given address, e.g. [::1]:7076"))
+        .arg(Arg::with_name("metrics-addr")
+            .long("metrics-addr")
+            .value_name("ADDR")
+            .help("Serve Prometheus metrics at /metrics on the given address, e.g. [::1]:7077"))
+        .arg(Arg::with_name("work-peer")
+            .long("work-peer")
+            .value_name("URL")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Also request work from a work_generate HTTP endpoint, e.g. http://[::1]:7076"))
+        .arg(Arg::with_name("work-threads")
+            .long("work-threads")
+            .value_name("N")
+            .help("The number of threads to generate work on locally (default one per CPU)"))
+        .subcommands(cli::subcommands())
         .get_matches();
 
     let network = match matches.value_of("network").unwrap() {
@@ -132,7 +228,58 @@ fn main() {
         _ => unreachable!(),
     };
 
-    let log_level = match matches.value_of("log-level").unwrap() {
+    let data_dir_path = matches.value_of("data-dir")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| DataDir::default_path(network));
+    if matches.subcommand_name().is_some() {
+        if let Err(e) = cli::run(&matches, network, &data_dir_path) {
+            eprintln!("{}", e);
+            for e in e.iter().skip(1) {
+                eprintln!("Caused by: {}", e);
+            }
+            ::std::process::exit(1);
+        }
+        return;
+    }
+
+    if matches.is_present("generate-config") {
+        let config_path = matches.value_of("config")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| data_dir_path.join("config.toml"));
+        let result = config_path.parent()
+            .map_or(Ok(()), |dir| std::fs::create_dir_all(dir).map_err(Error::from))
+            .and_then(|_| Config::write_defaults(&config_path, network));
+        match result {
+            Ok(()) => println!("Wrote default config to {}", config_path.display()),
+            Err(e) => {
+                eprintln!("Failed to write config to {}: {}", config_path.display(), e);
+                ::std::process::exit(1);
+            },
+        }
+        return;
+    }
+
+    // Held until the node exits so that no other node can use the directory
+    let data_dir = match DataDir::open(&data_dir_path) {
+        Ok(data_dir) => data_dir,
+        Err(e) => {
+            eprintln!("Failed to open data directory {}: {}", data_dir_path.display(), e);
+            ::std::process::exit(1);
+        },
+    };
+
+    let config = match load_config(&matches, network, &data_dir) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            for e in e.iter().skip(1) {
+                eprintln!("Caused by: {}", e);
+            }
+            ::std::process::exit(1);
+        },
+    };
+
+    let log_level = match config.log_level.as_str() {
         "off" => log::LevelFilter::Off,
         "error" => log::LevelFilter::Error,
         "warn" => log::LevelFilter::Warn,
@@ -143,7 +290,7 @@ fn main() {
     };
 
     // Setup logger
-    if let Err(e) = setup_logger(log_level) {
+    if let Err(e) = setup_logger(log_level, &data_dir.log_path()) {
         use std::io::Write;
         let stderr = &mut ::std::io::stderr();
         let errmsg = "Error writing to stderr";
@@ -152,7 +299,9 @@ fn main() {
     }
 
     // Run program and log errors from error-chain using logger
-    if let Err(ref e) = run(network) {
+    let result = config.to_node_config(network, &data_dir)
+        .and_then(run);
+    if let Err(ref e) = result {
 
         error!("Failed with error: {}", e);
 
@@ -0,0 +1,279 @@
+//! Counters and gauges describing the node, served over HTTP in the Prometheus text format.
+//!
+//! Counters are kept in `State::metrics` and bumped where the events happen. Gauges such as
+//! the peer count are read from the state when `/metrics` is scraped.
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::header::CONTENT_TYPE;
+use hyper::service::service_fn_ok;
+
+use futures::Future;
+
+use nano_lib_rs::message::{Message, MessageKind, MessagePayload};
+
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use ledger::ProcessResult;
+use node::State;
+use error::*;
+
+/// Every message kind with its label, in the order of their values
+pub const MESSAGE_KINDS: [(MessageKind, &str); 9] = [
+    (MessageKind::Invalid, "invalid"),
+    (MessageKind::NotAMessage, "not_a_message"),
+    (MessageKind::KeepAlive, "keepalive"),
+    (MessageKind::Publish, "publish"),
+    (MessageKind::ConfirmReq, "confirm_req"),
+    (MessageKind::ConfirmAck, "confirm_ack"),
+    (MessageKind::BulkPull, "bulk_pull"),
+    (MessageKind::BulkPush, "bulk_push"),
+    (MessageKind::FrontierReq, "frontier_req"),
+];
+
+/// Every block processing result with its label, in declaration order
+const PROCESS_RESULTS: [(ProcessResult, &str); 10] = [
+    (ProcessResult::Progress, "progress"),
+    (ProcessResult::Old, "old"),
+    (ProcessResult::Unsupported, "unsupported"),
+    (ProcessResult::BadSignature, "bad_signature"),
+    (ProcessResult::InsufficientWork, "insufficient_work"),
+    (ProcessResult::GapPrevious, "gap_previous"),
+    (ProcessResult::GapSource, "gap_source"),
+    (ProcessResult::Fork, "fork"),
+    (ProcessResult::Unreceivable, "unreceivable"),
+    (ProcessResult::BalanceMismatch, "balance_mismatch"),
+];
+
+const CONTENT_TYPE_TEXT: &str = "text/plain; version=0.0.4";
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    messages_received: [AtomicUsize; 9],
+    messages_sent: [AtomicUsize; 9],
+    bytes_received: AtomicUsize,
+    bytes_sent: AtomicUsize,
+    deserialize_errors: AtomicUsize,
+    peers_pruned: AtomicUsize,
+    peers_banned: AtomicUsize,
+    /// Datagrams from banned IPs, dropped before decoding
+    banned_datagrams: AtomicUsize,
+    /// Messages dropped for exceeding the rate limits of their sender, by type
+    rate_limited: [AtomicUsize; 9],
+    /// New peers refused because their IP or subnet already had too many
+    peers_rejected: AtomicUsize,
+    blocks_processed: [AtomicUsize; 10],
+    work_validation_failures: AtomicUsize,
+    /// Messages dropped from the outgoing queue, by type
+    messages_dropped: [AtomicUsize; 9],
+    /// Received blocks shed because the validation queue was full, by the type of their message
+    blocks_shed: [AtomicUsize; 9],
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    /// Count a received message. A message whose header failed to decode arrives with the
+    /// `Invalid` kind, and one whose body failed to decode with an `Invalid` payload.
+    pub fn message_received(&self, msg: &Message) {
+        let kind = msg.kind();
+        self.messages_received[kind as usize].fetch_add(1, Ordering::Relaxed);
+        if kind == MessageKind::Invalid || msg.payload == MessagePayload::Invalid {
+            self.deserialize_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count a message taken off the outgoing queue to be sent
+    pub fn message_sent(&self, kind: MessageKind) {
+        self.messages_sent[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn bytes_received(&self, bytes: usize) {
+        self.bytes_received.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn bytes_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count a message dropped because the outgoing queue was full or the bandwidth cap reached
+    pub fn message_dropped(&self, kind: MessageKind) {
+        self.messages_dropped[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn peers_pruned(&self, count: usize) {
+        self.peers_pruned.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn peer_banned(&self) {
+        self.peers_banned.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn banned_datagram(&self) {
+        self.banned_datagrams.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn message_rate_limited(&self, kind: MessageKind) {
+        self.rate_limited[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn peer_rejected(&self) {
+        self.peers_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn block_processed(&self, result: ProcessResult) {
+        self.blocks_processed[result as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn work_validation_failed(&self) {
+        self.work_validation_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn block_shed(&self, kind: MessageKind) {
+        self.blocks_shed[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Write the counters in the Prometheus text format
+    fn render(&self, out: &mut String) {
+        header(out, "nano_messages_received_total", "counter", "Messages received, by type");
+        for &(kind, label) in MESSAGE_KINDS.iter() {
+            sample(out, "nano_messages_received_total", Some(("kind", label)), load(&self.messages_received[kind as usize]));
+        }
+        header(out, "nano_messages_sent_total", "counter", "Messages sent, by type");
+        for &(kind, label) in MESSAGE_KINDS.iter() {
+            sample(out, "nano_messages_sent_total", Some(("kind", label)), load(&self.messages_sent[kind as usize]));
+        }
+        counter(out, "nano_received_bytes_total", "Bytes received from peers", load(&self.bytes_received));
+        counter(out, "nano_sent_bytes_total", "Bytes sent to peers", load(&self.bytes_sent));
+        counter(out, "nano_deserialize_errors_total", "Received messages which could not be decoded", load(&self.deserialize_errors));
+        counter(out, "nano_peers_pruned_total", "Peers moved to the inactive peers after going quiet", load(&self.peers_pruned));
+        counter(out, "nano_peers_banned_total", "IPs banned for misbehavior or over RPC", load(&self.peers_banned));
+        counter(out, "nano_banned_datagrams_total", "Datagrams dropped because the sender is banned", load(&self.banned_datagrams));
+        header(out, "nano_rate_limited_messages_total", "counter", "Messages dropped by the rate limits of their sender, by type");
+        for &(kind, label) in MESSAGE_KINDS.iter() {
+            sample(out, "nano_rate_limited_messages_total", Some(("kind", label)), load(&self.rate_limited[kind as usize]));
+        }
+        counter(out, "nano_peers_rejected_total", "New peers refused by the per IP and per subnet caps", load(&self.peers_rejected));
+        header(out, "nano_blocks_processed_total", "counter", "Blocks processed by the ledger, by result");
+        for &(result, label) in PROCESS_RESULTS.iter() {
+            sample(out, "nano_blocks_processed_total", Some(("result", label)), load(&self.blocks_processed[result as usize]));
+        }
+        counter(out, "nano_work_validation_failures_total", "Received blocks whose work did not validate", load(&self.work_validation_failures));
+        header(out, "nano_messages_dropped_total", "counter", "Outgoing messages dropped by the queue, by type");
+        for &(kind, label) in MESSAGE_KINDS.iter() {
+            sample(out, "nano_messages_dropped_total", Some(("kind", label)), load(&self.messages_dropped[kind as usize]));
+        }
+        header(out, "nano_blocks_shed_total", "counter", "Received blocks shed by the full validation queue, by message type");
+        for &(kind, label) in MESSAGE_KINDS.iter() {
+            sample(out, "nano_blocks_shed_total", Some(("kind", label)), load(&self.blocks_shed[kind as usize]));
+        }
+    }
+}
+
+fn load(counter: &AtomicUsize) -> usize {
+    counter.load(Ordering::Relaxed)
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, label: Option<(&str, &str)>, value: usize) {
+    let _ = match label {
+        Some((key, value_label)) => writeln!(out, "{}{{{}=\"{}\"}} {}", name, key, value_label, value),
+        None => writeln!(out, "{} {}", name, value),
+    };
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: usize) {
+    header(out, name, "counter", help);
+    sample(out, name, None, value);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: usize) {
+    header(out, name, "gauge", help);
+    sample(out, name, None, value);
+}
+
+/// Every metric of the node in the Prometheus text format
+pub fn render(state: &State) -> String {
+    let mut out = String::new();
+    state.metrics.render(&mut out);
+    gauge(&mut out, "nano_peers", "Peers heard from recently", state.peer_count());
+    gauge(&mut out, "nano_inactive_peers", "Peers which have gone quiet", state.inactive_peers.read().unwrap().len());
+    gauge(&mut out, "nano_ledger_blocks", "Blocks in the ledger", state.ledger.read().unwrap().block_count());
+    gauge(&mut out, "nano_outgoing_queue_depth", "Messages waiting to be sent", state.outgoing.len());
+    gauge(&mut out, "nano_validation_queue_depth", "Received blocks waiting to be validated", state.validation.len());
+    out
+}
+
+/// Bind to `addr` and return a future serving `/metrics`
+pub fn serve(addr: SocketAddr, state: Arc<State>) -> Result<impl Future<Item=(), Error=()>> {
+    let server = Server::try_bind(&addr)?
+        .serve(move || {
+            let state = state.clone();
+            service_fn_ok(move |req: Request<Body>| handle_request(&req, &state))
+        });
+    info!("Metrics listening on: {}", server.local_addr());
+    Ok(server.map_err(|e| error!("Metrics server error: {}", e)))
+}
+
+fn handle_request(req: &Request<Body>, state: &State) -> Response<Body> {
+    if req.method() != &Method::GET || req.uri().path() != "/metrics" {
+        return Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap();
+    }
+    Response::builder()
+        .header(CONTENT_TYPE, CONTENT_TYPE_TEXT)
+        .body(Body::from(render(state)))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nano_lib_rs::message::MessageBuilder;
+
+    #[test]
+    fn renders_counters() {
+        let metrics = Metrics::new();
+        let peer = "[::1]:7075".parse().unwrap();
+        metrics.message_received(&MessageBuilder::new(MessageKind::KeepAlive)
+            .with_payload(MessagePayload::KeepAlive(vec![peer]))
+            .build());
+        metrics.message_received(&MessageBuilder::new(MessageKind::Invalid).build());
+        // A known kind whose body could not be decoded
+        metrics.message_received(&MessageBuilder::new(MessageKind::KeepAlive).build());
+        metrics.bytes_received(155);
+        metrics.message_sent(MessageKind::Publish);
+        metrics.message_dropped(MessageKind::KeepAlive);
+        metrics.block_processed(ProcessResult::Fork);
+
+        let mut out = String::new();
+        metrics.render(&mut out);
+        assert!(out.contains("# TYPE nano_messages_received_total counter\n"));
+        assert!(out.contains("nano_messages_received_total{kind=\"keepalive\"} 2\n"));
+        assert!(out.contains("nano_messages_sent_total{kind=\"publish\"} 1\n"));
+        assert!(out.contains("nano_received_bytes_total 155\n"));
+        assert!(out.contains("nano_deserialize_errors_total 2\n"));
+        assert!(out.contains("nano_blocks_processed_total{result=\"fork\"} 1\n"));
+        assert!(out.contains("nano_blocks_processed_total{result=\"progress\"} 0\n"));
+        assert!(out.contains("nano_messages_dropped_total{kind=\"keepalive\"} 1\n"));
+    }
+
+    #[test]
+    fn labels_match_values() {
+        for (i, &(kind, _)) in MESSAGE_KINDS.iter().enumerate() {
+            assert_eq!(kind as usize, i);
+        }
+        for (i, &(result, _)) in PROCESS_RESULTS.iter().enumerate() {
+            assert_eq!(result as usize, i);
+        }
+    }
+}
@@ -1,15 +1,24 @@
-//! A custom version of tokio::net::UdpFramed that does not exit on send error and
-//! which contains a reference to a `State` object
-use std::net::{SocketAddr, Ipv4Addr, SocketAddrV4};
+//! A custom version of tokio::net::UdpFramed that does not exit on send error,
+//! which contains a reference to a `State` object and which yields the size of each
+//! received datagram along with its frame. On Linux, datagrams are sent and received
+//! in batches with `sendmmsg` and `recvmmsg`.
+use std::collections::VecDeque;
+use std::io;
+use std::net::{SocketAddr, SocketAddrV6};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
 
 use futures::{Async, Poll, Stream, Sink, StartSend, AsyncSink};
 
 use tokio::net::UdpSocket;
 
 use tokio_io::codec::{Decoder, Encoder};
-use bytes::{BytesMut, BufMut};
+use bytes::{Bytes, BytesMut, BufMut};
 
 use std::sync::Arc;
+#[cfg(target_os = "linux")]
+use nano_lib_rs::mmsg;
+use nano_lib_rs::message::{Message, MessageKind};
 use node::state::State;
 use utils::to_ipv6;
 
@@ -36,97 +45,145 @@ pub struct UdpFramed<C> {
     codec: C,
     rd: BytesMut,
     wr: BytesMut,
-    out_addr: SocketAddr,
-    flushed: bool,
+    /// Encoded datagrams waiting to be sent, with their destinations
+    out: VecDeque<(Bytes, SocketAddr)>,
+    /// The message kind of each datagram in `out`
+    out_kinds: VecDeque<MessageKind>,
+    /// Datagrams sent since the peer stats were last updated
+    sent: Vec<(SocketAddrV6, MessageKind, usize)>,
+    /// Buffers for datagrams received in a batch
+    batch: Vec<Vec<u8>>,
+    /// The buffer index, length and sender of batched datagrams not yet decoded
+    received: VecDeque<(usize, usize, SocketAddr)>,
     node_state: Arc<State>,
 }
 
 impl<C: Decoder> Stream for UdpFramed<C> {
-    type Item = (C::Item, SocketAddr);
+    type Item = (C::Item, SocketAddr, usize);
     type Error = C::Error;
 
     fn poll(&mut self) -> Poll<Option<(Self::Item)>, Self::Error> {
-        self.rd.reserve(INITIAL_RD_CAPACITY);
-
-        let (n, addr) = unsafe {
-            // Read into the buffer without having to initialize the memory.
-            let (n, addr) = try_ready!(self.socket.poll_recv_from(self.rd.bytes_mut()));
-            self.rd.advance_mut(n);
-            (n, addr)
-        };
-        trace!("received {} bytes, decoding", n);
-        let frame_res = self.codec.decode(&mut self.rd);
-        self.rd.clear();
-        let frame = frame_res?;
-        let result = frame.map(|frame| (frame, addr)); // frame -> (frame, addr)
-        trace!("frame decoded from buffer");
-        Ok(Async::Ready(result))
+        loop {
+            let (n, addr) = match self.received.pop_front() {
+                Some((i, n, addr)) => {
+                    self.rd.extend_from_slice(&self.batch[i][..n]);
+                    (n, addr)
+                },
+                None => {
+                    self.rd.reserve(INITIAL_RD_CAPACITY);
+                    let (n, addr) = unsafe {
+                        // Read into the buffer without having to initialize the memory.
+                        let (n, addr) = try_ready!(self.socket.poll_recv_from(self.rd.bytes_mut()));
+                        self.rd.advance_mut(n);
+                        (n, addr)
+                    };
+                    // The socket was readable, so pick up whatever else is waiting
+                    self.recv_batch();
+                    (n, addr)
+                },
+            };
+            self.node_state.metrics.bytes_received(n);
+            // Drop datagrams from banned senders without spending time decoding them
+            if self.node_state.reputation.is_banned(*to_ipv6(addr).ip()) {
+                trace!("dropped {} bytes from banned {}", n, addr);
+                self.node_state.metrics.banned_datagram();
+                self.rd.clear();
+                continue;
+            }
+            trace!("received {} bytes, decoding", n);
+            let frame_res = self.codec.decode(&mut self.rd);
+            self.rd.clear();
+            let frame = frame_res?;
+            let result = frame.map(|frame| (frame, addr, n)); // frame -> (frame, addr, size)
+            trace!("frame decoded from buffer");
+            return Ok(Async::Ready(result));
+        }
     }
 }
 
-impl<C: Encoder> Sink for UdpFramed<C> {
+impl<C: Encoder<Item=Message>> Sink for UdpFramed<C> {
     type SinkItem = (C::Item, SocketAddr);
     type SinkError = C::Error;
 
     fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
         trace!("sending frame");
 
-        if !self.flushed {
-            match try!(self.poll_complete()) {
-                Async::Ready(()) => {},
-                Async::NotReady => return Ok(AsyncSink::NotReady(item)),
+        if self.out.len() >= MAX_QUEUED_DATAGRAMS {
+            try!(self.poll_complete());
+            if self.out.len() >= MAX_QUEUED_DATAGRAMS {
+                return Ok(AsyncSink::NotReady(item));
             }
         }
 
         let (frame, out_addr) = item;
+        let kind = frame.kind();
         self.codec.encode(frame, &mut self.wr)?;
-        self.out_addr = out_addr;
-        self.flushed = false;
-        trace!("frame encoded; length={}", self.wr.len());
+        let datagram = self.wr.take().freeze();
+        trace!("frame encoded; length={}", datagram.len());
+        self.out.push_back((datagram, out_addr));
+        self.out_kinds.push_back(kind);
 
         Ok(AsyncSink::Ready)
     }
 
     fn poll_complete(&mut self) -> Poll<(), C::Error> {
-        if self.flushed {
-            return Ok(Async::Ready(()))
-        }
+        let result = self.send_queued();
+        self.node_state.peer_messages_sent(&self.sent);
+        self.sent.clear();
+        result
+    }
 
-        trace!("flushing frame; length={}", self.wr.len());
-        match self.socket.poll_send_to(&self.wr, &self.out_addr) {
-            Ok(Async::NotReady) => {
-                return Ok(Async::NotReady);
-            },
-            Ok(Async::Ready(n)) => {
-                trace!("written {}", n);
+    fn close(&mut self) -> Poll<(), C::Error> {
+        try_ready!(self.poll_complete());
+        Ok(().into())
+    }
+}
 
-                let wrote_all = n == self.wr.len();
-                self.wr.clear();
-                self.flushed = true;
+impl<C: Encoder> UdpFramed<C> {
+    /// Send the queued datagrams until the socket is full
+    fn send_queued(&mut self) -> Poll<(), C::Error> {
+        while !self.out.is_empty() {
+            if self.send_batch() {
+                continue;
+            }
 
-                if !wrote_all {
-                    debug!("Failed to write entire datagram to socket; Wrote: {} expected: {}", n, self.wr.len());
-                }
-            },
-            Err(e) => {
-                if e.kind() == ::std::io::ErrorKind::WouldBlock {
+            // One datagram at a time, which also waits for the socket to become writable
+            let result = {
+                let (ref datagram, ref out_addr) = self.out[0];
+                trace!("flushing frame; length={}", datagram.len());
+                self.socket.poll_send_to(datagram, out_addr)
+            };
+            match result {
+                Ok(Async::NotReady) => {
                     return Ok(Async::NotReady);
+                },
+                Ok(Async::Ready(n)) => {
+                    trace!("written {}", n);
+                    let (datagram, out_addr) = self.out.pop_front().unwrap();
+                    if n != datagram.len() {
+                        debug!("Failed to write entire datagram to socket; Wrote: {} expected: {}", n, datagram.len());
+                    }
+                    self.datagram_sent(n, out_addr);
+                },
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::WouldBlock {
+                        return Ok(Async::NotReady);
+                    }
+                    self.send_failed(&e);
                 }
-                debug!("Error sending frame: {:?}, removing peer: {}", e, self.out_addr);
-                self.node_state.remove_peer(to_ipv6(self.out_addr));
             }
         }
         Ok(Async::Ready(()))
     }
-
-    fn close(&mut self) -> Poll<(), C::Error> {
-        try_ready!(self.poll_complete());
-        Ok(().into())
-    }
 }
 
 const INITIAL_RD_CAPACITY: usize = 64 * 1024;
 const INITIAL_WR_CAPACITY: usize = 8 * 1024;
+/// Datagrams queued before `start_send` waits for them to be sent. Enough for a full batch.
+const MAX_QUEUED_DATAGRAMS: usize = 64;
+/// Batched datagrams longer than this are cut short. Every message of the protocol fits.
+#[cfg(target_os = "linux")]
+const BATCH_DATAGRAM_SIZE: usize = 2048;
 
 impl<C> UdpFramed<C> {
     /// Create a new `UdpFramed` backed by the given socket and codec.
@@ -136,14 +193,95 @@ impl<C> UdpFramed<C> {
         UdpFramed {
             socket: socket,
             codec: codec,
-            out_addr: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0)),
             rd: BytesMut::with_capacity(INITIAL_RD_CAPACITY),
             wr: BytesMut::with_capacity(INITIAL_WR_CAPACITY),
-            flushed: true,
+            out: VecDeque::new(),
+            out_kinds: VecDeque::new(),
+            sent: Vec::new(),
+            batch: Self::batch_buffers(),
+            received: VecDeque::new(),
             node_state: state,
         }
     }
 
+    #[cfg(target_os = "linux")]
+    fn batch_buffers() -> Vec<Vec<u8>> {
+        vec![vec![0u8; BATCH_DATAGRAM_SIZE]; mmsg::BATCH_SIZE]
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn batch_buffers() -> Vec<Vec<u8>> {
+        Vec::new()
+    }
+
+    /// Queue up the datagrams waiting on the socket, if any
+    #[cfg(target_os = "linux")]
+    fn recv_batch(&mut self) {
+        match mmsg::recv_batch(self.socket.as_raw_fd(), &mut self.batch) {
+            Ok(received) => {
+                trace!("received a batch of {} datagrams", received.len());
+                for (i, (n, addr)) in received.into_iter().enumerate() {
+                    self.received.push_back((i, n, addr));
+                }
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+            Err(e) => debug!("Error receiving a batch of datagrams: {}", e),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn recv_batch(&mut self) {}
+
+    /// Send as many of the queued datagrams as one call allows. Returns false if nothing was
+    /// done, in which case they should be sent one at a time.
+    #[cfg(target_os = "linux")]
+    fn send_batch(&mut self) -> bool {
+        if self.out.len() < 2 {
+            return false;
+        }
+        let result = mmsg::send_batch(self.socket.as_raw_fd(), self.out.as_slices().0);
+        match result {
+            Ok(0) => false,
+            Ok(sent) => {
+                trace!("sent a batch of {} datagrams", sent);
+                for _ in 0..sent {
+                    let (datagram, out_addr) = self.out.pop_front().unwrap();
+                    self.datagram_sent(datagram.len(), out_addr);
+                }
+                true
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => false,
+            Err(e) => {
+                self.send_failed(&e);
+                true
+            },
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn send_batch(&mut self) -> bool {
+        false
+    }
+
+    /// Account for a datagram taken off the front of `out`. Peer stats are updated in bulk
+    /// once sending stops, to take the peers lock once per batch.
+    fn datagram_sent(&mut self, n: usize, out_addr: SocketAddr) {
+        self.node_state.metrics.bytes_sent(n);
+        self.node_state.outgoing.sent(n);
+        if let Some(kind) = self.out_kinds.pop_front() {
+            self.sent.push((to_ipv6(out_addr), kind, n));
+        }
+    }
+
+    /// Drop the first queued datagram, which could not be sent, along with its peer
+    fn send_failed(&mut self, e: &io::Error) {
+        self.out_kinds.pop_front();
+        if let Some((_, out_addr)) = self.out.pop_front() {
+            debug!("Error sending frame: {:?}, removing peer: {}", e, out_addr);
+            self.node_state.remove_peer(to_ipv6(out_addr));
+        }
+    }
+
     /// Returns a reference to the underlying I/O stream wrapped by `Framed`.
     ///
     /// # Note
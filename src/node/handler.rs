@@ -1,6 +1,8 @@
 use nano_lib_rs::message::{MessageBuilder, Message, MessageKind, MessagePayload};
+use nano_lib_rs::block::Block;
 
 use node::State;
+use node::pipeline::Job;
 use error::*;
 use utils::check_addr;
 
@@ -34,38 +36,32 @@ pub fn keepalive(msg: Message, _src: SocketAddrV6, state: Arc<State>)
     }
 }
 
-pub fn publish(mut msg: Message, _src: SocketAddrV6, _state: Arc<State>)
+/// Queue a received block to be validated off the socket task, or shed it if the queue is full
+fn validate(kind: MessageKind, block: Block, src: SocketAddrV6, state: &State) {
+    if !state.validation.push(Job { kind, block, src }) {
+        debug!("Shedding {:?} from {}, the validation queue is full", kind, src);
+        state.metrics.block_shed(kind);
+    }
+}
+
+pub fn publish(msg: Message, src: SocketAddrV6, state: Arc<State>)
     -> Box<Stream<Item=(Message, SocketAddr), Error=Error> + Send>
 {
-    if let MessagePayload::Publish(ref mut block) =  msg.payload {
-        let hash = match block.hash(false) {
-            Ok(hash) => hash.into(),
-            Err(e) => format!("Error calculating hash for block: {}", e),
-        };
-        let valid = if block.verify_work().unwrap_or(false) { "valid" } else { "INVALID" };
-        info!("Got {:?} block with hash {}; Work {}", block.kind, hash, valid);
-        Box::new(stream::empty())
+    if let MessagePayload::Publish(block) = msg.payload {
+        validate(MessageKind::Publish, block, src, &state);
     } else {
         debug!("Malformed Publish, ignoring.");
-        Box::new(stream::empty())
     }
+    Box::new(stream::empty())
 }
 
-pub fn confirm_req(mut msg: Message, _src: SocketAddrV6, _state: Arc<State>)
+pub fn confirm_req(msg: Message, src: SocketAddrV6, state: Arc<State>)
     -> Box<Stream<Item=(Message, SocketAddr), Error=Error> + Send>
 {
-
-    if let MessagePayload::ConfirmReq(ref mut block) =  msg.payload {
-        let hash = match block.hash(false) {
-            Ok(hash) => hash.into(),
-            Err(e) => format!("Error calculating hash for block: {}", e),
-        };
-        let valid = if block.verify_work().unwrap_or(false) { "valid" } else { "INVALID" };
-        info!("Got {:?} block with hash {}; Work {}", block.kind, hash, valid);
-        Box::new(stream::empty())
+    if let MessagePayload::ConfirmReq(block) = msg.payload {
+        validate(MessageKind::ConfirmReq, block, src, &state);
     } else {
         debug!("Malformed ConfirmReq, ignoring.");
-        Box::new(stream::empty())
     }
+    Box::new(stream::empty())
 }
- 
\ No newline at end of file
@@ -0,0 +1,280 @@
+//! Limits on what a single host can make the node do: token buckets on the messages of each
+//! source, and caps on the peers one IP or /64 subnet can occupy.
+use std::collections::HashMap;
+use std::mem;
+use std::net::Ipv6Addr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use nano_lib_rs::message::MessageKind;
+
+/// Buckets idle for this long are full again and can be forgotten
+const IDLE_BUCKET_SECS: u64 = 60;
+/// The most sources whose buckets are kept at once
+const MAX_TRACKED_SOURCES: usize = 65536;
+
+/// A bucket holding up to `capacity` tokens, refilled at `rate` tokens per second
+#[derive(Clone, Copy, Debug)]
+struct TokenBucket {
+    tokens: f64,
+    capacity: f64,
+    rate: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, burst: u32, now: Instant) -> Self {
+        TokenBucket {
+            tokens: burst as f64,
+            capacity: burst as f64,
+            rate: rate as f64,
+            updated: now,
+        }
+    }
+
+    /// Take a token if there is one
+    fn take(&mut self, now: Instant) -> bool {
+        let elapsed = now - self.updated;
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Messages per second allowed from a single IP, each with a burst of twice the rate
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimits {
+    /// Messages of every kind
+    pub messages: u32,
+    pub keepalives: u32,
+    pub publishes: u32,
+    pub confirm_reqs: u32,
+}
+
+impl RateLimits {
+    /// The rate for messages of `kind`, or `None` if only the overall limit applies
+    fn for_kind(&self, kind: MessageKind) -> Option<u32> {
+        match kind {
+            MessageKind::KeepAlive => Some(self.keepalives),
+            MessageKind::Publish => Some(self.publishes),
+            MessageKind::ConfirmReq => Some(self.confirm_reqs),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct IpBuckets {
+    messages: TokenBucket,
+    /// Indexed by the value of the message kind
+    kinds: [Option<TokenBucket>; 9],
+}
+
+/// The buckets of at most `max` sources, in two generations. Once the recent generation holds
+/// half of them it becomes the older one, replacing it, so the sources forgotten are those not
+/// heard from in the longest time.
+#[derive(Debug)]
+struct Sources {
+    max: usize,
+    recent: HashMap<Ipv6Addr, IpBuckets>,
+    older: HashMap<Ipv6Addr, IpBuckets>,
+}
+
+impl Sources {
+    fn new(max: usize) -> Self {
+        Sources {
+            max,
+            recent: HashMap::new(),
+            older: HashMap::new(),
+        }
+    }
+
+    /// The buckets of `source`, created with `new_buckets` if it is not tracked
+    fn get<F>(&mut self, source: Ipv6Addr, new_buckets: F) -> &mut IpBuckets
+        where F: FnOnce() -> IpBuckets
+    {
+        if !self.recent.contains_key(&source) {
+            let buckets = self.older.remove(&source).unwrap_or_else(new_buckets);
+            if self.recent.len() >= self.max / 2 {
+                self.older = mem::replace(&mut self.recent, HashMap::new());
+            }
+            self.recent.insert(source, buckets);
+        }
+        self.recent.get_mut(&source).unwrap()
+    }
+
+    fn len(&self) -> usize {
+        self.recent.len() + self.older.len()
+    }
+
+    fn retain<F>(&mut self, mut keep: F)
+        where F: FnMut(&IpBuckets) -> bool
+    {
+        self.recent.retain(|_, buckets| keep(buckets));
+        self.older.retain(|_, buckets| keep(buckets));
+    }
+}
+
+/// The source whose buckets `ip` uses. IPv6 hosts are usually given a whole /64, so native
+/// IPv6 addresses share the buckets of their /64.
+fn source(ip: Ipv6Addr) -> Ipv6Addr {
+    if ip.to_ipv4().is_some() {
+        return ip;
+    }
+    let segments = ip.segments();
+    Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3], 0, 0, 0, 0)
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    sources: Mutex<Sources>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        RateLimiter::with_max_sources(limits, MAX_TRACKED_SOURCES)
+    }
+
+    fn with_max_sources(limits: RateLimits, max_sources: usize) -> Self {
+        RateLimiter {
+            limits,
+            sources: Mutex::new(Sources::new(max_sources)),
+        }
+    }
+
+    /// Whether a message of `kind` from `ip` is within the limits, using up a token if it is
+    pub fn allow(&self, ip: Ipv6Addr, kind: MessageKind) -> bool {
+        let now = Instant::now();
+        let limits = self.limits;
+        let mut sources = self.sources.lock().unwrap();
+        let ip_buckets = sources.get(source(ip), || IpBuckets {
+            messages: TokenBucket::new(limits.messages, limits.messages.saturating_mul(2), now),
+            kinds: [None; 9],
+        });
+        if let Some(rate) = limits.for_kind(kind) {
+            let bucket = ip_buckets.kinds[kind as usize]
+                .get_or_insert_with(|| TokenBucket::new(rate, rate.saturating_mul(2), now));
+            if !bucket.take(now) {
+                return false;
+            }
+        }
+        ip_buckets.messages.take(now)
+    }
+
+    /// Forget the buckets of IPs which have not sent anything for a while
+    pub fn prune(&self) {
+        let now = Instant::now();
+        let idle = Duration::from_secs(IDLE_BUCKET_SECS);
+        self.sources.lock().unwrap().retain(|buckets| now - buckets.messages.updated < idle);
+    }
+}
+
+/// The most peers a single host may occupy in the peer list
+#[derive(Clone, Copy, Debug)]
+pub struct PeerLimits {
+    pub per_ip: usize,
+    /// Only applied to native IPv6 addresses, since every IPv4 address maps into the same /64
+    pub per_subnet: usize,
+}
+
+impl PeerLimits {
+    /// Whether `ip` may add another peer to `peers`
+    pub fn allows<'a, I>(&self, ip: &Ipv6Addr, peers: I) -> bool
+        where I: Iterator<Item=&'a Ipv6Addr>
+    {
+        let check_subnet = ip.to_ipv4().is_none();
+        let mut same_ip = 0;
+        let mut same_subnet = 0;
+        for peer in peers {
+            if peer == ip {
+                same_ip += 1;
+            }
+            if check_subnet && peer.segments()[..4] == ip.segments()[..4] {
+                same_subnet += 1;
+            }
+        }
+        same_ip < self.per_ip && (!check_subnet || same_subnet < self.per_subnet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn refills_buckets() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10, 2, now);
+        assert!(bucket.take(now));
+        assert!(bucket.take(now));
+        assert!(!bucket.take(now));
+        assert!(bucket.take(now + Duration::from_millis(100)));
+        assert!(!bucket.take(now + Duration::from_millis(100)));
+        // Never holds more than the burst
+        assert!(bucket.take(now + Duration::from_secs(10)));
+        assert!(bucket.take(now + Duration::from_secs(10)));
+        assert!(!bucket.take(now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn limits_each_kind() {
+        let limiter = RateLimiter::new(RateLimits { messages: 100, keepalives: 1, publishes: 100, confirm_reqs: 100 });
+        let ip: Ipv6Addr = "::ffff:192.0.2.1".parse().unwrap();
+        assert!(limiter.allow(ip, MessageKind::KeepAlive));
+        assert!(limiter.allow(ip, MessageKind::KeepAlive));
+        assert!(!limiter.allow(ip, MessageKind::KeepAlive));
+        assert!(limiter.allow(ip, MessageKind::Publish));
+        assert!(limiter.allow("::ffff:192.0.2.2".parse().unwrap(), MessageKind::KeepAlive));
+    }
+
+    #[test]
+    fn shares_buckets_per_ipv6_subnet() {
+        let limiter = RateLimiter::new(RateLimits { messages: 100, keepalives: 1, publishes: 100, confirm_reqs: 100 });
+        assert!(limiter.allow("2001:db8::1".parse().unwrap(), MessageKind::KeepAlive));
+        assert!(limiter.allow("2001:db8::2".parse().unwrap(), MessageKind::KeepAlive));
+        assert!(!limiter.allow("2001:db8::3".parse().unwrap(), MessageKind::KeepAlive));
+        assert!(limiter.allow("2001:db8:1::1".parse().unwrap(), MessageKind::KeepAlive));
+    }
+
+    #[test]
+    fn bounds_tracked_sources() {
+        let limiter = RateLimiter::with_max_sources(RateLimits { messages: 100, keepalives: 1, publishes: 100, confirm_reqs: 100 }, 4);
+        let busy: Ipv6Addr = "::ffff:192.0.2.1".parse().unwrap();
+        assert!(limiter.allow(busy, MessageKind::KeepAlive));
+        assert!(limiter.allow(busy, MessageKind::KeepAlive));
+        for i in 0..100u8 {
+            let ip = Ipv4Addr::new(198, 51, 100, i).to_ipv6_mapped();
+            assert!(limiter.allow(ip, MessageKind::Publish));
+            // A source heard from recently keeps its buckets
+            if i % 2 == 0 {
+                assert!(!limiter.allow(busy, MessageKind::KeepAlive));
+            }
+            assert!(limiter.sources.lock().unwrap().len() <= 4);
+        }
+    }
+
+    #[test]
+    fn caps_peers_per_ip_and_subnet() {
+        let limits = PeerLimits { per_ip: 2, per_subnet: 3 };
+        let v4: Ipv6Addr = "::ffff:192.0.2.1".parse().unwrap();
+        let other_v4: Ipv6Addr = "::ffff:192.0.2.2".parse().unwrap();
+        assert!(limits.allows(&v4, [v4, other_v4, other_v4, other_v4].iter()));
+        assert!(!limits.allows(&v4, [v4, v4].iter()));
+
+        let v6: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let neighbours: Vec<Ipv6Addr> = vec!["2001:db8::2".parse().unwrap(), "2001:db8::3".parse().unwrap()];
+        assert!(limits.allows(&v6, neighbours.iter()));
+        let mut crowded = neighbours.clone();
+        crowded.push("2001:db8::4".parse().unwrap());
+        assert!(!limits.allows(&v6, crowded.iter()));
+        assert!(limits.allows(&"2001:db8:1::1".parse().unwrap(), crowded.iter()));
+    }
+}
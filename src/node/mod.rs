@@ -1,22 +1,36 @@
 pub mod handler;
+pub mod limits;
+pub mod outbound;
+pub mod peers;
+pub mod pipeline;
+pub mod reputation;
+pub mod shutdown;
 pub mod state;
+use self::limits::{PeerLimits, RateLimiter, RateLimits};
+use self::outbound::OutboundQueue;
+use self::peers::PeerFile;
+use self::pipeline::ValidationQueue;
+use self::reputation::{Misbehavior, Reputation};
 use self::state::{State, PeerInfo};
 
 use net::codec::MessageCodec;
 use net::{UdpFramed};
 
 use nano_lib_rs::message::{MessageBuilder, Message, MessageKind, MessagePayload, NetworkKind};
-use nano_lib_rs;
+use nano_lib_rs::block::Block;
 
 use tokio;
 use tokio::prelude::*;
 use tokio::net::{UdpSocket};
 use futures::{self, Future};
-use futures::sync::mpsc;
+use hyper::Uri;
 
 use std::net::{SocketAddr, SocketAddrV6};
+use std::path::{Path, PathBuf};
 use net2::UdpBuilder;
 use std::sync::{Arc};
+use std::sync::mpsc::sync_channel;
+use std::thread;
 
 use tokio_timer::{Timer, TimerError};
 use std::time::{Duration};
@@ -26,21 +40,36 @@ use indexmap::IndexMap;
 use error::*;
 
 use utils::{log_errors, to_ipv6};
+use ledger::{Ledger, ProcessResult};
+use wallet::{receiver, WalletStore};
+use metrics;
+use rpc;
+use work::{self, WorkCache, WorkPool};
 
-const KEEPALIVE_INTERVAL: u64 = 60;
-const KEEPALIVE_CUTOFF: u64 = KEEPALIVE_INTERVAL * 5;
-
-const PEER_PRUNE_INTERVAL: u64 = KEEPALIVE_INTERVAL * 2;
+/// Seconds between saves of the ledger to disk
+const LEDGER_SAVE_INTERVAL: u64 = 300;
+/// Seconds between saves of the known peers to disk
+const PEER_SAVE_INTERVAL: u64 = 60;
 
 fn process_messages<S>(network: NetworkKind, state: Arc<State>, stream: S) -> impl Stream<Item=(Message, SocketAddr), Error=Error>
-    where S: Stream<Item=(Message, SocketAddr), Error=Error>
+    where S: Stream<Item=(Message, SocketAddr, usize), Error=Error>
 {
-    stream.map(move |(msg, src_addr)| -> Box<Stream<Item=(Message, SocketAddr), Error=Error> + Send> {
-        if network == msg.header.network {
+    stream.map(move |(msg, src_addr, size)| -> Box<Stream<Item=(Message, SocketAddr), Error=Error> + Send> {
+        state.metrics.message_received(&msg);
+        let src_addr_v6 = to_ipv6(src_addr);
+        if !state.rate_limiter.allow(*src_addr_v6.ip(), msg.kind()) {
+            trace!("Dropping {:?} from {}, over its rate limit", msg.kind(), src_addr);
+            state.metrics.message_rate_limited(msg.kind());
+            Box::new(stream::empty())
+        } else if msg.kind() == MessageKind::Invalid {
+            // Messages which failed to decode carry a default header, so only count them
+            state.peer_message_received(src_addr_v6, &msg.header, size);
+            state.penalize_peer(src_addr_v6, Misbehavior::InvalidMessage);
+            Box::new(stream::empty())
+        } else if network == msg.header.network {
             let state = state.clone();
             let kind = msg.kind();
-            let src_addr_v6 = to_ipv6(src_addr);
-            let _ = state.add_or_update_peer(src_addr_v6, true);
+            let _ = state.add_or_update_peer(src_addr_v6, true, Some((&msg.header, size)));
             debug!("Received message of kind: {:?} from {}", kind, src_addr);
             match kind {
                 MessageKind::KeepAlive => handler::keepalive(msg, src_addr_v6, state.clone()),
@@ -50,15 +79,16 @@ fn process_messages<S>(network: NetworkKind, state: Arc<State>, stream: S) -> im
             }
         } else {
             debug!("Received message from {:?} network, ignoring...", msg.header.network);
+            state.penalize_peer(src_addr_v6, Misbehavior::WrongNetwork);
             Box::new(stream::empty())
         }
     })
     .flatten()
 }
 
-fn send_keepalives(state: Arc<State>, timer: &Timer) -> impl Stream<Item=(Message, SocketAddr), Error=Error> {
+fn send_keepalives(state: Arc<State>, timer: &Timer, interval: Duration) -> impl Stream<Item=(Message, SocketAddr), Error=Error> {
     stream::once(Ok(()))
-        .chain(timer.interval(Duration::from_secs(KEEPALIVE_INTERVAL)))
+        .chain(timer.interval(interval))
         .map(move |_| {
             let state = state.clone();
             let count = state.peer_count();
@@ -76,24 +106,170 @@ fn send_keepalives(state: Arc<State>, timer: &Timer) -> impl Stream<Item=(Messag
         .flatten()
 }
 
-fn prune_peers(state: Arc<State>, timer: &Timer) -> impl Future<Item=(), Error=TimerError> {
-    timer.interval(Duration::from_secs(PEER_PRUNE_INTERVAL))
+/// Saving writes the whole ledger, so it happens on its own thread rather than on the reactor.
+/// A tick arriving while the previous save is still running is skipped.
+fn save_ledger_periodically(state: Arc<State>, timer: &Timer, path: PathBuf) -> impl Future<Item=(), Error=TimerError> {
+    let (tick, ticks) = sync_channel(1);
+    thread::spawn(move || {
+        for () in ticks {
+            save_ledger(&state, &path);
+        }
+    });
+    timer.interval(Duration::from_secs(LEDGER_SAVE_INTERVAL))
+        .for_each(move |_| {
+            let _ = tick.try_send(());
+            futures::future::ok(())
+        })
+}
+
+/// Write the ledger to `path`, logging any failure. The ledger is only locked while it is
+/// copied, not while the copy is written.
+fn save_ledger(state: &State, path: &Path) {
+    let _saving = state.ledger_saving.lock().unwrap();
+    let snapshot = state.ledger.read().unwrap().snapshot();
+    match snapshot.save(path) {
+        Ok(()) => debug!("Saved ledger to {}", path.display()),
+        Err(e) => error!("Failed to save ledger: {}", e),
+    }
+}
+
+fn save_peers_periodically(state: Arc<State>, timer: &Timer, path: PathBuf) -> impl Future<Item=(), Error=TimerError> {
+    timer.interval(Duration::from_secs(PEER_SAVE_INTERVAL))
+        .for_each(move |_| {
+            save_peers(&state, &path);
+            futures::future::ok(())
+        })
+}
+
+/// Write the active and inactive peers to `path`, logging any failure
+fn save_peers(state: &State, path: &Path) {
+    let peers = state.peer_file();
+    match peers.save(path) {
+        Ok(()) => debug!("Saved {} peers and {} inactive peers", peers.peers.len(), peers.inactive_peers.len()),
+        Err(e) => error!("Failed to save peers: {}", e),
+    }
+}
+
+fn prune_peers(state: Arc<State>, timer: &Timer, interval: Duration, cutoff: Duration) -> impl Future<Item=(), Error=TimerError> {
+    timer.interval(interval)
         .for_each(move |_| {
             let state = state.clone();
-            let count = state.prune_peers();
+            let count = state.prune_peers(cutoff);
+            state.metrics.peers_pruned(count);
+            state.reputation.prune();
+            state.rate_limiter.prune();
             debug!("Pruned {} inactive peers. Current peer count: {}", count, state.peer_count());
             futures::future::ok(())
         })
 }
 
+/// Process a block into the ledger and flood it to a random subset of peers if it was new and valid
+pub fn publish_block(state: &State, block: Block) -> ProcessResult {
+    publish(state, block, false)
+}
+
+/// Like `publish_block`, for a block which already passed `ledger::verify`
+pub fn publish_verified_block(state: &State, block: Block) -> ProcessResult {
+    publish(state, block, true)
+}
+
+fn publish(state: &State, mut block: Block, verified: bool) -> ProcessResult {
+    let hash = match block.hash(false) {
+        Ok(hash) => hash,
+        Err(_) => return ProcessResult::Unsupported,
+    };
+    let result = {
+        let mut ledger = state.ledger.write().unwrap();
+        if verified {
+            ledger.process_verified(block.clone())
+        } else {
+            ledger.process(block.clone())
+        }
+    };
+    state.metrics.block_processed(result);
+    debug!("Processed block {}: {:?}", String::from(hash), result);
+    if result == ProcessResult::Progress {
+        receiver::block_processed(state, &block, hash);
+        work::cache::block_confirmed(state, &block, hash);
+        let fanout = (state.peer_count() as f64).sqrt().ceil() as usize;
+        let msg = MessageBuilder::new(MessageKind::Publish)
+            .with_network(state.network)
+            .with_block_kind(block.kind)
+            .with_payload(MessagePayload::Publish(block))
+            .build();
+        for peer in state.random_peers(fanout) {
+            state.send_message(msg.clone(), SocketAddr::V6(peer));
+        }
+    }
+    result
+}
+
 pub struct NodeConfig {
     pub peers: Vec<SocketAddr>,
+    /// Fail at startup when neither `peers` nor the saved peers have anyone to contact
+    pub require_peers: bool,
     pub listen_addr: SocketAddr,
     pub network: NetworkKind,
+    pub ledger_path: PathBuf,
+    pub peers_path: PathBuf,
+    pub wallets_path: PathBuf,
+    pub work_cache_path: PathBuf,
+    pub rpc_addr: Option<SocketAddr>,
+    /// Serve Prometheus metrics on this address
+    pub metrics_addr: Option<SocketAddr>,
+    /// Sends smaller than this many raw are not received automatically
+    pub receive_minimum: u128,
+    /// HTTP endpoints to request work from alongside local generation
+    pub work_peers: Vec<Uri>,
+    /// The number of threads to generate work on locally
+    pub work_threads: usize,
+    pub keepalive_interval: Duration,
+    /// Peers not heard from for this long are pruned
+    pub keepalive_cutoff: Duration,
+    pub peer_prune_interval: Duration,
+    /// Peers whose misbehavior score reaches this are banned
+    pub ban_threshold: u32,
+    pub ban_duration: Duration,
+    pub rate_limits: RateLimits,
+    pub peer_limits: PeerLimits,
+    /// The most messages waiting to be sent at once
+    pub outbound_queue_size: usize,
+    /// Cap on the bytes sent per second, 0 for no cap
+    pub outbound_bytes_per_sec: u64,
+    /// The number of threads checking the work and signatures of received blocks
+    pub validation_threads: usize,
+    /// The most received blocks waiting to be validated at once
+    pub validation_queue_size: usize,
+}
+
+/// A started node. It keeps running until `shutdown` is called or its socket fails. The future
+/// returned alongside it by `run` finishes when it stops receiving messages, and the queued
+/// messages are sent after that.
+pub struct Node {
+    pub state: Arc<State>,
+    ledger_path: PathBuf,
+    peers_path: PathBuf,
 }
 
+impl Node {
+    /// Stop receiving messages, running timers and generating work
+    pub fn shutdown(&self) {
+        // Flush the queued messages without waiting for the bandwidth cap
+        self.state.outgoing.uncap();
+        self.state.shutdown.trigger();
+        self.state.work.cancel_all();
+    }
 
-pub fn run(config: NodeConfig, handle: &tokio::reactor::Handle) -> Result<impl Future<Item = (), Error = ()>> {
+    /// Save the ledger and peers for the next run. The node does not vote yet, so there is no
+    /// vote sequence to save.
+    pub fn save(&self) {
+        save_ledger(&self.state, &self.ledger_path);
+        save_peers(&self.state, &self.peers_path);
+    }
+}
+
+/// Start the node, returning it and the future driving it
+pub fn run(config: NodeConfig, handle: &tokio::reactor::Handle) -> Result<(Node, impl Future<Item = (), Error = ()>)> {
     let socket_std = UdpBuilder::new_v6()?
         .only_v6(false)?
         .bind(&config.listen_addr)?;
@@ -101,52 +277,136 @@ pub fn run(config: NodeConfig, handle: &tokio::reactor::Handle) -> Result<impl F
 
     info!("Listening on: {}", socket.local_addr()?);
 
+    // The preconfigured peers are never banned, since anyone can forge datagrams from them
+    let trusted = config.peers.iter().map(|addr| *to_ipv6(*addr).ip()).collect();
     let initial_peers: IndexMap<SocketAddrV6, PeerInfo> = config.peers.into_iter()
         .map(|addr| {
             (to_ipv6(addr), PeerInfo::default())
         }).collect();
 
-    let state = Arc::new(State::new(initial_peers));
+    let wallets = WalletStore::open(&config.wallets_path)?;
+    let ledger = Ledger::for_network(config.network).load(&config.ledger_path)?;
+
+    let work_cache = WorkCache::open(&config.work_cache_path)?;
+    let work = WorkPool::new(config.work_peers, config.work_threads)?;
+    if !work.peers().is_empty() {
+        info!("Using {} work peers", work.peers().len());
+    }
+
+    let state = Arc::new(State::new(
+        config.network,
+        initial_peers,
+        ledger,
+        wallets,
+        config.receive_minimum,
+        work,
+        work_cache,
+        OutboundQueue::new(config.outbound_queue_size, config.outbound_bytes_per_sec),
+        ValidationQueue::new(config.validation_queue_size),
+        Reputation::new(config.ban_threshold, config.ban_duration, trusted),
+        RateLimiter::new(config.rate_limits),
+        config.peer_limits,
+    ));
+    let saved_peers = PeerFile::load(&config.peers_path).unwrap_or_else(|e| {
+        warn!("Could not load saved peers: {}", e);
+        PeerFile::default()
+    });
+    if !saved_peers.is_empty() {
+        info!("Loaded {} saved peers", saved_peers.peers.len() + saved_peers.inactive_peers.len());
+    }
+    state.restore_peers(saved_peers);
+    if config.require_peers && state.peer_count() == 0 {
+        bail!("Could not resolve any preconfigured peer and no peers were saved");
+    }
+
+    receiver::spawn_worker(state.clone());
+    work::cache::queue_wallet_accounts(&state);
+    work::cache::spawn_worker(state.clone());
+    pipeline::spawn_workers(state.clone(), config.validation_threads);
 
     let (sink, stream) = UdpFramed::new(socket, MessageCodec::new(), state.clone()).split();
 
     let message_processor = process_messages(config.network, state.clone(), stream);
 
     let timer = Timer::default();
-    let keepalive_handler = send_keepalives(state.clone(), &timer);
-    let peer_prune_handler = prune_peers(state.clone(), &timer);
-
-    let (sock_send, sock_recv) = mpsc::channel::<(nano_lib_rs::message::Message, SocketAddr)>(2048);
-    let process_send = sock_send.clone();
-    let keepalive_send = sock_send.clone();
-    
-    Ok(futures::future::lazy(||{
-        tokio::spawn(
-            process_send
-                .sink_map_err(|e| error!("Fatal error sending messages: {:?}", e))
-                .send_all(log_errors(message_processor)
-                    .map_err(|e| error!("Fatal error processing keepalives: {:?}", e)))
-                .map(|_| ())
-        );
-
-        tokio::spawn(
-            keepalive_send
-                .sink_map_err(|e| error!("Fatal sending keepalive: {:?}", e))
-                .send_all(log_errors(keepalive_handler)
-                    .map_err(|e| error!("Fatal error processing keepalives: {:?}", e)))
-                .map(|_| ())
-        );
-
-        tokio::spawn(
+    let keepalive_handler = send_keepalives(state.clone(), &timer, config.keepalive_interval);
+    let peer_prune_handler = prune_peers(state.clone(), &timer, config.peer_prune_interval, config.keepalive_cutoff);
+    let ledger_saver = save_ledger_periodically(state.clone(), &timer, config.ledger_path.clone());
+    let peer_saver = save_peers_periodically(state.clone(), &timer, config.peers_path.clone());
+    let rpc_server = match config.rpc_addr {
+        Some(addr) => Some(rpc::serve(addr, state.clone())?),
+        None => None,
+    };
+    let metrics_server = match config.metrics_addr {
+        Some(addr) => Some(metrics::serve(addr, state.clone())?),
+        None => None,
+    };
+
+    let outgoing = state.outgoing.stream(timer.clone());
+
+    let node = Node {
+        state: state.clone(),
+        ledger_path: config.ledger_path,
+        peers_path: config.peers_path,
+    };
+    // Every task stops when the node shuts down, except for the socket sink which first
+    // sends the messages already queued. The returned future is the processing of received
+    // messages, which also ends when the socket fails.
+    Ok((node, futures::future::lazy(move ||{
+        let shutdown = &state.shutdown;
+        let queue_all = |state: &Arc<State>| {
+            let state = state.clone();
+            move |(msg, addr): (Message, SocketAddr)| {
+                state.send_message(msg, addr);
+                Ok(())
+            }
+        };
+
+        tokio::spawn(shutdown.until(
+            log_errors(keepalive_handler)
+                .map_err(|e| error!("Fatal error processing keepalives: {:?}", e))
+                .for_each(queue_all(&state))
+        ));
+
+        tokio::spawn(shutdown.until(
             peer_prune_handler
                 .map_err(|e| error!("Error pruning peers: {}", e))
-        );
+        ));
+
+        tokio::spawn(shutdown.until(
+            ledger_saver
+                .map_err(|e| error!("Error saving ledger: {}", e))
+        ));
+
+        tokio::spawn(shutdown.until(
+            peer_saver
+                .map_err(|e| error!("Error saving peers: {}", e))
+        ));
+
+        if let Some(rpc_server) = rpc_server {
+            tokio::spawn(shutdown.until(rpc_server));
+        }
+
+        if let Some(metrics_server) = metrics_server {
+            tokio::spawn(shutdown.until(metrics_server));
+        }
 
+        let sent_state = state.clone();
+        let sink_state = state.clone();
         tokio::spawn(sink
             .sink_map_err(|e| error!("Fatal error sending message: {:?}", e))
-            .send_all(sock_recv)
-            .map(|_| ()));
+            .send_all(shutdown.drain(outgoing)
+                .inspect(move |&(ref msg, _)| sent_state.metrics.message_sent(msg.kind())))
+            .then(move |_| {
+                // Nothing can be sent any more, so stop the rest of the node too
+                sink_state.shutdown.trigger();
+                Ok(())
+            }));
 
-        Ok(())
-    }))
+        shutdown.until(
+            log_errors(message_processor)
+                .map_err(|e| error!("Fatal error processing messages: {:?}", e))
+                .for_each(queue_all(&state))
+        )
+    })))
 }
\ No newline at end of file
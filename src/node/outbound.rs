@@ -0,0 +1,321 @@
+//! The queue of messages waiting to be sent by the socket.
+//!
+//! Messages are queued by priority, votes first and keepalives last, so a burst of keepalives
+//! cannot hold up anything more urgent. The socket reports the bytes it sends, and once they
+//! exceed the bandwidth cap the queue stops yielding messages until the cap allows more. The
+//! cap is lifted when the node shuts down, so the messages already queued are sent at once.
+use futures::{Async, Future, Poll, Stream};
+use futures::task::{self, Task};
+
+use nano_lib_rs::message::{Message, MessageKind};
+
+use tokio_timer::{Sleep, Timer};
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+type Outgoing = (Message, SocketAddr);
+
+/// The queues in the order they are emptied
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Vote = 0,
+    ConfirmReq = 1,
+    Publish = 2,
+    KeepAlive = 3,
+}
+
+impl Priority {
+    pub fn of(kind: MessageKind) -> Self {
+        match kind {
+            MessageKind::ConfirmAck => Priority::Vote,
+            MessageKind::ConfirmReq => Priority::ConfirmReq,
+            MessageKind::KeepAlive => Priority::KeepAlive,
+            _ => Priority::Publish,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Queues {
+    /// Indexed by priority
+    queues: [VecDeque<Outgoing>; 4],
+    /// The task of the socket, waiting for a message
+    task: Option<Task>,
+}
+
+impl Queues {
+    fn len(&self) -> usize {
+        self.queues.iter().map(|queue| queue.len()).sum()
+    }
+
+    fn pop(&mut self) -> Option<Outgoing> {
+        self.queues.iter_mut()
+            .filter_map(|queue| queue.pop_front())
+            .next()
+    }
+}
+
+/// A message which was not queued, or was pushed out of the queue
+#[derive(Debug)]
+pub enum Dropped {
+    /// The queue was full
+    Full(Outgoing),
+    /// A keepalive, refused while the bandwidth cap is reached
+    Saturated(Outgoing),
+}
+
+impl Dropped {
+    pub fn message(&self) -> &Outgoing {
+        match *self {
+            Dropped::Full(ref outgoing) | Dropped::Saturated(ref outgoing) => outgoing,
+        }
+    }
+}
+
+/// Bytes the socket may still send, refilled at `rate` bytes per second. Sending goes into debt
+/// instead of being cut short, since a datagram cannot be split.
+#[derive(Debug)]
+struct Bandwidth {
+    rate: u64,
+    allowance: f64,
+    updated: Instant,
+}
+
+impl Bandwidth {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now - self.updated;
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        // Allow at most a second's worth of bytes to build up
+        self.allowance = (self.allowance + elapsed * self.rate as f64).min(self.rate as f64);
+        self.updated = now;
+    }
+
+    /// How long until the debt is paid off, or `None` if sending can go ahead
+    fn wait(&mut self, now: Instant) -> Option<Duration> {
+        if self.rate == 0 {
+            return None;
+        }
+        self.refill(now);
+        if self.allowance >= 0.0 {
+            return None;
+        }
+        let secs = -self.allowance / self.rate as f64;
+        Some(Duration::new(secs as u64, (secs.fract() * 1e9) as u32))
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    queues: Mutex<Queues>,
+    bandwidth: Mutex<Bandwidth>,
+    /// The most messages waiting at once
+    capacity: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct OutboundQueue {
+    inner: Arc<Inner>,
+}
+
+impl OutboundQueue {
+    /// A queue holding up to `capacity` messages and sending up to `bytes_per_sec`, or without
+    /// a bandwidth cap if it is 0
+    pub fn new(capacity: usize, bytes_per_sec: u64) -> Self {
+        OutboundQueue {
+            inner: Arc::new(Inner {
+                queues: Mutex::new(Queues {
+                    queues: Default::default(),
+                    task: None,
+                }),
+                bandwidth: Mutex::new(Bandwidth {
+                    rate: bytes_per_sec,
+                    allowance: bytes_per_sec as f64,
+                    updated: Instant::now(),
+                }),
+                capacity,
+            }),
+        }
+    }
+
+    /// Queue a message, returning the message dropped to make room if any. Keepalives are
+    /// dropped while the bandwidth cap is reached. When the queue is full, the oldest message of
+    /// the lowest priority below the new one is dropped, or the new one if there is none.
+    pub fn push(&self, msg: Message, addr: SocketAddr) -> Option<Dropped> {
+        let priority = Priority::of(msg.kind());
+        if priority == Priority::KeepAlive && self.is_saturated() {
+            return Some(Dropped::Saturated((msg, addr)));
+        }
+        let mut queues = self.inner.queues.lock().unwrap();
+        let mut dropped = None;
+        if queues.len() >= self.inner.capacity {
+            let lower = (priority as usize + 1..queues.queues.len()).rev()
+                .find(|&i| !queues.queues[i].is_empty());
+            match lower {
+                Some(i) => dropped = queues.queues[i].pop_front().map(Dropped::Full),
+                None => return Some(Dropped::Full((msg, addr))),
+            }
+        }
+        queues.queues[priority as usize].push_back((msg, addr));
+        if let Some(task) = queues.task.take() {
+            task.notify();
+        }
+        dropped
+    }
+
+    /// The number of messages waiting to be sent
+    pub fn len(&self) -> usize {
+        self.inner.queues.lock().unwrap().len()
+    }
+
+    /// Whether the bandwidth cap has been used up
+    pub fn is_saturated(&self) -> bool {
+        self.inner.bandwidth.lock().unwrap().wait(Instant::now()).is_some()
+    }
+
+    /// Count bytes sent by the socket against the bandwidth cap
+    pub fn sent(&self, bytes: usize) {
+        let mut bandwidth = self.inner.bandwidth.lock().unwrap();
+        if bandwidth.rate != 0 {
+            bandwidth.refill(Instant::now());
+            bandwidth.allowance -= bytes as f64;
+        }
+    }
+
+    /// Stop applying the bandwidth cap, waking the socket if it is waiting for it
+    pub fn uncap(&self) {
+        self.inner.bandwidth.lock().unwrap().rate = 0;
+        if let Some(task) = self.inner.queues.lock().unwrap().task.take() {
+            task.notify();
+        }
+    }
+
+    /// The messages in order of priority, for the socket to send. Never ends.
+    pub fn stream(&self, timer: Timer) -> OutboundStream {
+        OutboundStream {
+            queue: self.clone(),
+            timer,
+            sleep: None,
+        }
+    }
+}
+
+pub struct OutboundStream {
+    queue: OutboundQueue,
+    timer: Timer,
+    /// Waiting for the bandwidth cap to allow more
+    sleep: Option<Sleep>,
+}
+
+impl Stream for OutboundStream {
+    type Item = Outgoing;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Outgoing>, ()> {
+        loop {
+            // Checked before the sleep, which is abandoned if the cap was lifted meanwhile
+            let wait = self.queue.inner.bandwidth.lock().unwrap().wait(Instant::now());
+            if let Some(wait) = wait {
+                if self.sleep.is_none() {
+                    self.sleep = Some(self.timer.sleep(wait));
+                }
+                let slept = self.sleep.as_mut().map(|sleep| sleep.poll());
+                match slept {
+                    Some(Ok(Async::NotReady)) => {
+                        // Also woken by `uncap`
+                        self.queue.inner.queues.lock().unwrap().task = Some(task::current());
+                        return Ok(Async::NotReady);
+                    },
+                    Some(Err(e)) => error!("Error waiting for outbound bandwidth: {}", e),
+                    _ => {},
+                }
+                self.sleep = None;
+                continue;
+            }
+            self.sleep = None;
+            let mut queues = self.queue.inner.queues.lock().unwrap();
+            return match queues.pop() {
+                Some(outgoing) => Ok(Async::Ready(Some(outgoing))),
+                None => {
+                    queues.task = Some(task::current());
+                    Ok(Async::NotReady)
+                },
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nano_lib_rs::message::MessageBuilder;
+
+    fn message(kind: MessageKind) -> Message {
+        MessageBuilder::new(kind).build()
+    }
+
+    fn addr() -> SocketAddr {
+        "[::ffff:192.0.2.1]:7075".parse().unwrap()
+    }
+
+    #[test]
+    fn sends_by_priority() {
+        let queue = OutboundQueue::new(16, 0);
+        for &kind in [MessageKind::KeepAlive, MessageKind::Publish, MessageKind::ConfirmAck, MessageKind::ConfirmReq].iter() {
+            assert!(queue.push(message(kind), addr()).is_none());
+        }
+        assert_eq!(queue.len(), 4);
+        let kinds: Vec<MessageKind> = queue.stream(Timer::default())
+            .take(4)
+            .map(|(msg, _)| msg.kind())
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(kinds, vec![MessageKind::ConfirmAck, MessageKind::ConfirmReq, MessageKind::Publish, MessageKind::KeepAlive]);
+    }
+
+    #[test]
+    fn drops_lower_priority_when_full() {
+        let queue = OutboundQueue::new(2, 0);
+        assert!(queue.push(message(MessageKind::KeepAlive), addr()).is_none());
+        assert!(queue.push(message(MessageKind::Publish), addr()).is_none());
+        match queue.push(message(MessageKind::ConfirmReq), addr()) {
+            Some(Dropped::Full((dropped, _))) => assert_eq!(dropped.kind(), MessageKind::KeepAlive),
+            other => panic!("expected a full queue, got {:?}", other),
+        }
+        match queue.push(message(MessageKind::Publish), addr()) {
+            Some(Dropped::Full((dropped, _))) => assert_eq!(dropped.kind(), MessageKind::Publish),
+            other => panic!("expected a full queue, got {:?}", other),
+        }
+        assert_eq!(queue.len(), 2);
+    }
+
+    #[test]
+    fn drops_keepalives_when_saturated() {
+        let queue = OutboundQueue::new(16, 1000);
+        assert!(!queue.is_saturated());
+        queue.sent(1500);
+        assert!(queue.is_saturated());
+        match queue.push(message(MessageKind::KeepAlive), addr()) {
+            Some(Dropped::Saturated(_)) => {},
+            other => panic!("expected a saturated queue, got {:?}", other),
+        }
+        assert!(queue.push(message(MessageKind::Publish), addr()).is_none());
+    }
+
+    #[test]
+    fn sends_at_once_when_uncapped() {
+        let queue = OutboundQueue::new(16, 1000);
+        // Ten seconds of debt
+        queue.sent(11_000);
+        assert!(queue.push(message(MessageKind::Publish), addr()).is_none());
+        queue.uncap();
+        let start = Instant::now();
+        let sent = queue.stream(Timer::default()).take(1).collect().wait().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(!queue.is_saturated());
+    }
+}
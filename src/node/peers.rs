@@ -0,0 +1,64 @@
+//! Peers remembered between runs, so the node can rejoin the network without the
+//! preconfigured peers.
+use serde_json;
+
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::net::SocketAddrV6;
+use std::path::Path;
+
+use error::*;
+
+/// The contents of `peers.json`
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PeerFile {
+    pub peers: Vec<SocketAddrV6>,
+    pub inactive_peers: Vec<SocketAddrV6>,
+}
+
+impl PeerFile {
+    /// Read the peers saved at `path`. A missing file has no peers.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(PeerFile::default());
+        }
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+
+    /// Write the peers to `path`, atomically replacing the previous file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        {
+            let writer = BufWriter::new(File::create(&tmp_path)?);
+            serde_json::to_writer_pretty(writer, self)?;
+        }
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty() && self.inactive_peers.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn saves_and_loads_peers() {
+        let path = env::temp_dir().join(format!("nano-rs-peers-{}.json", ::rand::random::<u64>()));
+        assert_eq!(PeerFile::load(&path).unwrap(), PeerFile::default());
+
+        let peers = PeerFile {
+            peers: vec!["[::ffff:192.168.1.10]:7075".parse().unwrap()],
+            inactive_peers: vec!["[2001:db8::1]:7075".parse().unwrap()],
+        };
+        peers.save(&path).unwrap();
+        assert_eq!(PeerFile::load(&path).unwrap(), peers);
+        fs::remove_file(&path).unwrap();
+    }
+}
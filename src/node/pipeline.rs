@@ -0,0 +1,285 @@
+//! Validation of received blocks off the socket task.
+//!
+//! The socket task only decodes messages and queues the blocks they carry. A pool of worker
+//! threads checks their work and signatures, the expensive part, and the results are put back
+//! into the order the blocks arrived in before they reach the ledger. When the queue is full,
+//! new blocks are shed, confirm_reqs before publishes. A block whose validation panics is
+//! dropped without holding up the ones after it.
+use nano_lib_rs::block::{Block, BlockHash};
+use nano_lib_rs::message::MessageKind;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::net::SocketAddrV6;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use ledger::{self, ProcessResult};
+use node::{State, publish_verified_block};
+use node::reputation::Misbehavior;
+
+/// A block received in a message, waiting to be validated
+#[derive(Debug)]
+pub struct Job {
+    /// The kind of message the block came in
+    pub kind: MessageKind,
+    pub block: Block,
+    pub src: SocketAddrV6,
+}
+
+#[derive(Debug, Default)]
+struct QueueInner {
+    jobs: VecDeque<(u64, Job)>,
+    /// The sequence number of the next job queued
+    next_seq: u64,
+}
+
+/// Items numbered in order, handed back in that order however they arrive. At most `max` items
+/// wait for a missing one before it is given up on.
+#[derive(Debug)]
+struct Sequencer<T> {
+    next: u64,
+    waiting: BTreeMap<u64, T>,
+    max: usize,
+}
+
+impl<T> Sequencer<T> {
+    fn new(max: usize) -> Self {
+        Sequencer {
+            next: 0,
+            waiting: BTreeMap::new(),
+            max: max.max(1),
+        }
+    }
+
+    /// Add the item numbered `seq`, returning every item now ready, in order. An item arriving
+    /// after it was given up on is ready at once.
+    fn push(&mut self, seq: u64, item: T) -> Vec<T> {
+        if seq < self.next {
+            return vec![item];
+        }
+        self.waiting.insert(seq, item);
+        let first = *self.waiting.keys().next().unwrap();
+        if self.waiting.len() > self.max && first > self.next {
+            warn!("Gave up waiting for blocks {} to {}", self.next, first - 1);
+            self.next = first;
+        }
+        let mut ready = Vec::new();
+        while let Some(item) = self.waiting.remove(&self.next) {
+            ready.push(item);
+            self.next += 1;
+        }
+        ready
+    }
+}
+
+#[derive(Debug)]
+pub struct ValidationQueue {
+    /// The most jobs waiting at once
+    capacity: usize,
+    inner: Mutex<QueueInner>,
+    available: Condvar,
+    /// Validated jobs waiting for the ones queued before them, or `None` for a job whose
+    /// validation panicked. Workers hold it while applying results, so they reach the ledger one
+    /// at a time and in order.
+    results: Mutex<Sequencer<Option<(Job, ProcessResult)>>>,
+}
+
+impl ValidationQueue {
+    pub fn new(capacity: usize) -> Self {
+        ValidationQueue {
+            capacity,
+            inner: Mutex::new(QueueInner::default()),
+            available: Condvar::new(),
+            results: Mutex::new(Sequencer::new(capacity)),
+        }
+    }
+
+    /// Queue a block for validation, returning false if it was shed. Confirm_reqs only ask
+    /// about blocks, so they are shed once the queue is half full to leave room for publishes.
+    pub fn push(&self, job: Job) -> bool {
+        let limit = match job.kind {
+            MessageKind::Publish => self.capacity,
+            _ => self.capacity / 2,
+        };
+        let mut inner = self.inner.lock().unwrap();
+        if inner.jobs.len() >= limit {
+            return false;
+        }
+        let seq = inner.next_seq;
+        inner.next_seq += 1;
+        inner.jobs.push_back((seq, job));
+        self.available.notify_one();
+        true
+    }
+
+    /// Take the oldest job, waiting until one is available
+    fn pop(&self) -> (u64, Job) {
+        let mut inner = self.inner.lock().unwrap();
+        loop {
+            if let Some(job) = inner.jobs.pop_front() {
+                return job;
+            }
+            inner = self.available.wait(inner).unwrap();
+        }
+    }
+
+    /// The number of blocks waiting to be validated
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().jobs.len()
+    }
+}
+
+/// Penalize the sender of a rejected block. Blocks which cannot be hashed cannot be told apart
+/// from a replay, so they are not scored.
+fn penalize_sender(state: &State, src: SocketAddrV6, misbehavior: Misbehavior, hash: Option<&BlockHash>) {
+    if let Some(&hash) = hash {
+        state.penalize_block_sender(src, misbehavior, hash);
+    }
+}
+
+/// Act on a validated block, in the order the blocks were received
+fn apply(state: &State, job: Job, result: ProcessResult) {
+    let Job { kind, mut block, src } = job;
+    let block_hash = block.hash(false);
+    let hash = match block_hash {
+        Ok(hash) => hash.into(),
+        Err(ref e) => format!("Error calculating hash for block: {}", e),
+    };
+    info!("Got {:?} block with hash {} in {:?} from {}; {:?}", block.kind, hash, kind, src, result);
+    match result {
+        ProcessResult::InsufficientWork => {
+            state.metrics.work_validation_failed();
+            penalize_sender(state, src, Misbehavior::InsufficientWork, block_hash.as_ref().ok());
+        },
+        ProcessResult::BadSignature => {
+            penalize_sender(state, src, Misbehavior::BadSignature, block_hash.as_ref().ok());
+        },
+        _ => {},
+    }
+    if kind == MessageKind::Publish {
+        if result == ProcessResult::Progress {
+            publish_verified_block(state, block);
+        } else {
+            state.metrics.block_processed(result);
+        }
+    }
+}
+
+/// Check the work and signature of a block, unless the ledger already has it. Most published
+/// blocks are re-floods of ones already processed, which only need to be found `Old`.
+fn validate(state: &State, block: &mut Block) -> ProcessResult {
+    if let Ok(hash) = block.hash(false) {
+        if state.ledger.read().unwrap().contains_block(&hash) {
+            return ProcessResult::Old;
+        }
+    }
+    ledger::verify(block)
+}
+
+/// Validate the job numbered `seq` with `validate`, then `apply` every result now in order.
+/// Panics are caught, so a job which fails leaves a placeholder instead of stalling the jobs
+/// after it, and the results lock is never poisoned.
+fn process<V, A>(results: &Mutex<Sequencer<Option<(Job, ProcessResult)>>>, seq: u64, mut job: Job, validate: V, mut apply: A)
+    where V: FnOnce(&mut Block) -> ProcessResult,
+          A: FnMut(Job, ProcessResult)
+{
+    let validation = panic::catch_unwind(AssertUnwindSafe(|| validate(&mut job.block)));
+    let validated = match validation {
+        Ok(result) => Some((job, result)),
+        Err(_) => {
+            error!("Validating a block from {} panicked, dropping it", job.src);
+            None
+        },
+    };
+    let mut results = results.lock().unwrap();
+    for (job, result) in results.push(seq, validated).into_iter().filter_map(|item| item) {
+        let src = job.src;
+        if panic::catch_unwind(AssertUnwindSafe(|| apply(job, result))).is_err() {
+            error!("Processing a block from {} panicked", src);
+        }
+    }
+}
+
+/// Start `threads` threads validating the blocks queued in `state.validation`
+pub fn spawn_workers(state: Arc<State>, threads: usize) -> Vec<thread::JoinHandle<()>> {
+    (0..threads.max(1)).map(|_| {
+        let state = state.clone();
+        thread::spawn(move || loop {
+            let (seq, job) = state.validation.pop();
+            if state.shutdown.is_triggered() {
+                return;
+            }
+            process(&state.validation.results, seq, job,
+                |block| validate(&state, block),
+                |job, result| apply(&state, job, result));
+        })
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nano_lib_rs::block::BlockKind;
+
+    fn job(kind: MessageKind) -> Job {
+        Job {
+            kind,
+            block: Block::new(BlockKind::State, None, None, None),
+            src: "[::ffff:192.0.2.1]:7075".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn orders_results() {
+        let mut sequencer = Sequencer::new(8);
+        assert!(sequencer.push(1, "b").is_empty());
+        assert!(sequencer.push(3, "d").is_empty());
+        assert_eq!(sequencer.push(0, "a"), vec!["a", "b"]);
+        assert_eq!(sequencer.push(2, "c"), vec!["c", "d"]);
+        assert!(sequencer.waiting.is_empty());
+    }
+
+    #[test]
+    fn gives_up_on_missing_results() {
+        let mut sequencer = Sequencer::new(2);
+        assert!(sequencer.push(1, "b").is_empty());
+        assert!(sequencer.push(2, "c").is_empty());
+        assert_eq!(sequencer.push(4, "e"), vec!["b", "c"]);
+        assert_eq!(sequencer.waiting.len(), 1);
+        // Too late to be put in order
+        assert_eq!(sequencer.push(0, "a"), vec!["a"]);
+        assert_eq!(sequencer.push(3, "d"), vec!["d", "e"]);
+        assert!(sequencer.waiting.is_empty());
+    }
+
+    #[test]
+    fn skips_failing_jobs() {
+        let results = Mutex::new(Sequencer::new(8));
+        let mut applied = Vec::new();
+        process(&results, 1, job(MessageKind::Publish), |_| ProcessResult::Progress, |job, result| applied.push((job.kind, result)));
+        assert!(applied.is_empty());
+        process(&results, 0, job(MessageKind::ConfirmReq), |_| panic!("validation failed"), |job, result| applied.push((job.kind, result)));
+        assert_eq!(applied, vec![(MessageKind::Publish, ProcessResult::Progress)]);
+
+        // A panic while applying does not poison the results either
+        process(&results, 2, job(MessageKind::Publish), |_| ProcessResult::Old, |_, _| panic!("apply failed"));
+        process(&results, 3, job(MessageKind::Publish), |_| ProcessResult::Old, |job, result| applied.push((job.kind, result)));
+        assert_eq!(applied.len(), 2);
+        assert!(results.lock().unwrap().waiting.is_empty());
+    }
+
+    #[test]
+    fn sheds_confirm_reqs_first() {
+        let queue = ValidationQueue::new(4);
+        assert!(queue.push(job(MessageKind::ConfirmReq)));
+        assert!(queue.push(job(MessageKind::ConfirmReq)));
+        assert!(!queue.push(job(MessageKind::ConfirmReq)));
+        assert!(queue.push(job(MessageKind::Publish)));
+        assert!(queue.push(job(MessageKind::Publish)));
+        assert!(!queue.push(job(MessageKind::Publish)));
+        assert_eq!(queue.len(), 4);
+        let seqs: Vec<u64> = (0..4).map(|_| queue.pop().0).collect();
+        assert_eq!(seqs, vec![0, 1, 2, 3]);
+    }
+}
@@ -0,0 +1,329 @@
+//! Scoring of misbehaving peers. Each misbehavior adds a penalty to the score of the sender's
+//! IP, which slowly decays. An IP whose score reaches the threshold is banned for a while, and
+//! datagrams from banned IPs are dropped before they are decoded.
+//!
+//! Source IPs can be spoofed, so only misbehavior which takes real work to fake can get an IP
+//! banned, and the preconfigured peers are never banned automatically. A rejected block only
+//! counts against the first IP it came from, so replaying it from forged addresses costs them
+//! nothing.
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::Ipv6Addr;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use nano_lib_rs::block::BlockHash;
+
+/// Seconds for a score to decay by one point
+const SCORE_DECAY_SECS: u64 = 10;
+/// The longest an IP can be banned for, in seconds
+pub const MAX_BAN_SECS: u64 = 365 * 24 * 60 * 60;
+/// How many rejected block hashes are remembered
+const MAX_REJECTED_BLOCKS: usize = 4096;
+
+/// Something a peer did wrong
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Misbehavior {
+    /// Sent a message which could not be decoded
+    InvalidMessage,
+    /// Sent a message for another network
+    WrongNetwork,
+    /// Sent a block whose work is below the threshold
+    InsufficientWork,
+    /// Sent a block with an invalid signature
+    BadSignature,
+}
+
+impl Misbehavior {
+    /// Points added to the score of the peer. Peers on another network are usually only
+    /// misconfigured, while forging signatures is never an accident.
+    pub fn penalty(&self) -> u32 {
+        match *self {
+            Misbehavior::InvalidMessage => 10,
+            Misbehavior::WrongNetwork => 5,
+            Misbehavior::InsufficientWork => 20,
+            Misbehavior::BadSignature => 50,
+        }
+    }
+
+    /// Whether this can push a score over the threshold. Undecodable messages, messages for
+    /// another network and blocks without work cost nothing to send from a forged address, but
+    /// a block with a bad signature has to carry valid work.
+    pub fn can_ban(&self) -> bool {
+        *self == Misbehavior::BadSignature
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Score {
+    points: u32,
+    /// When the points were last decayed
+    updated: Instant,
+}
+
+impl Score {
+    fn decay(&mut self, now: Instant) {
+        let steps = (now - self.updated).as_secs() / SCORE_DECAY_SECS;
+        self.points = self.points.saturating_sub(steps.min(u32::max_value() as u64) as u32);
+        self.updated += Duration::from_secs(steps * SCORE_DECAY_SECS);
+    }
+}
+
+/// The hashes of recently rejected blocks, forgetting the oldest once full
+#[derive(Debug, Default)]
+struct RejectedBlocks {
+    hashes: HashSet<BlockHash>,
+    order: VecDeque<BlockHash>,
+}
+
+impl RejectedBlocks {
+    /// Remember `hash`. Returns whether it was new.
+    fn insert(&mut self, hash: BlockHash) -> bool {
+        if !self.hashes.insert(hash) {
+            return false;
+        }
+        self.order.push_back(hash);
+        if self.order.len() > MAX_REJECTED_BLOCKS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.hashes.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+#[derive(Debug)]
+pub struct Reputation {
+    /// Scores at or above this get the IP banned
+    threshold: u32,
+    ban_duration: Duration,
+    /// IPs which are never banned for misbehavior
+    trusted: HashSet<Ipv6Addr>,
+    scores: Mutex<HashMap<Ipv6Addr, Score>>,
+    /// Banned IPs and when their ban ends
+    bans: RwLock<HashMap<Ipv6Addr, Instant>>,
+    rejected: Mutex<RejectedBlocks>,
+}
+
+impl Reputation {
+    pub fn new(threshold: u32, ban_duration: Duration, trusted: HashSet<Ipv6Addr>) -> Self {
+        Reputation {
+            threshold,
+            ban_duration,
+            trusted,
+            scores: Mutex::new(HashMap::new()),
+            bans: RwLock::new(HashMap::new()),
+            rejected: Mutex::new(RejectedBlocks::default()),
+        }
+    }
+
+    /// How long IPs are banned for when their score reaches the threshold
+    pub fn ban_duration(&self) -> Duration {
+        self.ban_duration
+    }
+
+    /// Add the penalty for `misbehavior` to the score of `ip`, banning it for the configured
+    /// time if the score reaches the threshold. Misbehavior which cannot ban on its own stops
+    /// just short of the threshold, and trusted IPs are not scored. Returns whether `ip` was
+    /// banned.
+    pub fn penalize(&self, ip: Ipv6Addr, misbehavior: Misbehavior) -> bool {
+        if self.trusted.contains(&ip) {
+            return false;
+        }
+        let now = Instant::now();
+        let mut scores = self.scores.lock().unwrap();
+        let banned = {
+            let score = scores.entry(ip).or_insert(Score { points: 0, updated: now });
+            score.decay(now);
+            score.points = score.points.saturating_add(misbehavior.penalty());
+            if !misbehavior.can_ban() {
+                score.points = score.points.min(self.threshold.saturating_sub(1));
+            }
+            score.points >= self.threshold
+        };
+        if banned {
+            scores.remove(&ip);
+            drop(scores);
+            self.ban(ip, self.ban_duration);
+        }
+        banned
+    }
+
+    /// Like `penalize`, for sending the block `hash` which was rejected. Only the first IP to
+    /// send a given block is scored. Returns whether `ip` was banned.
+    pub fn penalize_block(&self, ip: Ipv6Addr, misbehavior: Misbehavior, hash: BlockHash) -> bool {
+        if !self.rejected.lock().unwrap().insert(hash) {
+            return false;
+        }
+        self.penalize(ip, misbehavior)
+    }
+
+    /// Ban `ip` for `duration`, at most `MAX_BAN_SECS`, replacing any ban it already had
+    pub fn ban(&self, ip: Ipv6Addr, duration: Duration) {
+        let duration = duration.min(Duration::from_secs(MAX_BAN_SECS));
+        let now = Instant::now();
+        let until = now.checked_add(duration).unwrap_or(now);
+        self.bans.write().unwrap().insert(ip, until);
+    }
+
+    /// Lift the ban on `ip` and forget its score. Returns whether it was banned.
+    pub fn unban(&self, ip: Ipv6Addr) -> bool {
+        self.scores.lock().unwrap().remove(&ip);
+        match self.bans.write().unwrap().remove(&ip) {
+            Some(until) => until > Instant::now(),
+            None => false,
+        }
+    }
+
+    pub fn is_banned(&self, ip: Ipv6Addr) -> bool {
+        match self.bans.read().unwrap().get(&ip) {
+            Some(until) => *until > Instant::now(),
+            None => false,
+        }
+    }
+
+    /// The current score of `ip`
+    pub fn score(&self, ip: Ipv6Addr) -> u32 {
+        match self.scores.lock().unwrap().get_mut(&ip) {
+            Some(score) => {
+                score.decay(Instant::now());
+                score.points
+            },
+            None => 0,
+        }
+    }
+
+    /// The banned IPs with the time left on their ban
+    pub fn bans(&self) -> Vec<(Ipv6Addr, Duration)> {
+        let now = Instant::now();
+        self.bans.read().unwrap().iter()
+            .filter(|&(_, until)| *until > now)
+            .map(|(ip, until)| (*ip, *until - now))
+            .collect()
+    }
+
+    /// Forget expired bans and scores which have decayed to nothing
+    pub fn prune(&self) {
+        let now = Instant::now();
+        self.bans.write().unwrap().retain(|_, until| *until > now);
+        self.scores.lock().unwrap().retain(|_, score| {
+            score.decay(now);
+            score.points > 0
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reputation() -> Reputation {
+        Reputation::new(100, Duration::from_secs(60), HashSet::new())
+    }
+
+    fn block_hash(i: usize) -> BlockHash {
+        let mut bytes = [0u8; 32];
+        bytes[0] = i as u8;
+        bytes[1] = (i >> 8) as u8;
+        BlockHash::from_bytes(&bytes[..]).unwrap()
+    }
+
+    #[test]
+    fn bans_at_threshold() {
+        let reputation = reputation();
+        let ip: Ipv6Addr = "::ffff:192.0.2.1".parse().unwrap();
+        assert!(!reputation.penalize(ip, Misbehavior::BadSignature));
+        assert_eq!(reputation.score(ip), 50);
+        assert!(!reputation.is_banned(ip));
+        assert!(reputation.penalize(ip, Misbehavior::BadSignature));
+        assert!(reputation.is_banned(ip));
+        assert_eq!(reputation.score(ip), 0);
+        assert_eq!(reputation.bans().len(), 1);
+
+        assert!(reputation.unban(ip));
+        assert!(!reputation.is_banned(ip));
+        assert!(!reputation.unban(ip));
+    }
+
+    #[test]
+    fn cheap_misbehavior_does_not_ban() {
+        let reputation = reputation();
+        let ip: Ipv6Addr = "::ffff:192.0.2.1".parse().unwrap();
+        for _ in 0..20 {
+            assert!(!reputation.penalize(ip, Misbehavior::InvalidMessage));
+            assert!(!reputation.penalize(ip, Misbehavior::WrongNetwork));
+            assert!(!reputation.penalize(ip, Misbehavior::InsufficientWork));
+        }
+        assert_eq!(reputation.score(ip), 99);
+        assert!(!reputation.is_banned(ip));
+        assert!(reputation.penalize(ip, Misbehavior::BadSignature));
+    }
+
+    #[test]
+    fn scores_each_block_once() {
+        let reputation = reputation();
+        let first: Ipv6Addr = "::ffff:192.0.2.1".parse().unwrap();
+        let second: Ipv6Addr = "::ffff:192.0.2.2".parse().unwrap();
+        let block = block_hash(1);
+        // The same block replayed from another address, then again from the first
+        assert!(!reputation.penalize_block(first, Misbehavior::BadSignature, block));
+        assert!(!reputation.penalize_block(second, Misbehavior::BadSignature, block));
+        assert!(!reputation.penalize_block(first, Misbehavior::BadSignature, block));
+        assert_eq!(reputation.score(first), 50);
+        assert_eq!(reputation.score(second), 0);
+
+        assert!(reputation.penalize_block(first, Misbehavior::BadSignature, block_hash(2)));
+    }
+
+    #[test]
+    fn forgets_old_rejected_blocks() {
+        let mut rejected = RejectedBlocks::default();
+        for i in 0..MAX_REJECTED_BLOCKS + 1 {
+            assert!(rejected.insert(block_hash(i)));
+        }
+        assert!(!rejected.insert(block_hash(MAX_REJECTED_BLOCKS)));
+        assert_eq!(rejected.hashes.len(), MAX_REJECTED_BLOCKS);
+        // The oldest was forgotten to make room
+        assert!(rejected.insert(block_hash(0)));
+    }
+
+    #[test]
+    fn never_bans_trusted_ips() {
+        let ip: Ipv6Addr = "::ffff:192.0.2.1".parse().unwrap();
+        let reputation = Reputation::new(100, Duration::from_secs(60), vec![ip].into_iter().collect());
+        for _ in 0..10 {
+            assert!(!reputation.penalize(ip, Misbehavior::BadSignature));
+        }
+        assert_eq!(reputation.score(ip), 0);
+        assert!(!reputation.is_banned(ip));
+    }
+
+    #[test]
+    fn expires_bans() {
+        let reputation = reputation();
+        let ip: Ipv6Addr = "::ffff:192.0.2.1".parse().unwrap();
+        reputation.ban(ip, Duration::from_secs(0));
+        assert!(!reputation.is_banned(ip));
+        reputation.prune();
+        assert!(reputation.bans().is_empty());
+    }
+
+    #[test]
+    fn caps_ban_duration() {
+        let reputation = reputation();
+        let ip: Ipv6Addr = "::ffff:192.0.2.1".parse().unwrap();
+        reputation.ban(ip, Duration::from_secs(u64::max_value()));
+        assert!(reputation.is_banned(ip));
+        let bans = reputation.bans();
+        assert_eq!(bans.len(), 1);
+        assert!(bans[0].1 <= Duration::from_secs(MAX_BAN_SECS));
+    }
+
+    #[test]
+    fn decays_scores() {
+        let now = Instant::now();
+        let mut score = Score { points: 10, updated: now };
+        score.decay(now + Duration::from_secs(25));
+        assert_eq!(score.points, 8);
+        assert_eq!(score.updated, now + Duration::from_secs(20));
+    }
+}
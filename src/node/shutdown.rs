@@ -0,0 +1,140 @@
+//! Stopping the node cleanly. A `Shutdown` is triggered once, by a signal or by the caller,
+//! and every long running task of the node is tied to it.
+use futures::{Async, Future, Poll, Stream};
+use futures::future::Shared;
+use futures::sync::oneshot;
+
+use std::fmt;
+use std::sync::Mutex;
+
+use error::*;
+
+pub struct Shutdown {
+    trigger: Mutex<Option<oneshot::Sender<()>>>,
+    signal: Shared<oneshot::Receiver<()>>,
+}
+
+impl fmt::Debug for Shutdown {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Shutdown")
+            .field("triggered", &self.is_triggered())
+            .finish()
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (trigger, signal) = oneshot::channel();
+        Shutdown {
+            trigger: Mutex::new(Some(trigger)),
+            signal: signal.shared(),
+        }
+    }
+
+    /// Tell every task tied to this shutdown to stop. Only the first call has any effect.
+    pub fn trigger(&self) {
+        if let Some(trigger) = self.trigger.lock().unwrap().take() {
+            let _ = trigger.send(());
+        }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.trigger.lock().unwrap().is_none()
+    }
+
+    /// Run `future` until it finishes or the shutdown is triggered, whichever is first
+    pub fn until<F>(&self, future: F) -> impl Future<Item=(), Error=()>
+        where F: Future<Item=(), Error=()>
+    {
+        future.select(self.signal.clone().then(|_| Ok(())))
+            .then(|_| Ok(()))
+    }
+
+    /// Pass on the items of `stream` until the shutdown is triggered, then only the ones
+    /// already waiting. Used to flush a queue without waiting for new items.
+    pub fn drain<S: Stream>(&self, stream: S) -> Drain<S> {
+        Drain {
+            stream,
+            signal: self.signal.clone(),
+            stopping: false,
+        }
+    }
+}
+
+pub struct Drain<S> {
+    stream: S,
+    signal: Shared<oneshot::Receiver<()>>,
+    stopping: bool,
+}
+
+impl<S: Stream> Stream for Drain<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        if !self.stopping {
+            match self.signal.poll() {
+                Ok(Async::NotReady) => {},
+                _ => self.stopping = true,
+            }
+        }
+        match self.stream.poll()? {
+            Async::NotReady if self.stopping => Ok(Async::Ready(None)),
+            ready => Ok(ready),
+        }
+    }
+}
+
+/// Resolves on the first SIGINT or SIGTERM
+#[cfg(unix)]
+pub fn wait_for_signal() -> impl Future<Item=(), Error=Error> {
+    use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+
+    Signal::new(SIGINT).flatten_stream()
+        .select(Signal::new(SIGTERM).flatten_stream())
+        .into_future()
+        .map(|(signal, _)| {
+            let name = match signal {
+                Some(SIGTERM) => "SIGTERM",
+                _ => "SIGINT",
+            };
+            info!("Received {}", name);
+        })
+        .map_err(|(e, _)| e.into())
+}
+
+/// Resolves on the first Ctrl-C
+#[cfg(not(unix))]
+pub fn wait_for_signal() -> impl Future<Item=(), Error=Error> {
+    ::tokio_signal::ctrl_c().flatten_stream()
+        .into_future()
+        .map(|_| info!("Received Ctrl-C"))
+        .map_err(|(e, _)| e.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use futures::sync::mpsc;
+
+    #[test]
+    fn flushes_waiting_items() {
+        let shutdown = Shutdown::new();
+        let (tx, rx) = mpsc::unbounded::<u32>();
+        tx.unbounded_send(1).unwrap();
+        tx.unbounded_send(2).unwrap();
+        shutdown.trigger();
+        shutdown.trigger();
+        assert!(shutdown.is_triggered());
+        // The sender is still open, so without the shutdown this would wait forever
+        assert_eq!(shutdown.drain(rx).collect().wait().unwrap(), vec![1, 2]);
+        assert_eq!(shutdown.until(future::empty::<(), ()>()).wait(), Ok(()));
+    }
+}
@@ -1,22 +1,67 @@
-use std::sync::{RwLock};
+use std::sync::RwLock;
 use std::time::{Instant, Duration};
-use std::net::{SocketAddrV6};
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6};
 use indexmap::IndexMap;
 use indexmap::map::{Entry};
 use rand::{self, Rng};
 
+use nano_lib_rs::block::BlockHash;
+use nano_lib_rs::message::{Message, MessageHeader, MessageKind, NetworkKind};
+
+use ledger::Ledger;
+use metrics::Metrics;
+use node::limits::{PeerLimits, RateLimiter};
+use node::outbound::{Dropped, OutboundQueue};
+use node::peers::PeerFile;
+use node::pipeline::ValidationQueue;
+use node::reputation::{Misbehavior, Reputation};
+use node::shutdown::Shutdown;
 use utils::{check_addr};
-use super::KEEPALIVE_CUTOFF;
+use wallet::WalletStore;
+use wallet::actions::AccountLocks;
+use wallet::receiver::ReceiveQueue;
+use work::{WorkCache, WorkPool};
 
 #[derive(Clone, Copy, Debug)]
 pub struct PeerInfo {
-    last_seen: Instant
+    pub first_seen: Instant,
+    pub last_seen: Instant,
+    pub stats: PeerStats,
 }
 
 impl Default for PeerInfo {
     fn default() -> Self {
+        let now = Instant::now();
         PeerInfo {
-            last_seen: Instant::now()
+            first_seen: now,
+            last_seen: now,
+            stats: PeerStats::default(),
+        }
+    }
+}
+
+/// Traffic exchanged with a peer since it was first seen by this run of the node
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PeerStats {
+    /// Messages by kind, indexed by the value of the kind
+    pub messages_received: [u64; 9],
+    pub messages_sent: [u64; 9],
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    /// Messages which could not be decoded
+    pub invalid_messages: u64,
+    /// The header of the latest valid message, holding the versions and extensions of the peer
+    pub last_header: Option<MessageHeader>,
+}
+
+impl PeerStats {
+    pub fn record_received(&mut self, header: &MessageHeader, bytes: usize) {
+        self.messages_received[header.kind as usize] += 1;
+        self.bytes_received += bytes as u64;
+        if header.kind == MessageKind::Invalid {
+            self.invalid_messages += 1;
+        } else {
+            self.last_header = Some(*header);
         }
     }
 }
@@ -25,15 +70,89 @@ type Peers = IndexMap<SocketAddrV6, PeerInfo>;
 
 #[derive(Debug)]
 pub struct State {
+    pub network: NetworkKind,
     pub peers: RwLock<Peers>,
     pub inactive_peers: RwLock<Peers>,
+    pub ledger: RwLock<Ledger>,
+    /// Held while the ledger is written to disk, so saves from different threads do not
+    /// interleave or finish out of order
+    pub ledger_saving: Mutex<()>,
+    pub wallets: RwLock<WalletStore>,
+    /// Serializes the wallet actions on each account
+    pub account_locks: AccountLocks,
+    /// Generates work for locally created blocks
+    pub work: WorkPool,
+    /// Work computed ahead of time for the next block of each wallet account
+    pub work_cache: WorkCache,
+    /// Pending sends to wallet accounts waiting to be received
+    pub receive_queue: ReceiveQueue,
+    /// Messages queued here are sent by the socket task, most urgent first
+    pub outgoing: OutboundQueue,
+    /// Received blocks waiting for their work and signature to be checked
+    pub validation: ValidationQueue,
+    /// Triggered when the node is stopping
+    pub shutdown: Shutdown,
+    pub metrics: Metrics,
+    /// Misbehavior scores and bans of peer IPs
+    pub reputation: Reputation,
+    /// Token buckets on the messages of each source IP
+    pub rate_limiter: RateLimiter,
+    pub peer_limits: PeerLimits,
 }
 
 impl State {
-    pub fn new(initial_peers: Peers) -> Self {
+    pub fn new(
+        network: NetworkKind,
+        initial_peers: Peers,
+        ledger: Ledger,
+        wallets: WalletStore,
+        receive_minimum: u128,
+        work: WorkPool,
+        work_cache: WorkCache,
+        outgoing: OutboundQueue,
+        validation: ValidationQueue,
+        reputation: Reputation,
+        rate_limiter: RateLimiter,
+        peer_limits: PeerLimits,
+    ) -> Self {
         State {
+            network,
             peers: RwLock::new(initial_peers),
             inactive_peers: RwLock::new(IndexMap::new()),
+            ledger: RwLock::new(ledger),
+            ledger_saving: Mutex::new(()),
+            wallets: RwLock::new(wallets),
+            account_locks: AccountLocks::default(),
+            work,
+            work_cache,
+            receive_queue: ReceiveQueue::new(receive_minimum),
+            outgoing,
+            validation,
+            shutdown: Shutdown::new(),
+            metrics: Metrics::new(),
+            reputation,
+            rate_limiter,
+            peer_limits,
+        }
+    }
+
+    /// Queue a message to be sent. Returns false if a message had to be dropped, either this
+    /// one or a queued one of lower priority.
+    pub fn send_message(&self, msg: Message, addr: SocketAddr) -> bool {
+        match self.outgoing.push(msg, addr) {
+            None => true,
+            Some(dropped) => {
+                match dropped {
+                    Dropped::Full((ref msg, addr)) => {
+                        debug!("Dropped {:?} to {}, the outgoing queue is full", msg.kind(), addr);
+                    },
+                    Dropped::Saturated((ref msg, addr)) => {
+                        debug!("Not sending {:?} to {}, the bandwidth cap is reached", msg.kind(), addr);
+                    },
+                }
+                self.metrics.message_dropped(dropped.message().0.kind());
+                false
+            }
         }
     }
 
@@ -41,7 +160,23 @@ impl State {
         self.peers.read().unwrap().len()
     }
 
-    pub fn add_or_update_peer(&self, peer: SocketAddrV6, force: bool) -> bool {
+    /// Mark `peer` as seen, adding it to the active peers if it is allowed, and count the
+    /// message `received` from it if any. Returns whether it was added.
+    pub fn add_or_update_peer(&self, peer: SocketAddrV6, force: bool, received: Option<(&MessageHeader, usize)>) -> bool {
+        if self.reputation.is_banned(*peer.ip()) {
+            return false;
+        }
+        let seen = |info: &mut PeerInfo| {
+            info.last_seen = Instant::now();
+            if let Some((header, bytes)) = received {
+                info.stats.record_received(header, bytes);
+            }
+        };
+        // Most messages come from active peers, which only need the one lock
+        if let Some(info) = self.peers.write().unwrap().get_mut(&peer) {
+            seen(info);
+            return false;
+        }
         if !force {
             let inactive_map = self.inactive_peers.read().unwrap();
             if let Some(_) = inactive_map.get(&peer) {
@@ -54,28 +189,61 @@ impl State {
             map.insert(peer, *entry.get());
             entry.remove();
         }
-        match map.entry(peer) {
-            Entry::Occupied(mut entry) => {
-                entry.get_mut().last_seen = Instant::now();
-                false
-            },
-            Entry::Vacant(entry) => {
-                if check_addr(peer) {
-                    entry.insert(PeerInfo::default());
-                    true
-                } else {
-                    false
-                }
+        if let Some(info) = map.get_mut(&peer) {
+            seen(info);
+            return false;
+        }
+        if !check_addr(peer) {
+            return false;
+        }
+        if !self.peer_limits.allows(peer.ip(), map.keys().map(|addr| addr.ip())) {
+            debug!("Not adding peer {}, its IP or subnet has too many peers", peer);
+            self.metrics.peer_rejected();
+            return false;
+        }
+        let mut info = PeerInfo::default();
+        seen(&mut info);
+        map.insert(peer, info);
+        true
+    }
+
+    /// Count a message received from `peer` without marking it as seen, if it is active
+    pub fn peer_message_received(&self, peer: SocketAddrV6, header: &MessageHeader, bytes: usize) {
+        if let Some(info) = self.peers.write().unwrap().get_mut(&peer) {
+            info.stats.record_received(header, bytes);
+        }
+    }
+
+    /// Count the messages sent to active peers, with their kind and size in bytes, under a
+    /// single lock
+    pub fn peer_messages_sent(&self, sent: &[(SocketAddrV6, MessageKind, usize)]) {
+        if sent.is_empty() {
+            return;
+        }
+        let mut map = self.peers.write().unwrap();
+        for &(peer, kind, bytes) in sent {
+            if let Some(info) = map.get_mut(&peer) {
+                info.stats.messages_sent[kind as usize] += 1;
+                info.stats.bytes_sent += bytes as u64;
             }
         }
     }
 
-    pub fn prune_peers(&self) -> usize {
+    /// The details of an active or inactive peer, and whether it is active
+    pub fn peer_info(&self, peer: SocketAddrV6) -> Option<(PeerInfo, bool)> {
+        if let Some(info) = self.peers.read().unwrap().get(&peer) {
+            return Some((*info, true));
+        }
+        self.inactive_peers.read().unwrap().get(&peer).map(|info| (*info, false))
+    }
+
+    /// Move peers not heard from within `cutoff` to the inactive peers
+    pub fn prune_peers(&self, cutoff: Duration) -> usize {
         let mut inactive_map = self.inactive_peers.write().unwrap();
         let mut map = self.peers.write().unwrap();
         let mut to_prune = Vec::new();
         for (addr, info) in map.iter() {
-            if Instant::now() - info.last_seen > Duration::from_secs(KEEPALIVE_CUTOFF) {
+            if Instant::now() - info.last_seen > cutoff {
                 to_prune.push(*addr);
                 inactive_map.insert(*addr, *info);
             }
@@ -86,6 +254,64 @@ impl State {
         to_prune.len()
     }
     
+    /// The active and inactive peers, to be saved for the next run
+    pub fn peer_file(&self) -> PeerFile {
+        PeerFile {
+            peers: self.peers.read().unwrap().keys().cloned().collect(),
+            inactive_peers: self.inactive_peers.read().unwrap().keys().cloned().collect(),
+        }
+    }
+
+    /// Add the peers saved by an earlier run. Active peers are contacted again and pruned as
+    /// usual if they stay silent; inactive peers are only remembered.
+    pub fn restore_peers(&self, saved: PeerFile) {
+        let mut inactive_map = self.inactive_peers.write().unwrap();
+        let mut map = self.peers.write().unwrap();
+        for peer in saved.peers.into_iter().filter(|peer| check_addr(*peer)) {
+            map.entry(peer).or_insert_with(PeerInfo::default);
+        }
+        for peer in saved.inactive_peers.into_iter().filter(|peer| check_addr(*peer)) {
+            if !map.contains_key(&peer) {
+                inactive_map.entry(peer).or_insert_with(PeerInfo::default);
+            }
+        }
+    }
+
+    /// Count misbehavior by `peer` against its IP, dropping every peer on the IP if it gets banned
+    pub fn penalize_peer(&self, peer: SocketAddrV6, misbehavior: Misbehavior) {
+        debug!("Penalizing {} for {:?}", peer, misbehavior);
+        let banned = self.reputation.penalize(*peer.ip(), misbehavior);
+        self.penalized(peer, misbehavior, banned);
+    }
+
+    /// Penalize `peer` for sending the rejected block `hash`, unless another peer sent it first
+    pub fn penalize_block_sender(&self, peer: SocketAddrV6, misbehavior: Misbehavior, hash: BlockHash) {
+        debug!("Penalizing {} for {:?} in block {}", peer, misbehavior, String::from(hash));
+        let banned = self.reputation.penalize_block(*peer.ip(), misbehavior, hash);
+        self.penalized(peer, misbehavior, banned);
+    }
+
+    fn penalized(&self, peer: SocketAddrV6, misbehavior: Misbehavior, banned: bool) {
+        if banned {
+            warn!("Banned {} after {:?}", peer.ip(), misbehavior);
+            self.metrics.peer_banned();
+            self.remove_ip(*peer.ip());
+        }
+    }
+
+    /// Ban `ip` for `duration` and drop every peer on it
+    pub fn ban_ip(&self, ip: Ipv6Addr, duration: Duration) {
+        info!("Banned {} for {} seconds", ip, duration.as_secs());
+        self.reputation.ban(ip, duration);
+        self.metrics.peer_banned();
+        self.remove_ip(ip);
+    }
+
+    fn remove_ip(&self, ip: Ipv6Addr) {
+        self.peers.write().unwrap().retain(|addr, _| *addr.ip() != ip);
+        self.inactive_peers.write().unwrap().retain(|addr, _| *addr.ip() != ip);
+    }
+
     pub fn remove_peer(&self, peer: SocketAddrV6) {
         let mut map = self.peers.write().unwrap();
         if let Entry::Occupied(entry) = map.entry(peer) {
@@ -96,6 +322,9 @@ impl State {
     pub fn random_peers(&self, n: usize) -> Vec<SocketAddrV6> {
         let mut rng = rand::thread_rng();
         let peers = self.peers.read().unwrap();
+        if peers.is_empty() {
+            return Vec::new();
+        }
         (0..n).into_iter().map(|_| {
             let idx = rng.gen_range::<usize>(0, peers.len());
             peers.get_index(idx).unwrap().0.clone()
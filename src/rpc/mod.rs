@@ -0,0 +1,411 @@
+//! A JSON RPC server using the action based request format of the reference node.
+//! Every request is a POST with a JSON object body containing an `action` field.
+use hyper::{self, Body, Client, Method, Request, Response, Server, StatusCode};
+use hyper::header::CONTENT_TYPE;
+use hyper::service::service_fn;
+use serde_json::{self, Value};
+
+use futures::{future, Future, Stream};
+use futures::sync::oneshot;
+
+use nano_lib_rs::block::BlockHash;
+use nano_lib_rs::keys::{Account, Address, PublicKey};
+use tokio::runtime::Runtime;
+
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use metrics::MESSAGE_KINDS;
+use node::State;
+use node::reputation::MAX_BAN_SECS;
+use node::state::{PeerInfo, PeerStats};
+use wallet::{actions, receiver};
+use work;
+use error::*;
+
+type RpcFuture = Box<Future<Item=Value, Error=Error> + Send>;
+
+/// The number of threads running wallet actions
+const WORKER_THREADS: usize = 4;
+/// The most wallet actions waiting for a thread before new ones are refused
+const WORKER_QUEUE_SIZE: usize = 64;
+
+type Job = Box<FnMut() + Send>;
+
+/// A fixed set of threads running the actions which block, since wallet actions may spend a
+/// long time generating work
+#[derive(Debug)]
+struct Workers {
+    jobs: Mutex<mpsc::SyncSender<Job>>,
+}
+
+impl Workers {
+    fn new(threads: usize, queue_size: usize) -> Self {
+        let (jobs, queue) = mpsc::sync_channel::<Job>(queue_size);
+        let queue = Arc::new(Mutex::new(queue));
+        for _ in 0..threads {
+            let queue = queue.clone();
+            thread::spawn(move || loop {
+                let job = queue.lock().unwrap().recv();
+                match job {
+                    Ok(mut job) => job(),
+                    Err(_) => return,
+                }
+            });
+        }
+        Workers { jobs: Mutex::new(jobs) }
+    }
+
+    /// Queue an action, failing with `RpcBusy` if the queue is full
+    fn run<F>(&self, f: F) -> RpcFuture
+        where F: FnOnce() -> Result<Value> + Send + 'static
+    {
+        let (tx, rx) = oneshot::channel();
+        let mut task = Some((f, tx));
+        let job: Job = Box::new(move || {
+            if let Some((f, tx)) = task.take() {
+                let _ = tx.send(f());
+            }
+        });
+        if self.jobs.lock().unwrap().try_send(job).is_err() {
+            return Box::new(future::err(ErrorKind::RpcBusy.into()));
+        }
+        Box::new(rx.then(|res| match res {
+            Ok(result) => result,
+            Err(_) => Err("RPC action was cancelled".into()),
+        }))
+    }
+}
+
+/// Bind to `addr` and return a future serving RPC requests
+pub fn serve(addr: SocketAddr, state: Arc<State>) -> Result<impl Future<Item=(), Error=()>> {
+    let workers = Arc::new(Workers::new(WORKER_THREADS, WORKER_QUEUE_SIZE));
+    let server = Server::try_bind(&addr)?
+        .serve(move || {
+            let state = state.clone();
+            let workers = workers.clone();
+            service_fn(move |req| handle_request(req, state.clone(), workers.clone()))
+        });
+    info!("RPC listening on: {}", server.local_addr());
+    Ok(server.map_err(|e| error!("RPC server error: {}", e)))
+}
+
+fn handle_request(req: Request<Body>, state: Arc<State>, workers: Arc<Workers>)
+    -> Box<Future<Item=Response<Body>, Error=hyper::Error> + Send>
+{
+    if req.method() != &Method::POST {
+        let response = Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .body(Body::empty())
+            .unwrap();
+        return Box::new(future::ok(response));
+    }
+    Box::new(req.into_body().concat2().and_then(move |body| {
+        let result: RpcFuture = match serde_json::from_slice::<Value>(&body) {
+            Ok(request) => dispatch(request, state, &workers),
+            Err(e) => Box::new(future::err(e.into())),
+        };
+        result.then(|res| {
+            let value = res.unwrap_or_else(|e| json!({ "error": format!("{}", e) }));
+            Ok(json_response(&value))
+        })
+    }))
+}
+
+fn json_response(value: &Value) -> Response<Body> {
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(value.to_string()))
+        .unwrap()
+}
+
+fn dispatch(request: Value, state: Arc<State>, workers: &Workers) -> RpcFuture {
+    let action = match request["action"].as_str() {
+        Some(action) => action.to_owned(),
+        None => return Box::new(future::err(ErrorKind::InvalidRpcRequest("missing action".into()).into())),
+    };
+    debug!("RPC request: {}", action);
+    match action.as_str() {
+        "wallet_create" => workers.run(move || wallet_create(&request, &state)),
+        "password_enter" => workers.run(move || password_enter(&request, &state)),
+        "account_create" => workers.run(move || account_create(&request, &state)),
+        "send" => workers.run(move || send(&request, &state)),
+        "receive" => workers.run(move || receive(&request, &state)),
+        "account_representative_set" => workers.run(move || account_representative_set(&request, &state)),
+        "peers" => Box::new(future::result(peers(&request, &state))),
+        "stats" => Box::new(future::result(stats(&request, &state))),
+        "peer_ban" => Box::new(future::result(peer_ban(&request, &state))),
+        "peer_unban" => Box::new(future::result(peer_unban(&request, &state))),
+        "bans" => Box::new(future::result(bans(&state))),
+        _ => Box::new(future::err(ErrorKind::InvalidRpcRequest(format!("unknown action: {}", action)).into())),
+    }
+}
+
+fn get_str<'a>(request: &'a Value, field: &str) -> Result<&'a str> {
+    request[field].as_str()
+        .ok_or_else(|| ErrorKind::InvalidRpcRequest(format!("missing field: {}", field)).into())
+}
+
+fn get_account(request: &Value, field: &str) -> Result<PublicKey> {
+    Ok(Address(get_str(request, field)?.to_owned()).to_public_key()?)
+}
+
+fn get_amount(request: &Value, field: &str) -> Result<u128> {
+    get_str(request, field)?.parse::<u128>()
+        .map_err(|_| ErrorKind::InvalidRpcRequest(format!("invalid amount: {}", field)).into())
+}
+
+fn get_hash(request: &Value, field: &str) -> Result<BlockHash> {
+    Ok(BlockHash::from_hex(get_str(request, field)?)?)
+}
+
+fn get_peer(request: &Value, field: &str) -> Result<SocketAddrV6> {
+    get_str(request, field)?.parse::<SocketAddrV6>()
+        .map_err(|_| ErrorKind::InvalidRpcRequest(format!("invalid peer address: {}", field)).into())
+}
+
+/// An IP address, with IPv4 addresses mapped to IPv6 as they are in the peer list
+fn get_ip(request: &Value, field: &str) -> Result<Ipv6Addr> {
+    match get_str(request, field)?.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => Ok(ip.to_ipv6_mapped()),
+        Ok(IpAddr::V6(ip)) => Ok(ip),
+        Err(_) => bail!(ErrorKind::InvalidRpcRequest(format!("invalid IP address: {}", field))),
+    }
+}
+
+/// Send `request` to the RPC server at `addr` and wait for the response
+pub fn call(addr: SocketAddr, request: &Value) -> Result<Value> {
+    let mut runtime = Runtime::new()?;
+    let uri = format!("http://{}/", addr);
+    let request = Request::post(uri.as_str())
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(request.to_string()))
+        .unwrap();
+    let body = runtime.block_on(Client::new().request(request)
+        .and_then(|res| res.into_body().concat2()))?;
+    let response: Value = serde_json::from_slice(&body)?;
+    if let Some(e) = response["error"].as_str() {
+        bail!("RPC error: {}", e);
+    }
+    Ok(response)
+}
+
+/// Counts of each message kind, keyed by the labels used in the metrics
+fn message_counts(counts: &[u64; 9]) -> Value {
+    MESSAGE_KINDS.iter()
+        .map(|&(kind, label)| (label.to_owned(), json!(counts[kind as usize])))
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+fn traffic_json(stats: &PeerStats) -> Value {
+    json!({
+        "messages_received": message_counts(&stats.messages_received),
+        "messages_sent": message_counts(&stats.messages_sent),
+        "bytes_received": stats.bytes_received,
+        "bytes_sent": stats.bytes_sent,
+        "invalid_messages": stats.invalid_messages,
+    })
+}
+
+/// Everything known about a peer. Times are in seconds before now.
+fn peer_json(info: &PeerInfo, active: bool, now: Instant) -> Value {
+    let mut value = traffic_json(&info.stats);
+    value["active"] = json!(active);
+    value["first_seen"] = json!((now - info.first_seen).as_secs());
+    value["last_seen"] = json!((now - info.last_seen).as_secs());
+    if let Some(header) = info.stats.last_header {
+        value["protocol_version"] = json!((header.version_using as u8).to_string());
+        value["version_min"] = json!(header.version_min as u8);
+        value["version_max"] = json!(header.version_max as u8);
+        value["extensions"] = json!(header.extensions.bits());
+    }
+    value
+}
+
+/// The active peers with their protocol version, or with every detail if `peer_details` is set
+fn peers(request: &Value, state: &State) -> Result<Value> {
+    let details = request["peer_details"].as_bool().unwrap_or(false);
+    let now = Instant::now();
+    let peers = state.peers.read().unwrap().iter()
+        .map(|(addr, info)| {
+            let value = if details {
+                peer_json(info, true, now)
+            } else {
+                let version = info.stats.last_header.map(|header| (header.version_using as u8).to_string());
+                json!(version.unwrap_or_default())
+            };
+            (addr.to_string(), value)
+        })
+        .collect::<serde_json::Map<_, _>>();
+    Ok(json!({ "peers": peers }))
+}
+
+/// The traffic and misbehavior score of a single active or inactive peer if `peer` is given,
+/// otherwise the total traffic of the active peers
+fn stats(request: &Value, state: &State) -> Result<Value> {
+    if !request["peer"].is_null() {
+        let peer = get_peer(request, "peer")?;
+        let mut value = match state.peer_info(peer) {
+            Some((info, active)) => peer_json(&info, active, Instant::now()),
+            None => bail!(ErrorKind::InvalidRpcRequest(format!("unknown peer: {}", peer))),
+        };
+        value["score"] = json!(state.reputation.score(*peer.ip()));
+        return Ok(value);
+    }
+    let mut total = PeerStats::default();
+    let peers = state.peers.read().unwrap();
+    for info in peers.values() {
+        for kind in 0..total.messages_received.len() {
+            total.messages_received[kind] += info.stats.messages_received[kind];
+            total.messages_sent[kind] += info.stats.messages_sent[kind];
+        }
+        total.bytes_received += info.stats.bytes_received;
+        total.bytes_sent += info.stats.bytes_sent;
+        total.invalid_messages += info.stats.invalid_messages;
+    }
+    let mut value = traffic_json(&total);
+    value["peers"] = json!(peers.len());
+    value["inactive_peers"] = json!(state.inactive_peers.read().unwrap().len());
+    Ok(value)
+}
+
+/// Ban an IP for `duration` seconds, or for the configured ban time
+fn peer_ban(request: &Value, state: &State) -> Result<Value> {
+    let ip = get_ip(request, "address")?;
+    let duration = match request["duration"].as_str() {
+        Some(duration) => match duration.parse::<u64>() {
+            Ok(duration) if duration <= MAX_BAN_SECS => duration,
+            _ => bail!(ErrorKind::InvalidRpcRequest("invalid duration".into())),
+        },
+        None => state.reputation.ban_duration().as_secs(),
+    };
+    state.ban_ip(ip, Duration::from_secs(duration));
+    Ok(json!({ "banned": "1" }))
+}
+
+fn peer_unban(request: &Value, state: &State) -> Result<Value> {
+    let unbanned = state.reputation.unban(get_ip(request, "address")?);
+    Ok(json!({ "unbanned": if unbanned { "1" } else { "0" } }))
+}
+
+/// Every banned IP with the seconds left on its ban
+fn bans(state: &State) -> Result<Value> {
+    let bans = state.reputation.bans().into_iter()
+        .map(|(ip, left)| (ip.to_string(), json!(left.as_secs().to_string())))
+        .collect::<serde_json::Map<_, _>>();
+    Ok(json!({ "bans": bans }))
+}
+
+fn wallet_create(request: &Value, state: &State) -> Result<Value> {
+    let password = request["password"].as_str().unwrap_or("");
+    let id = state.wallets.write().unwrap().create(password)?;
+    Ok(json!({ "wallet": id }))
+}
+
+fn password_enter(request: &Value, state: &State) -> Result<Value> {
+    let id = get_str(request, "wallet")?;
+    let password = get_str(request, "password")?;
+    let mut wallets = state.wallets.write().unwrap();
+    let valid = match wallets.get_mut(id)?.unlock(password) {
+        Ok(()) => true,
+        Err(Error(ErrorKind::InvalidWalletPassword, _)) => false,
+        Err(e) => return Err(e),
+    };
+    drop(wallets);
+    if valid {
+        receiver::search_pending(state, id);
+    }
+    Ok(json!({ "valid": if valid { "1" } else { "0" } }))
+}
+
+fn account_create(request: &Value, state: &State) -> Result<Value> {
+    let id = get_str(request, "wallet")?;
+    let mut wallets = state.wallets.write().unwrap();
+    let account: Account = wallets.get_mut(id)?.create_account()?;
+    wallets.save(id)?;
+    drop(wallets);
+    work::cache::queue_wallet_accounts(state);
+    Ok(json!({ "account": account.address.0 }))
+}
+
+fn send(request: &Value, state: &Arc<State>) -> Result<Value> {
+    let hash = actions::send(
+        state,
+        get_str(request, "wallet")?,
+        &get_account(request, "source")?,
+        &get_account(request, "destination")?,
+        get_amount(request, "amount")?,
+    )?;
+    Ok(json!({ "block": String::from(hash) }))
+}
+
+fn receive(request: &Value, state: &Arc<State>) -> Result<Value> {
+    let hash = actions::receive(
+        state,
+        get_str(request, "wallet")?,
+        &get_account(request, "account")?,
+        &get_hash(request, "block")?,
+    )?;
+    Ok(json!({ "block": String::from(hash) }))
+}
+
+fn account_representative_set(request: &Value, state: &Arc<State>) -> Result<Value> {
+    let hash = actions::change_representative(
+        state,
+        get_str(request, "wallet")?,
+        &get_account(request, "account")?,
+        &get_account(request, "representative")?,
+    )?;
+    Ok(json!({ "block": String::from(hash) }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nano_lib_rs::message::{MessageBuilder, MessageKind};
+    use std::time::Duration;
+
+    #[test]
+    fn refuses_actions_when_workers_are_busy() {
+        let workers = Workers::new(1, 1);
+        let (release, released) = mpsc::channel::<()>();
+        let first = workers.run(move || {
+            let _ = released.recv();
+            Ok(json!(1))
+        });
+        // Wait for the worker to take the first action, leaving room for one more in the queue
+        while workers.jobs.lock().unwrap().try_send(Box::new(|| {})).is_err() {
+            thread::yield_now();
+        }
+        match workers.run(|| Ok(json!(3))).wait() {
+            Err(Error(ErrorKind::RpcBusy, _)) => {},
+            other => panic!("expected RpcBusy, got {:?}", other),
+        }
+        release.send(()).unwrap();
+        assert_eq!(first.wait().unwrap(), json!(1));
+    }
+
+    #[test]
+    fn formats_peer_details() {
+        let mut info = PeerInfo::default();
+        let header = MessageBuilder::new(MessageKind::KeepAlive).build().header;
+        info.stats.record_received(&header, 144);
+        info.stats.record_received(&MessageBuilder::new(MessageKind::Invalid).build().header, 12);
+        info.stats.messages_sent[MessageKind::Publish as usize] = 2;
+
+        let value = peer_json(&info, false, info.first_seen + Duration::from_secs(30));
+        assert_eq!(value["active"], json!(false));
+        assert_eq!(value["first_seen"], json!(30));
+        assert_eq!(value["messages_received"]["keepalive"], json!(1));
+        assert_eq!(value["messages_received"]["invalid"], json!(1));
+        assert_eq!(value["messages_sent"]["publish"], json!(2));
+        assert_eq!(value["bytes_received"], json!(156));
+        assert_eq!(value["invalid_messages"], json!(1));
+        assert_eq!(value["protocol_version"], json!("7"));
+        assert_eq!(value["version_min"], json!(1));
+        assert_eq!(value["extensions"], json!(0));
+    }
+}
@@ -0,0 +1,233 @@
+//! High level wallet operations. Each one reads the account frontier from the ledger,
+//! builds a state block, signs it, attaches work and publishes it. Actions on the same account
+//! run one at a time, so each builds on the block published by the one before it instead of
+//! forking the account.
+use nano_lib_rs::block::{Block, BlockHash, BlockKind, BlockPayload, Link};
+use nano_lib_rs::keys::{PrivateKey, PublicKey, Address};
+
+use nanopow_rs;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use ledger::{AccountInfo, ProcessResult};
+use node::{self, State};
+use error::*;
+
+/// A lock for each account actions have been run on
+#[derive(Debug, Default)]
+pub struct AccountLocks {
+    locks: Mutex<HashMap<[u8; 32], Arc<Mutex<()>>>>,
+}
+
+impl AccountLocks {
+    /// The lock held while an action runs on `account`
+    fn get(&self, account: &PublicKey) -> Arc<Mutex<()>> {
+        self.locks.lock().unwrap()
+            .entry(*account.as_bytes())
+            .or_insert_with(Default::default)
+            .clone()
+    }
+}
+
+/// Look up the private key and current ledger state of a wallet account
+fn account_context(state: &State, wallet: &str, account: &PublicKey) -> Result<(PrivateKey, Option<AccountInfo>)> {
+    let key = state.wallets.read().unwrap().get(wallet)?.private_key(account)?;
+    let info = state.ledger.read().unwrap().account_info(account);
+    Ok((key, info))
+}
+
+/// Send `amount` raw from `source` to `destination`
+pub fn send(state: &Arc<State>, wallet: &str, source: &PublicKey, destination: &PublicKey, amount: u128) -> Result<BlockHash> {
+    let lock = state.account_locks.get(source);
+    let _locked = lock.lock().unwrap();
+    let (key, info) = account_context(state, wallet, source)?;
+    let info = match info {
+        Some(info) => info,
+        None => bail!(ErrorKind::AccountNotOpened(Address::from(*source).0)),
+    };
+    if info.balance < amount {
+        bail!(ErrorKind::InsufficientBalance(info.balance, amount));
+    }
+    let payload = BlockPayload::State {
+        account: *source,
+        previous: info.head,
+        representative: info.representative,
+        balance: info.balance - amount,
+        link: Link::Destination(*destination),
+    };
+    sign_and_publish(state, &key, payload)
+}
+
+/// Receive the pending send `source` into `account`, opening the account if needed
+pub fn receive(state: &Arc<State>, wallet: &str, account: &PublicKey, source: &BlockHash) -> Result<BlockHash> {
+    let lock = state.account_locks.get(account);
+    let _locked = lock.lock().unwrap();
+    let (key, info) = account_context(state, wallet, account)?;
+    let pending = match state.ledger.read().unwrap().pending_info(account, source) {
+        Some(pending) => pending,
+        None => bail!(ErrorKind::NotPending(String::from(*source))),
+    };
+    let (previous, representative, balance) = match info {
+        Some(info) => (info.head, info.representative, info.balance),
+        None => {
+            let representative = state.wallets.read().unwrap()
+                .get(wallet)?
+                .representative()
+                .unwrap_or(*account);
+            (BlockHash::zero(), representative, 0)
+        },
+    };
+    let payload = BlockPayload::State {
+        account: *account,
+        previous,
+        representative,
+        balance: balance + pending.amount,
+        link: Link::Source(*source),
+    };
+    sign_and_publish(state, &key, payload)
+}
+
+/// Change the representative of `account`
+pub fn change_representative(state: &Arc<State>, wallet: &str, account: &PublicKey, representative: &PublicKey) -> Result<BlockHash> {
+    let lock = state.account_locks.get(account);
+    let _locked = lock.lock().unwrap();
+    let (key, info) = account_context(state, wallet, account)?;
+    let info = match info {
+        Some(info) => info,
+        None => bail!(ErrorKind::AccountNotOpened(Address::from(*account).0)),
+    };
+    let payload = BlockPayload::State {
+        account: *account,
+        previous: info.head,
+        representative: *representative,
+        balance: info.balance,
+        link: Link::Unknown([0u8; 32]),
+    };
+    sign_and_publish(state, &key, payload)
+}
+
+fn sign_and_publish(state: &Arc<State>, key: &PrivateKey, payload: BlockPayload) -> Result<BlockHash> {
+    let root = payload.work_source();
+    let mut block = Block::new(BlockKind::State, Some(payload), None, None);
+    block.sign(key)?;
+    let work = match state.work_cache.get(&root) {
+        Some(work) if nanopow_rs::check_work_with_threshold(&root, &work, nanopow_rs::DEFAULT_THRESHOLD) => work,
+        cached => {
+            if cached.is_some() {
+                // A stale or corrupt entry would otherwise fail every block on this root
+                warn!("Cached work for {} is invalid, generating", root);
+                if let Err(e) = state.work_cache.remove(&root) {
+                    warn!("Failed to save work cache: {}", e);
+                }
+            } else {
+                debug!("No cached work for {}, generating", root);
+            }
+            state.work.generate(&root, nanopow_rs::DEFAULT_THRESHOLD)?
+        },
+    };
+    block.work = Some(work);
+    let hash = block.hash(false)?;
+    match node::publish_block(state, block) {
+        ProcessResult::Progress => {},
+        result => bail!(ErrorKind::BlockRejected(format!("{:?}", result))),
+    }
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indexmap::IndexMap;
+    use nano_lib_rs::block::InputHash;
+    use nano_lib_rs::keys::Seed;
+    use nano_lib_rs::message::NetworkKind;
+    use nanopow_rs::Work;
+    use std::env;
+    use std::fs;
+    use std::time::Duration;
+
+    use ledger::Ledger;
+    use node::limits::{PeerLimits, RateLimiter, RateLimits};
+    use node::outbound::OutboundQueue;
+    use node::pipeline::ValidationQueue;
+    use node::reputation::Reputation;
+    use wallet::{Wallet, WalletStore};
+    use work::{WorkCache, WorkPool};
+
+    const GENESIS_BLOCK: &str = "47F694A96653EB497709490776E492EFBB88EBC5C4E95CC0B2C9DCAB1930C36B";
+
+    /// A node whose ledger gives the first account of a wallet 1000 raw, with work cached for the
+    /// next block of both of its accounts
+    fn state_with_wallet(path: &::std::path::Path) -> (Arc<State>, String, PublicKey, PublicKey) {
+        let seed = Seed::from_string("1234567890123456789012345678901234567890123456789012345678901234").unwrap();
+        let mut wallet = Wallet::from_seed(seed, "password").unwrap();
+        let first = wallet.create_account().unwrap().public_key;
+        let second = wallet.create_account().unwrap().public_key;
+        let mut wallets = WalletStore::open(path.join("wallets")).unwrap();
+        let id = wallets.insert(wallet).unwrap();
+
+        let genesis = BlockHash::from_hex(GENESIS_BLOCK).unwrap();
+        let ledger = Ledger::with_genesis(first, genesis, 1000);
+        let state = Arc::new(State::new(
+            NetworkKind::Live,
+            IndexMap::new(),
+            ledger,
+            wallets,
+            u128::max_value(),
+            WorkPool::new(Vec::new(), 1).unwrap(),
+            WorkCache::open(path.join("work_cache.json")).unwrap(),
+            OutboundQueue::new(16, 0),
+            ValidationQueue::new(16),
+            Reputation::new(100, Duration::from_secs(3600), Default::default()),
+            RateLimiter::new(RateLimits { messages: 100, keepalives: 100, publishes: 100, confirm_reqs: 100 }),
+            PeerLimits { per_ip: 10, per_subnet: 10 },
+        ));
+        state.work_cache.insert(&first, genesis.into(), Work::from_hex("49bf97fbffd55294").unwrap()).unwrap();
+        let open_root = InputHash::new(second.to_bytes());
+        state.work_cache.insert(&second, open_root, Work::from_hex("000000000343f92a").unwrap()).unwrap();
+        (state, id, first, second)
+    }
+
+    #[test]
+    fn sends_and_receives_between_accounts() {
+        let path = env::temp_dir().join(format!("nano-rs-actions-{}", ::rand::random::<u64>()));
+        let (state, id, first, second) = state_with_wallet(&path);
+
+        match send(&state, &id, &first, &second, 1001) {
+            Err(Error(ErrorKind::InsufficientBalance(1000, 1001), _)) => {},
+            other => panic!("expected InsufficientBalance, got {:?}", other),
+        }
+        match send(&state, &id, &second, &first, 1) {
+            Err(Error(ErrorKind::AccountNotOpened(_), _)) => {},
+            other => panic!("expected AccountNotOpened, got {:?}", other),
+        }
+
+        let sent = send(&state, &id, &first, &second, 100).unwrap();
+        let received = receive(&state, &id, &second, &sent).unwrap();
+        match receive(&state, &id, &second, &sent) {
+            Err(Error(ErrorKind::NotPending(_), _)) => {},
+            other => panic!("expected NotPending, got {:?}", other),
+        }
+
+        let ledger = state.ledger.read().unwrap();
+        assert_eq!(ledger.account_info(&first).unwrap().balance, 900);
+        let info = ledger.account_info(&second).unwrap();
+        assert_eq!((info.head, info.balance, info.representative), (received, 100, second));
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn shares_locks_per_account() {
+        let seed = Seed::from_string("1234567890123456789012345678901234567890123456789012345678901234").unwrap();
+        let first = PrivateKey::from_seed(seed.clone(), 0).public_key();
+        let second = PrivateKey::from_seed(seed, 1).public_key();
+        let locks = AccountLocks::default();
+        assert!(Arc::ptr_eq(&locks.get(&first), &locks.get(&first)));
+        assert!(!Arc::ptr_eq(&locks.get(&first), &locks.get(&second)));
+        let lock = locks.get(&first);
+        let _locked = lock.lock().unwrap();
+        assert!(locks.get(&first).try_lock().is_err());
+        assert!(locks.get(&second).try_lock().is_ok());
+    }
+}
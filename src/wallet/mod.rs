@@ -0,0 +1,377 @@
+//! Password encrypted wallets holding a deterministic seed and ad-hoc private keys.
+//!
+//! Each wallet has a random master key which encrypts the seed and every ad-hoc key.
+//! The master key itself is encrypted with a key derived from the wallet password using
+//! Argon2, so changing the password only requires re-encrypting the master key.
+pub mod actions;
+pub mod receiver;
+pub mod store;
+
+pub use self::store::{WalletStore, WalletId};
+
+use nano_lib_rs::keys::{Account, Address, PrivateKey, PublicKey, Seed};
+
+use argon2;
+use chacha20_poly1305_aead::{encrypt, decrypt};
+use data_encoding::HEXUPPER;
+use rand::{OsRng, Rng};
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use error::*;
+
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+#[cfg(not(test))]
+const KDF_MEM_COST: u32 = 64 * 1024;
+#[cfg(test)]
+const KDF_MEM_COST: u32 = 64;
+const KDF_TIME_COST: u32 = 2;
+
+const MASTER_KEY_AAD: &[u8] = b"nano-rs wallet master key";
+const SEED_AAD: &[u8] = b"nano-rs wallet seed";
+
+fn random_bytes(buf: &mut [u8]) -> Result<()> {
+    let mut rng = OsRng::new()?;
+    rng.fill_bytes(buf);
+    Ok(())
+}
+
+fn decode_hex(s: &str) -> Result<Vec<u8>> {
+    HEXUPPER.decode(s.as_bytes())
+        .map_err(|_| ErrorKind::WalletCorrupted("invalid hex".into()).into())
+}
+
+/// Parameters used to derive the password key, stored so they can be raised later
+/// without breaking existing wallets
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+struct KdfParams {
+    mem_cost: u32,
+    time_cost: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams {
+            mem_cost: KDF_MEM_COST,
+            time_cost: KDF_TIME_COST,
+        }
+    }
+}
+
+impl KdfParams {
+    fn derive_key(&self, password: &str, salt: &[u8]) -> Result<[u8; KEY_LEN]> {
+        let config = argon2::Config {
+            variant: argon2::Variant::Argon2id,
+            mem_cost: self.mem_cost,
+            time_cost: self.time_cost,
+            hash_length: KEY_LEN as u32,
+            ..argon2::Config::default()
+        };
+        let hash = argon2::hash_raw(password.as_bytes(), salt, &config)?;
+        let mut key = [0u8; KEY_LEN];
+        key.copy_from_slice(&hash);
+        Ok(key)
+    }
+}
+
+/// Data encrypted with ChaCha20-Poly1305, stored as hex
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Ciphertext {
+    nonce: String,
+    data: String,
+    tag: String,
+}
+
+impl Ciphertext {
+    fn seal(key: &[u8; KEY_LEN], aad: &[u8], plaintext: &[u8]) -> Result<Self> {
+        let mut nonce = [0u8; NONCE_LEN];
+        random_bytes(&mut nonce)?;
+        let mut data = Vec::with_capacity(plaintext.len());
+        let tag = encrypt(key, &nonce, aad, plaintext, &mut data)?;
+        Ok(Ciphertext {
+            nonce: HEXUPPER.encode(&nonce),
+            data: HEXUPPER.encode(&data),
+            tag: HEXUPPER.encode(&tag),
+        })
+    }
+
+    /// Returns `None` if authentication fails, i.e. the key is wrong or the data was tampered with
+    fn open(&self, key: &[u8; KEY_LEN], aad: &[u8]) -> Result<Option<Vec<u8>>> {
+        let nonce = decode_hex(&self.nonce)?;
+        let data = decode_hex(&self.data)?;
+        let tag = decode_hex(&self.tag)?;
+        let mut plaintext = Vec::with_capacity(data.len());
+        match decrypt(key, &nonce, aad, &data, &tag, &mut plaintext) {
+            Ok(()) => Ok(Some(plaintext)),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+/// A single wallet. The decrypted master key is only held in memory while unlocked.
+#[derive(Serialize, Deserialize)]
+pub struct Wallet {
+    kdf: KdfParams,
+    salt: String,
+    master_key: Ciphertext,
+    seed: Option<Ciphertext>,
+    /// Addresses of accounts derived from the seed, in index order
+    deterministic_accounts: Vec<String>,
+    /// Ad-hoc private keys keyed by address
+    adhoc_keys: BTreeMap<String, Ciphertext>,
+    /// Representative used when opening new accounts
+    #[serde(default)]
+    representative: Option<String>,
+    #[serde(skip)]
+    unlocked: Option<[u8; KEY_LEN]>,
+}
+
+impl fmt::Debug for Wallet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Wallet")
+            .field("locked", &self.is_locked())
+            .field("deterministic_accounts", &self.deterministic_accounts)
+            .field("adhoc_accounts", &self.adhoc_keys.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Wallet {
+    /// Create a new wallet with a randomly generated seed. The wallet starts out unlocked.
+    pub fn new(password: &str) -> Result<Self> {
+        let mut seed = [0u8; 32];
+        random_bytes(&mut seed)?;
+        Wallet::from_seed(Seed(seed), password)
+    }
+
+    /// Create a new wallet from an existing seed. The wallet starts out unlocked.
+    pub fn from_seed(seed: Seed, password: &str) -> Result<Self> {
+        let mut master_key = [0u8; KEY_LEN];
+        random_bytes(&mut master_key)?;
+        let mut wallet = Wallet::with_master_key(master_key, password)?;
+        wallet.import_seed(seed)?;
+        Ok(wallet)
+    }
+
+    fn with_master_key(master_key: [u8; KEY_LEN], password: &str) -> Result<Self> {
+        let kdf = KdfParams::default();
+        let mut salt = [0u8; SALT_LEN];
+        random_bytes(&mut salt)?;
+        let password_key = kdf.derive_key(password, &salt)?;
+        Ok(Wallet {
+            kdf,
+            salt: HEXUPPER.encode(&salt),
+            master_key: Ciphertext::seal(&password_key, MASTER_KEY_AAD, &master_key)?,
+            seed: None,
+            deterministic_accounts: Vec::new(),
+            adhoc_keys: BTreeMap::new(),
+            representative: None,
+            unlocked: Some(master_key),
+        })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.unlocked.is_none()
+    }
+
+    /// Forget the decrypted master key
+    pub fn lock(&mut self) {
+        self.unlocked = None;
+    }
+
+    /// Decrypt the master key with the given password
+    pub fn unlock(&mut self, password: &str) -> Result<()> {
+        let salt = decode_hex(&self.salt)?;
+        let password_key = self.kdf.derive_key(password, &salt)?;
+        let master_key = match self.master_key.open(&password_key, MASTER_KEY_AAD)? {
+            Some(key) => key,
+            None => bail!(ErrorKind::InvalidWalletPassword),
+        };
+        if master_key.len() != KEY_LEN {
+            bail!(ErrorKind::WalletCorrupted("invalid master key length".into()));
+        }
+        let mut key = [0u8; KEY_LEN];
+        key.copy_from_slice(&master_key);
+        self.unlocked = Some(key);
+        Ok(())
+    }
+
+    /// Re-encrypt the master key under a new password. The wallet must be unlocked.
+    pub fn change_password(&mut self, password: &str) -> Result<()> {
+        let master_key = self.master_key()?;
+        let kdf = KdfParams::default();
+        let mut salt = [0u8; SALT_LEN];
+        random_bytes(&mut salt)?;
+        let password_key = kdf.derive_key(password, &salt)?;
+        self.master_key = Ciphertext::seal(&password_key, MASTER_KEY_AAD, &master_key)?;
+        self.salt = HEXUPPER.encode(&salt);
+        self.kdf = kdf;
+        Ok(())
+    }
+
+    fn master_key(&self) -> Result<[u8; KEY_LEN]> {
+        match self.unlocked {
+            Some(key) => Ok(key),
+            None => bail!(ErrorKind::WalletLocked),
+        }
+    }
+
+    /// Export the decrypted seed. The wallet must be unlocked.
+    pub fn seed(&self) -> Result<Seed> {
+        let master_key = self.master_key()?;
+        match self.seed {
+            Some(ref seed) => {
+                let bytes = seed.open(&master_key, SEED_AAD)?
+                    .ok_or_else(|| Error::from(ErrorKind::WalletCorrupted("seed failed authentication".into())))?;
+                Ok(Seed::from_bytes(bytes)?)
+            },
+            None => bail!(ErrorKind::WalletCorrupted("wallet has no seed".into())),
+        }
+    }
+
+    /// Replace the seed of this wallet. Previously derived accounts are forgotten
+    /// and derivation restarts at index 0. The wallet must be unlocked.
+    pub fn import_seed(&mut self, seed: Seed) -> Result<()> {
+        let master_key = self.master_key()?;
+        self.seed = Some(Ciphertext::seal(&master_key, SEED_AAD, &*seed)?);
+        self.deterministic_accounts.clear();
+        Ok(())
+    }
+
+    /// The index the next deterministic account will be derived at
+    pub fn deterministic_index(&self) -> u32 {
+        self.deterministic_accounts.len() as u32
+    }
+
+    /// Derive the next account from the seed. The wallet must be unlocked.
+    pub fn create_account(&mut self) -> Result<Account> {
+        let seed = self.seed()?;
+        let index = self.deterministic_index();
+        let account: Account = PrivateKey::from_seed(seed, index).into();
+        self.deterministic_accounts.push(account.address.0.clone());
+        Ok(account)
+    }
+
+    /// Add an ad-hoc private key to the wallet. The wallet must be unlocked.
+    pub fn insert_adhoc(&mut self, key: PrivateKey) -> Result<Account> {
+        let master_key = self.master_key()?;
+        let account: Account = key.public_key().into();
+        let sealed = Ciphertext::seal(&master_key, account.address.0.as_bytes(), key.as_bytes())?;
+        self.adhoc_keys.insert(account.address.0.clone(), sealed);
+        Ok(account)
+    }
+
+    /// Remove an account from the wallet, returning whether it was present
+    pub fn remove_account(&mut self, account: &PublicKey) -> bool {
+        let address = Address::from(*account).0;
+        if let Some(pos) = self.deterministic_accounts.iter().position(|a| *a == address) {
+            // Keep the remaining indices stable by leaving a hole
+            self.deterministic_accounts[pos] = String::new();
+            return true;
+        }
+        self.adhoc_keys.remove(&address).is_some()
+    }
+
+    /// All accounts in this wallet, deterministic accounts first
+    pub fn accounts(&self) -> Vec<Account> {
+        self.deterministic_accounts.iter()
+            .chain(self.adhoc_keys.keys())
+            .filter(|a| !a.is_empty())
+            .filter_map(|a| Address(a.clone()).to_public_key().ok())
+            .map(Account::from)
+            .collect()
+    }
+
+    pub fn contains(&self, account: &PublicKey) -> bool {
+        let address = Address::from(*account).0;
+        self.deterministic_accounts.contains(&address) || self.adhoc_keys.contains_key(&address)
+    }
+
+    /// The representative for newly opened accounts, if one has been set
+    pub fn representative(&self) -> Option<PublicKey> {
+        self.representative.as_ref()
+            .and_then(|a| Address(a.clone()).to_public_key().ok())
+    }
+
+    pub fn set_representative(&mut self, representative: &PublicKey) {
+        self.representative = Some(Address::from(*representative).0);
+    }
+
+    /// Decrypt the private key of an account in this wallet. The wallet must be unlocked.
+    pub fn private_key(&self, account: &PublicKey) -> Result<PrivateKey> {
+        let master_key = self.master_key()?;
+        let address = Address::from(*account).0;
+        if let Some(index) = self.deterministic_accounts.iter().position(|a| *a == address) {
+            return Ok(PrivateKey::from_seed(self.seed()?, index as u32));
+        }
+        match self.adhoc_keys.get(&address) {
+            Some(sealed) => {
+                let bytes = sealed.open(&master_key, address.as_bytes())?
+                    .ok_or_else(|| Error::from(ErrorKind::WalletCorrupted("key failed authentication".into())))?;
+                Ok(PrivateKey::from_bytes(bytes)?)
+            },
+            None => bail!(ErrorKind::UnknownWalletAccount(address)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEED: &str = "1234567890123456789012345678901234567890123456789012345678901234";
+
+    #[test]
+    fn derives_deterministic_accounts() {
+        let seed = Seed::from_string(SEED).unwrap();
+        let mut wallet = Wallet::from_seed(seed, "password").unwrap();
+        let first = wallet.create_account().unwrap();
+        let second = wallet.create_account().unwrap();
+        assert_eq!(first.address.0, "xrb_3iwi45me3cgo9aza9wx5f7rder37hw11xtc1ek8psqxw5oxb8cujjad6qp9y");
+        assert_eq!(second.address.0, "xrb_3a9d1h6wt3zp8cqd6dhhgoyizmk1ciemqkrw97ysrphn7anm6xko1wxakaa1");
+        assert_eq!(wallet.deterministic_index(), 2);
+        assert!(wallet.contains(&first.public_key));
+    }
+
+    #[test]
+    fn lock_and_unlock() {
+        let mut wallet = Wallet::new("password").unwrap();
+        let account = wallet.create_account().unwrap();
+        wallet.lock();
+        assert!(wallet.is_locked());
+        assert!(wallet.private_key(&account.public_key).is_err());
+        assert!(wallet.unlock("wrong").is_err());
+        wallet.unlock("password").unwrap();
+        let key = wallet.private_key(&account.public_key).unwrap();
+        assert_eq!(key.public_key(), account.public_key);
+    }
+
+    #[test]
+    fn change_password_keeps_keys() {
+        let mut wallet = Wallet::new("old").unwrap();
+        let adhoc = PrivateKey::from_seed(Seed::from_string(SEED).unwrap(), 7);
+        let account = wallet.insert_adhoc(adhoc.clone()).unwrap();
+        let seed = wallet.seed().unwrap();
+        wallet.change_password("new").unwrap();
+        wallet.lock();
+        assert!(wallet.unlock("old").is_err());
+        wallet.unlock("new").unwrap();
+        assert_eq!(wallet.seed().unwrap().0, seed.0);
+        assert_eq!(wallet.private_key(&account.public_key).unwrap().to_hex(), adhoc.to_hex());
+    }
+
+    #[test]
+    fn survives_serialization() {
+        let mut wallet = Wallet::new("password").unwrap();
+        let account = wallet.create_account().unwrap();
+        let json = ::serde_json::to_string(&wallet).unwrap();
+        let mut loaded: Wallet = ::serde_json::from_str(&json).unwrap();
+        assert!(loaded.is_locked());
+        loaded.unlock("password").unwrap();
+        assert_eq!(loaded.private_key(&account.public_key).unwrap().public_key(), account.public_key);
+    }
+}
@@ -0,0 +1,227 @@
+//! Automatic receiving of pending sends to unlocked wallet accounts.
+//!
+//! Sends are queued as soon as they are added to the ledger, and the largest amounts are
+//! received first. There is no voting yet, so a send counts as confirmed once the ledger
+//! has accepted it. Wallets are loaded locked, so sends which were already pending are
+//! searched for when a wallet is unlocked.
+use nano_lib_rs::block::{Block, BlockHash, BlockPayload};
+use nano_lib_rs::keys::PublicKey;
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashSet};
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use node::State;
+use wallet::{actions, WalletId};
+use error::*;
+
+/// By default sends smaller than 0.000001 Nano are not received automatically
+pub const DEFAULT_RECEIVE_MINIMUM: u128 = 1_000_000_000_000_000_000_000_000;
+
+/// A pending send to a wallet account
+#[derive(Clone)]
+pub struct Receivable {
+    pub wallet: WalletId,
+    pub account: PublicKey,
+    pub hash: BlockHash,
+    pub amount: u128,
+}
+
+impl fmt::Debug for Receivable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Receivable({}, {} raw)", String::from(self.hash), self.amount)
+    }
+}
+
+impl PartialEq for Receivable {
+    fn eq(&self, other: &Self) -> bool {
+        self.hash == other.hash
+    }
+}
+
+impl Eq for Receivable {}
+
+impl PartialOrd for Receivable {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Receivable {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.amount.cmp(&other.amount)
+            .then_with(|| self.hash.cmp(&other.hash))
+    }
+}
+
+#[derive(Debug, Default)]
+struct QueueInner {
+    heap: BinaryHeap<Receivable>,
+    queued: HashSet<BlockHash>,
+}
+
+/// A queue of pending sends ordered by amount, largest first
+#[derive(Debug)]
+pub struct ReceiveQueue {
+    minimum: u128,
+    inner: Mutex<QueueInner>,
+    available: Condvar,
+}
+
+impl ReceiveQueue {
+    /// Create a queue which ignores sends smaller than `minimum` raw
+    pub fn new(minimum: u128) -> Self {
+        ReceiveQueue {
+            minimum,
+            inner: Mutex::new(QueueInner::default()),
+            available: Condvar::new(),
+        }
+    }
+
+    /// Queue a receivable, returning false if it is below the minimum or already queued
+    pub fn push(&self, receivable: Receivable) -> bool {
+        if receivable.amount < self.minimum {
+            return false;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.queued.insert(receivable.hash) {
+            return false;
+        }
+        inner.heap.push(receivable);
+        self.available.notify_one();
+        true
+    }
+
+    /// Take the largest receivable, if any
+    pub fn try_pop(&self) -> Option<Receivable> {
+        let mut inner = self.inner.lock().unwrap();
+        let receivable = inner.heap.pop();
+        if let Some(ref r) = receivable {
+            inner.queued.remove(&r.hash);
+        }
+        receivable
+    }
+
+    /// Take the largest receivable, waiting until one is available
+    pub fn pop(&self) -> Receivable {
+        let mut inner = self.inner.lock().unwrap();
+        loop {
+            if let Some(receivable) = inner.heap.pop() {
+                inner.queued.remove(&receivable.hash);
+                return receivable;
+            }
+            inner = self.available.wait(inner).unwrap();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().heap.len()
+    }
+}
+
+/// Queue a newly processed block for receiving if it is a send to an unlocked wallet account
+pub fn block_processed(state: &State, block: &Block, hash: BlockHash) {
+    let destination = match block.payload {
+        Some(BlockPayload::State { ref link, .. }) => match PublicKey::from_bytes(link.as_bytes()) {
+            Ok(destination) => destination,
+            Err(_) => return,
+        },
+        _ => return,
+    };
+    let pending = match state.ledger.read().unwrap().pending_info(&destination, &hash) {
+        Some(pending) => pending,
+        None => return,
+    };
+    let wallets = state.wallets.read().unwrap();
+    let wallet = match wallets.find_account(&destination) {
+        Some(id) => id.clone(),
+        None => return,
+    };
+    if wallets.get(&wallet).map(|w| w.is_locked()).unwrap_or(true) {
+        debug!("Wallet {} is locked, not receiving {}", wallet, String::from(hash));
+        return;
+    }
+    state.receive_queue.push(Receivable {
+        wallet,
+        account: destination,
+        hash,
+        amount: pending.amount,
+    });
+}
+
+/// Queue every pending send in the ledger for the accounts of `wallet`, if it is unlocked.
+/// Returns the number of sends queued.
+pub fn search_pending(state: &State, wallet: &str) -> usize {
+    let wallets = state.wallets.read().unwrap();
+    let ledger = state.ledger.read().unwrap();
+    let w = match wallets.get(wallet) {
+        Ok(w) if !w.is_locked() => w,
+        _ => return 0,
+    };
+    let mut queued = 0;
+    for account in w.accounts() {
+        for (hash, pending) in ledger.pending_for(&account.public_key) {
+            let receivable = Receivable {
+                wallet: wallet.to_owned(),
+                account: account.public_key,
+                hash,
+                amount: pending.amount,
+            };
+            if state.receive_queue.push(receivable) {
+                queued += 1;
+            }
+        }
+    }
+    if queued > 0 {
+        info!("Queued {} pending blocks for receiving", queued);
+    }
+    queued
+}
+
+/// Start a thread which receives queued sends one at a time
+pub fn spawn_worker(state: Arc<State>) -> thread::JoinHandle<()> {
+    thread::spawn(move || loop {
+        let receivable = state.receive_queue.pop();
+        if state.shutdown.is_triggered() {
+            return;
+        }
+        debug!("Receiving {:?}", receivable);
+        match actions::receive(&state, &receivable.wallet, &receivable.account, &receivable.hash) {
+            Ok(hash) => info!("Received {:?} with block {}", receivable, String::from(hash)),
+            Err(Error(ErrorKind::WalletLocked, _)) => debug!("Wallet locked, skipping {:?}", receivable),
+            Err(Error(ErrorKind::NotPending(_), _)) => debug!("{:?} already received", receivable),
+            Err(e) => warn!("Failed to receive {:?}: {}", receivable, e),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nano_lib_rs::keys::{PrivateKey, Seed};
+
+    fn receivable(amount: u128, hash_byte: u8) -> Receivable {
+        let seed = Seed::from_string("1234567890123456789012345678901234567890123456789012345678901234").unwrap();
+        Receivable {
+            wallet: "wallet".into(),
+            account: PrivateKey::from_seed(seed, 0).public_key(),
+            hash: BlockHash::from_bytes([hash_byte; 32]).unwrap(),
+            amount,
+        }
+    }
+
+    #[test]
+    fn pops_largest_amount_first() {
+        let queue = ReceiveQueue::new(10);
+        assert!(queue.push(receivable(50, 1)));
+        assert!(queue.push(receivable(500, 2)));
+        assert!(!queue.push(receivable(5, 3)));
+        assert!(queue.push(receivable(100, 4)));
+        assert!(!queue.push(receivable(100, 4)));
+        let amounts: Vec<u128> = (0..3).map(|_| queue.pop().amount).collect();
+        assert_eq!(amounts, vec![500, 100, 50]);
+        assert!(queue.try_pop().is_none());
+    }
+}
@@ -0,0 +1,141 @@
+use super::Wallet;
+
+use nano_lib_rs::keys::PublicKey;
+
+use data_encoding::HEXUPPER;
+use rand::{OsRng, Rng};
+use serde_json;
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use error::*;
+
+/// Wallets are identified by a random 32 byte id, encoded as hex
+pub type WalletId = String;
+
+const WALLET_EXTENSION: &str = "json";
+
+/// A directory of wallets, one JSON file per wallet
+#[derive(Debug)]
+pub struct WalletStore {
+    path: PathBuf,
+    wallets: HashMap<WalletId, Wallet>,
+}
+
+impl WalletStore {
+    /// Open the store at `path`, creating the directory if needed and loading every wallet in it.
+    /// All loaded wallets start out locked.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        fs::create_dir_all(&path)?;
+        let mut wallets = HashMap::new();
+        for entry in fs::read_dir(&path)? {
+            let file_path = entry?.path();
+            if file_path.extension().and_then(|e| e.to_str()) != Some(WALLET_EXTENSION) {
+                continue;
+            }
+            let id = match file_path.file_stem().and_then(|s| s.to_str()) {
+                Some(id) => id.to_owned(),
+                None => continue,
+            };
+            let wallet: Wallet = serde_json::from_reader(BufReader::new(File::open(&file_path)?))?;
+            wallets.insert(id, wallet);
+        }
+        info!("Loaded {} wallets from {}", wallets.len(), path.display());
+        Ok(WalletStore { path, wallets })
+    }
+
+    /// Create and persist a new wallet with a random seed
+    pub fn create(&mut self, password: &str) -> Result<WalletId> {
+        let wallet = Wallet::new(password)?;
+        self.insert(wallet)
+    }
+
+    /// Add an existing wallet to the store and persist it
+    pub fn insert(&mut self, wallet: Wallet) -> Result<WalletId> {
+        let mut id_bytes = [0u8; 32];
+        OsRng::new()?.fill_bytes(&mut id_bytes);
+        let id = HEXUPPER.encode(&id_bytes);
+        self.wallets.insert(id.clone(), wallet);
+        self.save(&id)?;
+        Ok(id)
+    }
+
+    pub fn get(&self, id: &str) -> Result<&Wallet> {
+        self.wallets.get(id).ok_or_else(|| ErrorKind::UnknownWallet(id.to_owned()).into())
+    }
+
+    pub fn get_mut(&mut self, id: &str) -> Result<&mut Wallet> {
+        self.wallets.get_mut(id).ok_or_else(|| ErrorKind::UnknownWallet(id.to_owned()).into())
+    }
+
+    pub fn ids(&self) -> Vec<WalletId> {
+        self.wallets.keys().cloned().collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(&WalletId, &Wallet)> {
+        self.wallets.iter()
+    }
+
+    /// Find the wallet containing `account`
+    pub fn find_account(&self, account: &PublicKey) -> Option<&WalletId> {
+        self.wallets.iter()
+            .find(|&(_, wallet)| wallet.contains(account))
+            .map(|(id, _)| id)
+    }
+
+    /// Remove a wallet from the store and delete its file
+    pub fn remove(&mut self, id: &str) -> Result<()> {
+        if self.wallets.remove(id).is_none() {
+            bail!(ErrorKind::UnknownWallet(id.to_owned()));
+        }
+        fs::remove_file(self.wallet_path(id))?;
+        Ok(())
+    }
+
+    /// Write a wallet to disk. The file is replaced atomically so a crash cannot leave
+    /// a truncated wallet behind.
+    pub fn save(&self, id: &str) -> Result<()> {
+        let wallet = self.get(id)?;
+        let path = self.wallet_path(id);
+        let tmp_path = path.with_extension("tmp");
+        {
+            let writer = BufWriter::new(File::create(&tmp_path)?);
+            serde_json::to_writer_pretty(writer, wallet)?;
+        }
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    fn wallet_path(&self, id: &str) -> PathBuf {
+        self.path.join(format!("{}.{}", id, WALLET_EXTENSION))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn persists_wallets() {
+        let path = env::temp_dir().join(format!("nano-rs-wallets-{}", ::rand::random::<u64>()));
+        let (id, account) = {
+            let mut store = WalletStore::open(&path).unwrap();
+            let id = store.create("password").unwrap();
+            let account = store.get_mut(&id).unwrap().create_account().unwrap();
+            store.save(&id).unwrap();
+            (id, account)
+        };
+        let mut store = WalletStore::open(&path).unwrap();
+        assert_eq!(store.find_account(&account.public_key), Some(&id));
+        let wallet = store.get_mut(&id).unwrap();
+        assert!(wallet.is_locked());
+        wallet.unlock("password").unwrap();
+        assert_eq!(wallet.private_key(&account.public_key).unwrap().public_key(), account.public_key);
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
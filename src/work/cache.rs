@@ -0,0 +1,264 @@
+//! Work precomputed for the next block of each wallet account.
+//!
+//! Whenever a block on a wallet account is confirmed, work for the block after it is generated
+//! in the background and stored under the new frontier hash, so the next send or receive can be
+//! published straight away. There is no voting yet, so a block counts as confirmed once the
+//! ledger has accepted it. The cache is written to disk after every change.
+use nano_lib_rs::block::{Block, BlockHash, BlockPayload, InputHash, Work};
+use nano_lib_rs::keys::PublicKey;
+
+use nanopow_rs;
+
+use data_encoding::HEXUPPER;
+use serde_json;
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use ledger::Ledger;
+use node::State;
+use error::*;
+
+#[derive(Clone, Copy, Debug)]
+struct CachedWork {
+    account: [u8; 32],
+    work: Work,
+}
+
+/// The on-disk form of a cache entry, keyed by the hex encoded root
+#[derive(Serialize, Deserialize)]
+struct StoredWork {
+    account: String,
+    work: String,
+}
+
+/// Work keyed by the root it was computed for, along with the accounts waiting for work
+#[derive(Debug)]
+pub struct WorkCache {
+    path: PathBuf,
+    entries: Mutex<HashMap<[u8; 32], CachedWork>>,
+    queue: Mutex<VecDeque<(PublicKey, InputHash)>>,
+    available: Condvar,
+}
+
+fn decode_bytes(hex: &str) -> Option<[u8; 32]> {
+    let bytes = HEXUPPER.decode(hex.as_bytes()).ok()?;
+    if bytes.len() != 32 {
+        return None;
+    }
+    let mut buf = [0u8; 32];
+    buf.copy_from_slice(&bytes);
+    Some(buf)
+}
+
+impl WorkCache {
+    /// Load the cache saved at `path`, starting out empty if there is none
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut entries = HashMap::new();
+        if path.exists() {
+            let stored: HashMap<String, StoredWork> = serde_json::from_reader(BufReader::new(File::open(&path)?))?;
+            for (root, stored) in stored {
+                match (decode_bytes(&root), decode_bytes(&stored.account), Work::from_hex(&stored.work)) {
+                    (Some(root), Some(account), Ok(work)) => {
+                        entries.insert(root, CachedWork { account, work });
+                    },
+                    _ => warn!("Ignoring invalid cached work for {}", root),
+                }
+            }
+            info!("Loaded {} cached work values from {}", entries.len(), path.display());
+        }
+        Ok(WorkCache {
+            path,
+            entries: Mutex::new(entries),
+            queue: Mutex::new(VecDeque::new()),
+            available: Condvar::new(),
+        })
+    }
+
+    /// Cached work for `root`, if any
+    pub fn get(&self, root: &InputHash) -> Option<Work> {
+        self.entries.lock().unwrap().get(root.as_bytes()).map(|cached| cached.work)
+    }
+
+    /// Store work for the frontier `root` of `account`, replacing work for its older frontiers
+    pub fn insert(&self, account: &PublicKey, root: InputHash, work: Work) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        let account = *account.as_bytes();
+        entries.retain(|_, cached| cached.account != account);
+        entries.insert(*root.as_bytes(), CachedWork { account, work });
+        self.save(&entries)
+    }
+
+    /// Drop work cached for any root of `account` other than `frontier`,
+    /// returning the number of entries removed
+    pub fn invalidate(&self, account: &PublicKey, frontier: &InputHash) -> Result<usize> {
+        let mut entries = self.entries.lock().unwrap();
+        let account = *account.as_bytes();
+        let before = entries.len();
+        entries.retain(|root, cached| cached.account != account || root == frontier.as_bytes());
+        let removed = before - entries.len();
+        if removed > 0 {
+            self.save(&entries)?;
+        }
+        Ok(removed)
+    }
+
+    /// Drop the work cached for `root`, returning whether there was any
+    pub fn remove(&self, root: &InputHash) -> Result<bool> {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.remove(root.as_bytes()).is_none() {
+            return Ok(false);
+        }
+        self.save(&entries)?;
+        Ok(true)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    /// Queue work to be precomputed for the frontier `root` of `account`
+    pub fn queue(&self, account: PublicKey, root: InputHash) {
+        self.queue.lock().unwrap().push_back((account, root));
+        self.available.notify_one();
+    }
+
+    /// Take the next queued frontier, waiting until one is available
+    fn pop(&self) -> (PublicKey, InputHash) {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            if let Some(next) = queue.pop_front() {
+                return next;
+            }
+            queue = self.available.wait(queue).unwrap();
+        }
+    }
+
+    /// Write the cache to disk, atomically replacing the previous file
+    fn save(&self, entries: &HashMap<[u8; 32], CachedWork>) -> Result<()> {
+        let stored: HashMap<String, StoredWork> = entries.iter()
+            .map(|(root, cached)| (HEXUPPER.encode(root), StoredWork {
+                account: HEXUPPER.encode(&cached.account),
+                work: String::from(cached.work),
+            }))
+            .collect();
+        let tmp_path = self.path.with_extension("tmp");
+        {
+            let writer = BufWriter::new(File::create(&tmp_path)?);
+            serde_json::to_writer(writer, &stored)?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+/// The root the next block of `account` will be built on
+fn frontier_root(ledger: &Ledger, account: &PublicKey) -> InputHash {
+    match ledger.account_info(account) {
+        Some(info) => info.head.into(),
+        None => InputHash::new(*account.as_bytes()),
+    }
+}
+
+/// Drop stale work and queue precaching if a newly confirmed block is on a wallet account
+pub fn block_confirmed(state: &State, block: &Block, hash: BlockHash) {
+    let account = match block.payload {
+        Some(BlockPayload::State { ref account, .. }) => *account,
+        _ => return,
+    };
+    if state.wallets.read().unwrap().find_account(&account).is_none() {
+        return;
+    }
+    let root = InputHash::from(hash);
+    if let Err(e) = state.work_cache.invalidate(&account, &root) {
+        warn!("Failed to save work cache: {}", e);
+    }
+    state.work_cache.queue(account, root);
+}
+
+/// Queue precaching for every wallet account without work cached for its frontier.
+/// Returns the number of accounts queued.
+pub fn queue_wallet_accounts(state: &State) -> usize {
+    let wallets = state.wallets.read().unwrap();
+    let ledger = state.ledger.read().unwrap();
+    let mut queued = 0;
+    for (_, wallet) in wallets.iter() {
+        for account in wallet.accounts() {
+            let root = frontier_root(&ledger, &account.public_key);
+            if state.work_cache.get(&root).is_none() {
+                state.work_cache.queue(account.public_key, root);
+                queued += 1;
+            }
+        }
+    }
+    if queued > 0 {
+        debug!("Queued {} wallet accounts for work precaching", queued);
+    }
+    queued
+}
+
+/// Start a thread which precomputes queued work one account at a time
+pub fn spawn_worker(state: Arc<State>) -> thread::JoinHandle<()> {
+    thread::spawn(move || loop {
+        let (account, root) = state.work_cache.pop();
+        if state.shutdown.is_triggered() {
+            return;
+        }
+        let is_frontier = |state: &State| frontier_root(&state.ledger.read().unwrap(), &account) == root;
+        // The frontier may have moved on while this was queued
+        if state.work_cache.get(&root).is_some() || !is_frontier(&state) {
+            continue;
+        }
+        match state.work.generate(&root, nanopow_rs::DEFAULT_THRESHOLD) {
+            Ok(work) => {
+                if !is_frontier(&state) {
+                    debug!("Frontier changed while precomputing work for {}", root);
+                    continue;
+                }
+                debug!("Precomputed work for {}", root);
+                if let Err(e) = state.work_cache.insert(&account, root, work) {
+                    warn!("Failed to save work cache: {}", e);
+                }
+            },
+            Err(e) => warn!("Failed to precompute work for {}: {}", root, e),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nano_lib_rs::keys::{PrivateKey, Seed};
+    use std::env;
+
+    #[test]
+    fn persists_and_invalidates_work() {
+        let path = env::temp_dir().join(format!("nano-rs-work-{}.json", ::rand::random::<u64>()));
+        let seed = Seed::from_string("1234567890123456789012345678901234567890123456789012345678901234").unwrap();
+        let account = PrivateKey::from_seed(seed, 0).public_key();
+        let first = InputHash::new([1u8; 32]);
+        let second = InputHash::new([2u8; 32]);
+        {
+            let cache = WorkCache::open(&path).unwrap();
+            cache.insert(&account, first, Work(1)).unwrap();
+            // Work for a newer frontier replaces the old one
+            cache.insert(&account, second, Work(2)).unwrap();
+            assert_eq!(cache.get(&first), None);
+        }
+        let cache = WorkCache::open(&path).unwrap();
+        assert_eq!(cache.get(&second), Some(Work(2)));
+        assert_eq!(cache.invalidate(&account, &second).unwrap(), 0);
+        assert_eq!(cache.invalidate(&account, &first).unwrap(), 1);
+        assert_eq!(cache.len(), 0);
+        cache.insert(&account, first, Work(1)).unwrap();
+        assert!(cache.remove(&first).unwrap());
+        assert!(!cache.remove(&first).unwrap());
+        assert_eq!(WorkCache::open(&path).unwrap().len(), 0);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,272 @@
+//! Work generation for locally created blocks.
+//!
+//! Requests are sent to every configured work peer, HTTP endpoints speaking the
+//! `work_generate` protocol of the reference node, and raced against generation on the local
+//! CPU. The first valid result wins and every other generation is cancelled. If no peer
+//! answers with valid work, the local generation carries on until it finds some.
+use hyper::{Body, Client, Request, Uri};
+use hyper::client::HttpConnector;
+use hyper::header::CONTENT_TYPE;
+use serde_json::{self, Value};
+use tokio::runtime::{Runtime, TaskExecutor};
+
+use futures::{future, Future, Stream};
+use futures::sync::oneshot;
+
+use nano_lib_rs::block::{InputHash, Work};
+use nanopow_rs::{self, spawn_work, Stop, WorkHandle};
+
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use error::*;
+
+pub mod cache;
+
+pub use self::cache::WorkCache;
+
+type WorkFuture = Box<Future<Item=Work, Error=Error> + Send>;
+
+/// HTTP client state, only created when there are work peers to talk to
+struct PeerClient {
+    client: Client<HttpConnector>,
+    executor: TaskExecutor,
+    // Kept alive for as long as the executor is used
+    _runtime: Mutex<Runtime>,
+}
+
+/// Generates work locally and on a list of work peers
+pub struct WorkPool {
+    peers: Vec<Uri>,
+    threads: usize,
+    client: Option<PeerClient>,
+    /// Stop tokens of the local generations in progress
+    running: Mutex<Vec<Stop>>,
+    cancelled: AtomicBool,
+}
+
+impl fmt::Debug for WorkPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WorkPool")
+            .field("peers", &self.peers)
+            .field("threads", &self.threads)
+            .finish()
+    }
+}
+
+impl WorkPool {
+    /// Create a pool generating work on `threads` local threads as well as on `peers`
+    pub fn new(peers: Vec<Uri>, threads: usize) -> Result<Self> {
+        let client = if peers.is_empty() {
+            None
+        } else {
+            let runtime = Runtime::new()?;
+            let executor = runtime.executor();
+            let client = Client::builder()
+                .executor(executor.clone())
+                .build_http();
+            Some(PeerClient { client, executor, _runtime: Mutex::new(runtime) })
+        };
+        Ok(WorkPool {
+            peers,
+            threads: threads.max(1),
+            client,
+            running: Mutex::new(Vec::new()),
+            cancelled: AtomicBool::new(false),
+        })
+    }
+
+    pub fn peers(&self) -> &[Uri] {
+        &self.peers
+    }
+
+    /// Generate work for `root` reaching `threshold`, blocking until it is found or the pool
+    /// is cancelled
+    pub fn generate(&self, root: &InputHash, threshold: u64) -> Result<Work> {
+        let local = spawn_work(root, threshold, self.threads, None);
+        let stop = local.stop_token();
+        {
+            let mut running = self.running.lock().unwrap();
+            if self.cancelled.load(Ordering::SeqCst) {
+                stop.stop();
+                bail!(ErrorKind::WorkGenerationFailed);
+            }
+            running.push(stop.clone());
+        }
+        let result = match self.client {
+            Some(ref peer_client) => self.race(peer_client, local, root, threshold),
+            None => local.wait().ok().and_then(|w| w).ok_or(ErrorKind::WorkGenerationFailed.into()),
+        };
+        stop.stop();
+        self.running.lock().unwrap().retain(|stop| !stop.is_stopped());
+        result
+    }
+
+    /// Stop every generation in progress and fail any started later. Used when shutting down.
+    pub fn cancel_all(&self) {
+        let mut running = self.running.lock().unwrap();
+        self.cancelled.store(true, Ordering::SeqCst);
+        for stop in running.drain(..) {
+            stop.stop();
+        }
+    }
+
+    /// Race the local generation against every peer
+    fn race(&self, peer_client: &PeerClient, local: WorkHandle, root: &InputHash, threshold: u64) -> Result<Work> {
+
+        let mut racers: Vec<WorkFuture> = Vec::with_capacity(self.peers.len() + 1);
+        racers.push(Box::new(local.then(|res| match res {
+            Ok(Some(work)) => Ok(work),
+            _ => Err(ErrorKind::WorkGenerationFailed.into()),
+        })));
+        for peer in self.peers.iter() {
+            racers.push(request_work(&peer_client.client, peer, *root, threshold));
+        }
+
+        // Losing peer requests are dropped, and so aborted, as soon as one racer succeeds
+        let (tx, rx) = oneshot::channel();
+        peer_client.executor.spawn(future::select_ok(racers).then(move |res| {
+            let _ = tx.send(res.map(|(work, _)| work));
+            Ok(())
+        }));
+        let result = rx.wait().unwrap_or_else(|_| Err(ErrorKind::WorkGenerationFailed.into()));
+
+        for peer in self.peers.iter() {
+            let cancel = post(peer, &json!({
+                "action": "work_cancel",
+                "hash": String::from(*root),
+            }));
+            peer_client.executor.spawn(peer_client.client.request(cancel)
+                .map(|_| ())
+                .map_err(|e| debug!("Failed to cancel work on peer: {}", e)));
+        }
+        result
+    }
+}
+
+fn post(peer: &Uri, body: &Value) -> Request<Body> {
+    Request::post(peer.clone())
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// Ask a single peer for work, failing if the peer answers with anything but valid work
+fn request_work(client: &Client<HttpConnector>, peer: &Uri, root: InputHash, threshold: u64) -> WorkFuture {
+    let request = post(peer, &json!({
+        "action": "work_generate",
+        "hash": String::from(root),
+        "difficulty": format!("{:016x}", threshold),
+    }));
+    let peer = peer.to_string();
+    Box::new(client.request(request)
+        .and_then(|res| res.into_body().concat2())
+        .from_err::<Error>()
+        .and_then(move |body| {
+            let response: Value = serde_json::from_slice(&body)?;
+            let work = response["work"].as_str()
+                .and_then(|work| Work::from_hex(work).ok())
+                .ok_or_else(|| ErrorKind::InvalidWorkPeerResponse(peer.clone()))?;
+            if !nanopow_rs::check_work_with_threshold(&root, &work, threshold) {
+                bail!(ErrorKind::InvalidWorkPeerResponse(peer));
+            }
+            debug!("Received work for {} from {}", root, peer);
+            Ok(work)
+        })
+        .map_err(|e| {
+            debug!("Work peer failed: {}", e);
+            e
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{Response, Server};
+    use hyper::service::service_fn;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    const ROOT: &str = "8D3E5F07BFF7B7484CDCB392F47009F62997253D28BD98B94BCED95F03C4DA09";
+
+    /// Start a work peer which answers every request with `work`, recording the actions it saw
+    fn mock_peer(rt: &mut Runtime, work: &'static str, actions: Arc<Mutex<Vec<String>>>) -> Uri {
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .serve(move || {
+                let actions = actions.clone();
+                service_fn(move |req: Request<Body>| {
+                    let actions = actions.clone();
+                    req.into_body().concat2().map(move |body| {
+                        let request: Value = serde_json::from_slice(&body).unwrap();
+                        actions.lock().unwrap().push(request["action"].as_str().unwrap().to_owned());
+                        Response::new(Body::from(json!({ "work": work }).to_string()))
+                    })
+                })
+            });
+        let uri = format!("http://{}", server.local_addr()).parse().unwrap();
+        rt.spawn(server.map_err(|_| ()));
+        uri
+    }
+
+    #[test]
+    fn uses_work_from_peer() {
+        let mut rt = Runtime::new().unwrap();
+        let actions = Arc::new(Mutex::new(Vec::new()));
+        let peer = mock_peer(&mut rt, "4effb6b0cd5625e2", actions.clone());
+        let pool = WorkPool::new(vec![peer], 1).unwrap();
+        let root = InputHash::from_hex(ROOT).unwrap();
+
+        let work = pool.generate(&root, nanopow_rs::DEFAULT_THRESHOLD).unwrap();
+        assert_eq!(work, Work::from_hex("4effb6b0cd5625e2").unwrap());
+
+        // The cancel is sent in the background
+        for _ in 0..100 {
+            if actions.lock().unwrap().len() == 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(*actions.lock().unwrap(), vec!["work_generate", "work_cancel"]);
+    }
+
+    #[test]
+    fn falls_back_to_local_work() {
+        let mut rt = Runtime::new().unwrap();
+        let actions = Arc::new(Mutex::new(Vec::new()));
+        // Work which does not reach the requested threshold
+        let bad_peer = mock_peer(&mut rt, "4effc680cd5625e2", actions.clone());
+        let dead_peer = "http://127.0.0.1:1".parse().unwrap();
+        let pool = WorkPool::new(vec![bad_peer, dead_peer], 2).unwrap();
+        let root = InputHash::from_hex(ROOT).unwrap();
+
+        let work = pool.generate(&root, nanopow_rs::TEST_THRESHOLD).unwrap();
+        assert!(nanopow_rs::check_work_with_threshold(&root, &work, nanopow_rs::TEST_THRESHOLD));
+    }
+
+    #[test]
+    fn generates_locally_without_peers() {
+        let pool = WorkPool::new(Vec::new(), 2).unwrap();
+        let root = InputHash::from_hex(ROOT).unwrap();
+        let work = pool.generate(&root, nanopow_rs::TEST_THRESHOLD).unwrap();
+        assert!(nanopow_rs::check_work_with_threshold(&root, &work, nanopow_rs::TEST_THRESHOLD));
+    }
+
+    #[test]
+    fn cancels_generation() {
+        let pool = Arc::new(WorkPool::new(Vec::new(), 1).unwrap());
+        let root = InputHash::from_hex(ROOT).unwrap();
+        let generating = {
+            let pool = pool.clone();
+            // Far too hard to be found before it is cancelled
+            thread::spawn(move || pool.generate(&root, u64::max_value()))
+        };
+        while pool.running.lock().unwrap().is_empty() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        pool.cancel_all();
+        assert!(generating.join().unwrap().is_err());
+        assert!(pool.generate(&root, nanopow_rs::TEST_THRESHOLD).is_err());
+    }
+}